@@ -1,17 +1,19 @@
 use std::net::SocketAddr;
+use std::str::FromStr;
 
 use actix_web::{web, App, HttpResponse, HttpServer};
-use r53::{Name, RRset};
+use r53::{Name, RRType, RRset};
 use serde::{Deserialize, Serialize};
 
-use super::common::error_response;
-use crate::auth::Auth;
+use super::common::{conflict_response, error_response, not_found_response};
+use crate::auth::{Auth, AuthError, DnssecAlgorithm, KeyRole, Nsec3Config, ZoneConfig};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct AddZoneRequest {
     #[serde(rename = "name")]
     pub name: String,
-    pub ips: Vec<String>,
+    #[serde(flatten)]
+    pub config: ZoneConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -20,6 +22,39 @@ struct AddRRsetRequest {
     pub rrset: Vec<String>,
 }
 
+//rrset in the resource api, one rr per string in master file format
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RRsetBody {
+    pub rrset: Vec<String>,
+}
+
+impl RRsetBody {
+    fn new(rrset: &RRset) -> Self {
+        Self {
+            rrset: rrset.to_string().lines().map(|rr| rr.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SignZoneRequest {
+    pub algorithm: String,
+    //nsec is used without nsec3 parameters
+    #[serde(default)]
+    pub nsec3: Option<Nsec3Config>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RolloverRequest {
+    pub role: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeleteRRsetQuery {
+    //only delete the rr with this rdata instead of the whole rrset
+    pub rdata: Option<String>,
+}
+
 struct ApiState {
     pub auth: Auth,
 }
@@ -30,9 +65,34 @@ impl ApiState {
     }
 }
 
+fn auth_error_response(err: anyhow::Error) -> HttpResponse {
+    match err.downcast_ref::<AuthError>() {
+        Some(AuthError::UnknownZone)
+        | Some(AuthError::UnknownRRset)
+        | Some(AuthError::UnknownRR) => not_found_response(err.to_string()),
+        Some(AuthError::DuplicateZone)
+        | Some(AuthError::ApexRRset)
+        | Some(AuthError::SecondaryZone) => conflict_response(err.to_string()),
+        None => error_response(err.to_string()),
+    }
+}
+
+fn parse_rrset_path(path: &(String, String, String)) -> anyhow::Result<(Name, Name, RRType)> {
+    Ok((
+        Name::new(path.0.as_ref())?,
+        Name::new(path.1.as_ref())?,
+        path.2.parse::<RRType>()?,
+    ))
+}
+
 async fn add_zone(req: web::Json<AddZoneRequest>, zones: web::Data<ApiState>) -> HttpResponse {
-    if let Ok(name) = Name::new(req.name.as_ref()) {
-        if let Err(e) = zones.auth.add_zone(name, &req.ips) {
+    match Name::new(req.name.as_ref()) {
+        Ok(name) => {
+            if let Err(e) = zones.auth.add_zone(name, &req.config) {
+                return auth_error_response(e);
+            }
+        }
+        Err(e) => {
             return error_response(e.to_string());
         }
     }
@@ -44,7 +104,7 @@ async fn add_rrset(req: web::Json<AddRRsetRequest>, zones: web::Data<ApiState>)
         Ok(name) => match RRset::from_strs(&req.rrset) {
             Ok(rrset) => {
                 if let Err(e) = zones.auth.add_rrset(&name, rrset) {
-                    return error_response(e.to_string());
+                    return auth_error_response(e);
                 }
             }
             Err(e) => {
@@ -58,6 +118,247 @@ async fn add_rrset(req: web::Json<AddRRsetRequest>, zones: web::Data<ApiState>)
     HttpResponse::Ok().json(req.0)
 }
 
+async fn export_zone(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.export_zone(&name) {
+            Ok(content) => HttpResponse::Ok().content_type("text/dns").body(content),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn get_allow_transfer(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.allow_transfer(&name) {
+            Ok(acl) => HttpResponse::Ok().json(acl),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn set_allow_transfer(
+    path: web::Path<String>,
+    req: web::Json<Vec<String>>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.set_allow_transfer(&name, &req) {
+            Ok(_) => HttpResponse::Ok().json(req.0),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn get_allow_update(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.allow_update(&name) {
+            Ok(acl) => HttpResponse::Ok().json(acl),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn set_allow_update(
+    path: web::Path<String>,
+    req: web::Json<Vec<String>>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.set_allow_update(&name, &req) {
+            Ok(_) => HttpResponse::Ok().json(req.0),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn get_also_notify(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.also_notify(&name) {
+            Ok(targets) => HttpResponse::Ok().json(targets),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn set_also_notify(
+    path: web::Path<String>,
+    req: web::Json<Vec<String>>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.set_also_notify(&name, &req) {
+            Ok(_) => HttpResponse::Ok().json(req.0),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn get_dnssec(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.dnssec(&name) {
+            Ok(Some(status)) => HttpResponse::Ok().json(status),
+            Ok(None) => not_found_response(format!("zone {} isn't signed", name)),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+//sign the zone with new keys, ds of the new ksk is returned
+async fn sign_zone(
+    path: web::Path<String>,
+    req: web::Json<SignZoneRequest>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    let name = match Name::new(path.as_ref()) {
+        Ok(name) => name,
+        Err(e) => return error_response(e.to_string()),
+    };
+    let algorithm = match req.algorithm.parse::<DnssecAlgorithm>() {
+        Ok(algorithm) => algorithm,
+        Err(e) => return error_response(e.to_string()),
+    };
+    match zones
+        .auth
+        .sign_zone(&name, algorithm, req.nsec3.clone())
+        .and_then(|_| zones.auth.dnssec(&name))
+    {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => auth_error_response(e),
+    }
+}
+
+//start the rollover of ksk or zsk, the keys with their states are returned
+async fn rollover(
+    path: web::Path<String>,
+    req: web::Json<RolloverRequest>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    let name = match Name::new(path.as_ref()) {
+        Ok(name) => name,
+        Err(e) => return error_response(e.to_string()),
+    };
+    let role = match req.role.parse::<KeyRole>() {
+        Ok(role) => role,
+        Err(e) => return error_response(e.to_string()),
+    };
+    match zones
+        .auth
+        .rollover(&name, role)
+        .and_then(|_| zones.auth.dnssec(&name))
+    {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => auth_error_response(e),
+    }
+}
+
+async fn unsign_zone(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.unsign_zone(&name) {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn list_zones(zones: web::Data<ApiState>) -> HttpResponse {
+    let names = zones
+        .auth
+        .zones()
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<String>>();
+    HttpResponse::Ok().json(names)
+}
+
+async fn delete_zone(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.delete_zone(&name) {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn get_rrsets(path: web::Path<String>, zones: web::Data<ApiState>) -> HttpResponse {
+    match Name::new(path.as_ref()) {
+        Ok(name) => match zones.auth.get_rrsets(&name) {
+            Ok(rrsets) => {
+                HttpResponse::Ok().json(rrsets.iter().map(RRsetBody::new).collect::<Vec<_>>())
+            }
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+async fn get_rrset(
+    path: web::Path<(String, String, String)>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    match parse_rrset_path(&path) {
+        Ok((zone, name, typ)) => match zones.auth.get_rrset(&zone, &name, typ) {
+            Ok(rrset) => HttpResponse::Ok().json(RRsetBody::new(&rrset)),
+            Err(e) => auth_error_response(e),
+        },
+        Err(e) => error_response(e.to_string()),
+    }
+}
+
+//create or replace the rrset, the rrs must have the name and type in path
+async fn put_rrset(
+    path: web::Path<(String, String, String)>,
+    req: web::Json<RRsetBody>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    let (zone, name, typ) = match parse_rrset_path(&path) {
+        Ok(v) => v,
+        Err(e) => return error_response(e.to_string()),
+    };
+    let rrset = match RRset::from_strs(&req.rrset) {
+        Ok(rrset) => rrset,
+        Err(e) => return error_response(e.to_string()),
+    };
+    if !rrset.name.eq(&name) || rrset.typ != typ {
+        return error_response(format!("rrset doesn't match {} {}", name, typ));
+    }
+    match zones.auth.add_rrset(&zone, rrset) {
+        Ok(_) => HttpResponse::Ok().json(req.0),
+        Err(e) => auth_error_response(e),
+    }
+}
+
+async fn delete_rrset(
+    path: web::Path<(String, String, String)>,
+    query: web::Query<DeleteRRsetQuery>,
+    zones: web::Data<ApiState>,
+) -> HttpResponse {
+    let (zone, name, typ) = match parse_rrset_path(&path) {
+        Ok(v) => v,
+        Err(e) => return error_response(e.to_string()),
+    };
+    let result = match query.rdata {
+        Some(ref rdata) => match RRset::from_str(&format!("{} 0 IN {} {}", name, typ, rdata)) {
+            Ok(rr) => zones.auth.delete_rr(&zone, rr),
+            Err(e) => return error_response(e.to_string()),
+        },
+        None => zones.auth.delete_rrset(&zone, &name, typ),
+    };
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => auth_error_response(e),
+    }
+}
+
 pub async fn start_auth_api(auth: Auth, addr: SocketAddr) {
     HttpServer::new(move || {
         let auth = auth.clone();
@@ -66,6 +367,38 @@ pub async fn start_auth_api(auth: Auth, addr: SocketAddr) {
             .app_data(web::Data::new(ApiState::new(auth)))
             .service(web::resource("/AddZone").route(web::post().to(add_zone)))
             .service(web::resource("/AddRRset").route(web::post().to(add_rrset)))
+            .service(web::resource("/zones").route(web::get().to(list_zones)))
+            .service(web::resource("/zones/{zone}").route(web::delete().to(delete_zone)))
+            .service(web::resource("/zones/{zone}/export").route(web::get().to(export_zone)))
+            .service(web::resource("/zones/{zone}/rrsets").route(web::get().to(get_rrsets)))
+            .service(
+                web::resource("/zones/{zone}/allow-transfer")
+                    .route(web::get().to(get_allow_transfer))
+                    .route(web::put().to(set_allow_transfer)),
+            )
+            .service(
+                web::resource("/zones/{zone}/allow-update")
+                    .route(web::get().to(get_allow_update))
+                    .route(web::put().to(set_allow_update)),
+            )
+            .service(
+                web::resource("/zones/{zone}/also-notify")
+                    .route(web::get().to(get_also_notify))
+                    .route(web::put().to(set_also_notify)),
+            )
+            .service(
+                web::resource("/zones/{zone}/dnssec")
+                    .route(web::get().to(get_dnssec))
+                    .route(web::put().to(sign_zone))
+                    .route(web::delete().to(unsign_zone)),
+            )
+            .service(web::resource("/zones/{zone}/dnssec/rollover").route(web::post().to(rollover)))
+            .service(
+                web::resource("/zones/{zone}/rrsets/{name}/{type}")
+                    .route(web::get().to(get_rrset))
+                    .route(web::put().to(put_rrset))
+                    .route(web::delete().to(delete_rrset)),
+            )
     })
     .bind(addr)
     .unwrap()
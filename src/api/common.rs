@@ -9,3 +9,11 @@ struct ErrorResponse {
 pub fn error_response(err: String) -> HttpResponse {
     HttpResponse::UnprocessableEntity().json(ErrorResponse { error_message: err })
 }
+
+pub fn not_found_response(err: String) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse { error_message: err })
+}
+
+pub fn conflict_response(err: String) -> HttpResponse {
+    HttpResponse::Conflict().json(ErrorResponse { error_message: err })
+}
@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use r53::{RRType, RRset, Response, SectionType};
+use serde::Deserialize;
+
+use crate::auth::soa_negative_ttl;
+use crate::server::{handle_message, Handler, TlsCertificate};
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+pub const DOH_PATH: &str = "/dns-query";
+
+#[derive(Deserialize, Debug)]
+struct DohQuery {
+    pub dns: String,
+}
+
+struct DohState<H: Handler> {
+    pub handler: H,
+}
+
+//dns over https(rfc8484) has its own tls listeners, apart from the
+//management api
+pub async fn start_doh<H: Handler>(handler: H, addrs: Vec<SocketAddr>, tls: TlsCertificate) {
+    let config = tls.http_server_config().expect("invalid tls certificate");
+    let mut server = HttpServer::new(move || App::new().configure(configure_doh(handler.clone())));
+    for addr in addrs {
+        server = server
+            .bind_rustls(addr, config.clone())
+            .expect("bind addr failed");
+    }
+    server.run().await.unwrap()
+}
+
+//dns over https(rfc8484) endpoint, dispatch to the same handler as the dns servers
+fn configure_doh<H: Handler>(handler: H) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg: &mut web::ServiceConfig| {
+        cfg.app_data(web::Data::new(DohState { handler })).service(
+            web::resource(DOH_PATH)
+                .route(web::get().to(doh_get::<H>))
+                .route(web::post().to(doh_post::<H>)),
+        );
+    }
+}
+
+async fn doh_get<H: Handler>(
+    query: web::Query<DohQuery>,
+    state: web::Data<DohState<H>>,
+) -> HttpResponse {
+    match base64::decode_config(&query.dns, base64::URL_SAFE_NO_PAD) {
+        Ok(message) => resolve(&message, state.handler.clone()).await,
+        Err(_) => HttpResponse::BadRequest().finish(),
+    }
+}
+
+async fn doh_post<H: Handler>(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<DohState<H>>,
+) -> HttpResponse {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if content_type != Some(DNS_MESSAGE_CONTENT_TYPE) {
+        return HttpResponse::UnsupportedMediaType().finish();
+    }
+    resolve(&body, state.handler.clone()).await
+}
+
+//tsig of the query is verified and the answer signed as over udp and tcp
+async fn resolve<H: Handler>(message: &[u8], mut handler: H) -> HttpResponse {
+    let (response, body) = match handle_message(&mut handler, message).await {
+        Ok(result) => result,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    HttpResponse::Ok()
+        .content_type(DNS_MESSAGE_CONTENT_TYPE)
+        .insert_header((
+            header::CACHE_CONTROL,
+            format!("max-age={}", cache_max_age(&response)),
+        ))
+        .body(body)
+}
+
+//rfc8484 section 5.1, freshness shouldn't outlive any rrset in the answer,
+//negative answers are cached as long as the soa in authority section
+//allows(rfc2308 section 5), which is the smaller of its ttl and minimum
+fn cache_max_age(response: &Response) -> u32 {
+    let min_ttl = |rrsets: Option<&Vec<RRset>>| {
+        rrsets
+            .filter(|rrsets| !rrsets.is_empty())
+            .map(|rrsets| rrsets.iter().map(|rrset| rrset.ttl.0).min().unwrap())
+    };
+    let negative_ttl = || {
+        response
+            .section(SectionType::Authority)?
+            .iter()
+            .find(|rrset| rrset.typ == RRType::SOA)
+            .and_then(soa_negative_ttl)
+    };
+    min_ttl(response.section(SectionType::Answer))
+        .or_else(negative_ttl)
+        .or_else(|| min_ttl(response.section(SectionType::Authority)))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{TsigKey, TsigKeyring, TsigSession};
+    use crate::test_util::build_auth;
+    use actix_web::{test, App};
+    use r53::{MessageRender, Name, RRType, Rcode, Request};
+
+    fn query_wire(name: &str) -> Vec<u8> {
+        let mut req = Request::new(Name::new(name).unwrap(), RRType::A);
+        req.header.id = 0;
+        let mut buf = vec![0; 512];
+        let len = {
+            let mut render = MessageRender::new(&mut buf[..]);
+            req.to_wire(&mut render).unwrap()
+        };
+        buf.truncate(len);
+        buf
+    }
+
+    #[actix_web::test]
+    async fn test_doh_get_and_post() {
+        let app = test::init_service(App::new().configure(configure_doh(build_auth()))).await;
+
+        let dns = base64::encode_config(query_wire("www.example.com"), base64::URL_SAFE_NO_PAD);
+        let req = test::TestRequest::get()
+            .uri(&format!("{}?dns={}", DOH_PATH, dns))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            DNS_MESSAGE_CONTENT_TYPE
+        );
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "max-age=3600"
+        );
+        let body = test::read_body(resp).await;
+        let response = Response::from_wire(&body).unwrap();
+        assert_eq!(response.header.rcode, Rcode::NoError);
+        assert_eq!(
+            response.section(SectionType::Answer).unwrap()[0].rdatas[0].to_string(),
+            "2.2.2.2"
+        );
+
+        let req = test::TestRequest::post()
+            .uri(DOH_PATH)
+            .insert_header((header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE))
+            .set_payload(query_wire("none.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        //soa minimum is lower than its ttl
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "max-age=600"
+        );
+        let body = test::read_body(resp).await;
+        let response = Response::from_wire(&body).unwrap();
+        assert_eq!(response.header.rcode, Rcode::NXDomain);
+
+        let req = test::TestRequest::post()
+            .uri(DOH_PATH)
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(query_wire("www.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 415);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}?dns=%21%21", DOH_PATH))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_doh_tsig() {
+        let key = "doh-key:hmac-sha256:c2VjcmV0LWtleS1vZi14ZnI="
+            .parse::<TsigKey>()
+            .unwrap();
+        let auth = build_auth().tsig_keys(TsigKeyring::new(vec![key.clone()]));
+        let app = test::init_service(App::new().configure(configure_doh(auth))).await;
+        let post = |message: Vec<u8>| {
+            test::TestRequest::post()
+                .uri(DOH_PATH)
+                .insert_header((header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE))
+                .set_payload(message)
+                .to_request()
+        };
+
+        //answer is signed with the key of the query
+        let mut session = TsigSession::new(key);
+        let mut query = query_wire("www.example.com");
+        session.sign(&mut query);
+        let body = test::call_and_read_body(&app, post(query)).await;
+        let response = Response::from_wire(&session.verify(&body).unwrap()).unwrap();
+        assert_eq!(response.header.rcode, Rcode::NoError);
+
+        let other = "other-key:hmac-sha256:c2VjcmV0LWtleS1vZi14ZnI="
+            .parse::<TsigKey>()
+            .unwrap();
+        let mut query = query_wire("www.example.com");
+        TsigSession::new(other).sign(&mut query);
+        let body = test::call_and_read_body(&app, post(query)).await;
+        let response = Response::from_wire(&body).unwrap();
+        assert_eq!(response.header.rcode, Rcode::NotAuth);
+    }
+}
@@ -1,6 +1,8 @@
 mod auth;
 mod common;
+mod doh;
 mod recursor;
 
 pub use auth::start_auth_api;
+pub use doh::start_doh;
 pub use recursor::start_recursor_api;
@@ -0,0 +1,120 @@
+use anyhow::{self, bail};
+use std::net::IpAddr;
+
+use crate::server::key_name;
+
+const KEY_PREFIX: &str = "key:";
+
+//address prefixes like 10.0.0.0/8, 2001:db8::/32 or a single address, and
+//tsig keys like key:xfr-key matching requests signed with the key from any
+//address. an empty acl matches nothing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Acl {
+    prefixes: Vec<(IpAddr, u8)>,
+    keys: Vec<String>,
+}
+
+impl Acl {
+    pub fn new<T: AsRef<str>>(entries: &[T]) -> anyhow::Result<Self> {
+        let mut acl = Acl::default();
+        for entry in entries {
+            match entry.as_ref().strip_prefix(KEY_PREFIX) {
+                Some(key) => acl.keys.push(key_name(key)?),
+                None => acl.prefixes.push(parse_prefix(entry.as_ref())?),
+            }
+        }
+        Ok(acl)
+    }
+
+    //key is the name of the tsig key the request is verified with
+    pub fn allows(&self, ip: IpAddr, key: Option<&str>) -> bool {
+        key.map_or(false, |key| self.keys.iter().any(|k| k == key)) || self.contains(ip)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        self.prefixes.iter().any(|(addr, len)| match (addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                prefix_match(u32::from(*addr) as u128, u32::from(ip) as u128, 32, *len)
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                prefix_match(u128::from(*addr), u128::from(ip), 128, *len)
+            }
+            _ => false,
+        })
+    }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.prefixes
+            .iter()
+            .map(|(addr, len)| format!("{}/{}", addr, len))
+            .chain(self.keys.iter().map(|key| format!("{}{}", KEY_PREFIX, key)))
+            .collect()
+    }
+}
+
+fn parse_prefix(prefix: &str) -> anyhow::Result<(IpAddr, u8)> {
+    let (addr, len) = match prefix.split_once('/') {
+        Some((addr, len)) => (addr, Some(len)),
+        None => (prefix, None),
+    };
+    let addr = match addr.parse::<IpAddr>() {
+        Ok(addr) => addr,
+        Err(_) => bail!("invalid address {}", prefix),
+    };
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let len = match len {
+        Some(len) => match len.parse::<u8>() {
+            Ok(len) if len <= max_len => len,
+            _ => bail!("invalid prefix length {}", prefix),
+        },
+        None => max_len,
+    };
+    Ok((addr, len))
+}
+
+fn prefix_match(prefix: u128, ip: u128, bits: u8, len: u8) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let shift = (bits - len) as u32;
+    (prefix >> shift) == (ip >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl::new(&["10.0.0.0/8", "192.0.2.1", "2001:db8::/32"]).unwrap();
+        for ip in ["10.1.2.3", "192.0.2.1", "::ffff:10.0.0.1", "2001:db8::53"] {
+            assert!(acl.contains(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["11.0.0.1", "192.0.2.2", "2001:db9::1"] {
+            assert!(!acl.contains(ip.parse().unwrap()), "{}", ip);
+        }
+        assert_eq!(
+            acl.to_strings(),
+            vec!["10.0.0.0/8", "192.0.2.1/32", "2001:db8::/32"]
+        );
+
+        assert!(!Acl::default().contains("127.0.0.1".parse().unwrap()));
+        assert!(Acl::new(&["0.0.0.0/0"])
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+
+        let acl = Acl::new(&["key:XFR-Key", "10.0.0.0/8"]).unwrap();
+        assert!(acl.allows("192.0.2.1".parse().unwrap(), Some("xfr-key.")));
+        assert!(acl.allows("10.0.0.1".parse().unwrap(), Some("other-key.")));
+        assert!(!acl.allows("192.0.2.1".parse().unwrap(), Some("other-key.")));
+        assert!(!acl.allows("192.0.2.1".parse().unwrap(), None));
+        assert_eq!(acl.to_strings(), vec!["10.0.0.0/8", "key:xfr-key."]);
+        for invalid in ["10.0.0.0/33", "10.0.0", "::/129", "a/8", "key:", "key:a..b"] {
+            assert!(Acl::new(&[invalid]).is_err());
+        }
+    }
+}
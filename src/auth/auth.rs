@@ -1,108 +1,2303 @@
-use std::fmt::Display;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use anyhow::{self, bail};
 use async_trait::async_trait;
+use chrono::Utc;
 use r53::{
     DomainTree, FindResultFlag, Name, RRType, RRset, Rcode, Request, Response, ResponseBuilder,
     SectionType,
 };
 
+use tokio::time;
+
+use super::acl::Acl;
+use super::canonical::CanonicalName;
+use super::dnssec::{
+    generate_keys, now, parse_keys, rdata_target, DnssecAlgorithm, DnssecStatus, KeyRole,
+    KeyStatus, Nsec3Config, ZoneKey,
+};
+use super::error::AuthError;
+use super::notify::{parse_remotes, send_notify, Notifier, Remote};
+use super::rollover::{start_rollover, RolloverPolicy, ZoneTtls};
+use super::secondary::{
+    apply_diffs, fetch_zone, soa_timers, SecondaryZone, SoaTimers, Transfer, INITIAL_RETRY,
+};
+use super::serial::{serial_gt, soa_serial, SerialPolicy};
+use super::store::{Operation, RRsetChange, Store, ZoneSnapshot};
+use super::update::{apply_updates, check_prerequisites, prescan};
 use super::zone::{FindMode, FindResult, MemoryZone};
-use super::zone_content_generator::{default_ns_and_glue, default_soa};
-use crate::server::Handler;
+use super::zone_content_generator::{ZoneConfig, ZoneTemplate};
+use super::zone_file::ZoneFileParser;
+use crate::server::{Client, Handler, TsigKey, TsigKeyring, Update};
+
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(600);
+const ROLLOVER_INTERVAL: Duration = Duration::from_secs(600);
+//cnames followed for one query
+const MAX_CNAME_CHAIN: usize = 16;
 
 #[derive(Clone)]
 pub struct Auth {
     zones: Arc<RwLock<DomainTree<MemoryZone>>>,
+    zone_names: Arc<RwLock<BTreeSet<CanonicalName>>>,
+    store: Arc<Mutex<Option<Store>>>,
+    template: Arc<ZoneTemplate>,
+    serial_policy: SerialPolicy,
+    rollover_policy: RolloverPolicy,
+    secondaries: Arc<RwLock<Vec<SecondaryZone>>>,
+    notifier: Arc<Notifier>,
+    keyring: Arc<TsigKeyring>,
 }
 
 impl Auth {
     pub fn new() -> Self {
         Self {
             zones: Arc::new(RwLock::new(DomainTree::new())),
+            zone_names: Arc::new(RwLock::new(BTreeSet::new())),
+            store: Arc::new(Mutex::new(None)),
+            template: Arc::new(ZoneTemplate::default()),
+            serial_policy: SerialPolicy::Increment,
+            rollover_policy: RolloverPolicy::default(),
+            secondaries: Arc::new(RwLock::new(Vec::new())),
+            notifier: Arc::new(Notifier::default()),
+            keyring: Arc::new(TsigKeyring::default()),
         }
     }
 
-    pub fn add_zone<T: AsRef<str> + Display>(
+    pub fn serial_policy(mut self, policy: SerialPolicy) -> Self {
+        self.serial_policy = policy;
+        self
+    }
+
+    pub fn rollover_policy(mut self, policy: RolloverPolicy) -> Self {
+        self.rollover_policy = policy;
+        self
+    }
+
+    //defaults of the zones created through api
+    pub fn zone_template(mut self, template: ZoneTemplate) -> Self {
+        self.template = Arc::new(template);
+        self
+    }
+
+    //keys to verify signed requests, and to sign the messages to the
+    //primaries and notify targets with a key
+    pub fn tsig_keys(mut self, keyring: TsigKeyring) -> Self {
+        self.keyring = Arc::new(keyring);
+        self
+    }
+
+    //restore the zones in the data dir over the ones loaded from zone files,
+    //every later change made through the api is persisted in it. zone file whose serial is newer
+    //than the one in the data dir replaces the stored zone along with the
+    //changes made to it, otherwise the stored zone is kept
+    pub fn restore(self, data_dir: &Path) -> anyhow::Result<Self> {
+        let (mut store, recovery) = Store::open(data_dir)?;
+        let mut files = self
+            .zones()
+            .into_iter()
+            .map(CanonicalName::new)
+            .collect::<BTreeSet<_>>();
+
+        //latest serial of each zone in the data dir
+        let mut stored = Vec::with_capacity(recovery.zones.len());
+        let mut serials = BTreeMap::new();
+        for snapshot in recovery.zones.iter() {
+            let (name, zone) = zone_from_snapshot(snapshot)?;
+            serials.insert(CanonicalName::new(name.clone()), zone.serial());
+            stored.push((name, zone));
+        }
+        for op in recovery.operations.iter() {
+            let name = CanonicalName::new(Name::new(op.zone())?);
+            match op.serial() {
+                Some(serial) => {
+                    serials.insert(name, Some(serial));
+                }
+                None => {
+                    serials.entry(name).or_insert(None);
+                }
+            }
+        }
+        let newer = serials
+            .into_iter()
+            .filter(|(name, stored)| {
+                match (self.with_zone(name.name(), |zone| zone.serial()), stored) {
+                    (Ok(Some(file)), Some(stored)) => serial_gt(file, *stored),
+                    _ => false,
+                }
+            })
+            .map(|(name, _)| name)
+            .collect::<BTreeSet<_>>();
+        for name in newer.iter() {
+            log::warn!(
+                "zone file of {} is newer than the data dir, changes made through api are dropped",
+                name.name()
+            );
+        }
+
+        for (name, zone) in stored {
+            let key = CanonicalName::new(name.clone());
+            if newer.contains(&key) {
+                continue;
+            }
+            if files.remove(&key) {
+                self.do_delete_zone(&name)?;
+            }
+            self.insert_zone(name, zone)?;
+        }
+        for op in recovery.operations.iter() {
+            let name = Name::new(op.zone())?;
+            let key = CanonicalName::new(name.clone());
+            if newer.contains(&key) {
+                continue;
+            }
+            //zone added through api replaces the one from zone file
+            if matches!(op, Operation::AddZone { .. }) && files.remove(&key) {
+                self.do_delete_zone(&name)?;
+            }
+            if let Err(e) = self.apply(op) {
+                bail!("replay change of zone {} failed: {}", name, e);
+            }
+        }
+        //zone files taking over are persisted, so the journal doesn't have
+        //the changes made to the old content any more
+        if !newer.is_empty() {
+            store.snapshot(self.zone_snapshots())?;
+        }
+        *self.store.lock().unwrap() = Some(store);
+        Ok(self)
+    }
+
+    pub fn zones(&self) -> Vec<Name> {
+        let zone_names = self.zone_names.read().unwrap();
+        zone_names.iter().map(|name| name.name().clone()).collect()
+    }
+
+    //zone content is generated from the config and template before it's
+    //journaled, so later template change won't affect existing zones
+    pub fn add_zone(&self, name: Name, config: &ZoneConfig) -> anyhow::Result<()> {
+        let rrsets = self.template.zone_rrsets(&name, config)?;
+        let acl = Acl::new(&config.allow_transfer)?;
+        let targets = parse_remotes(&config.also_notify)?;
+        let update_acl = Acl::new(&config.allow_update)?;
+        let keys = match config.dnssec {
+            Some(ref algorithm) => generate_keys(algorithm.parse()?, now())?,
+            None => Vec::new(),
+        };
+        let nsec3 = config.nsec3.clone().filter(|_| !keys.is_empty());
+        if let Some(ref nsec3) = nsec3 {
+            nsec3.salt()?;
+        }
+        self.commit(Operation::AddZone {
+            name: name.to_string(),
+            content: rrsets.iter().map(|rrset| rrset.to_string()).collect(),
+            allow_transfer: acl.to_strings(),
+            also_notify: targets.iter().map(|remote| remote.to_string()).collect(),
+            allow_update: update_acl.to_strings(),
+            dnssec_keys: keys.iter().map(|key| key.to_string()).collect(),
+            nsec3,
+        })
+    }
+
+    //zone pulled from the primaries, which is answered with servfail until
+    //the first transfer and after it expires. secondary zones aren't
+    //persisted, they are transferred again at startup
+    pub fn add_secondary_zone<T: AsRef<str>>(
         &self,
         name: Name,
-        ip_addrs: &Vec<T>,
+        primaries: &[T],
     ) -> anyhow::Result<()> {
-        let mut zones = self.zones.write().unwrap();
-        let result = zones.find(&name);
-        if result.flag != FindResultFlag::ExacatMatch {
-            let mut zone = MemoryZone::new(name.clone());
-            zone.add_rrset(default_soa(&name));
-            let (ns, glue) = default_ns_and_glue(&name, ip_addrs);
-            zone.add_rrset(ns);
-            zone.add_rrset(glue);
-            zones.insert(name, Some(zone));
-            Ok(())
-        } else {
-            bail!("add duplicate zone");
+        let primaries = parse_remotes(primaries)?;
+        if primaries.is_empty() {
+            bail!("secondary zone {} has no primary", name);
+        }
+        let mut zone = MemoryZone::new(name.clone());
+        zone.set_secondary(true);
+        zone.set_expired(true);
+        self.insert_zone(name.clone(), zone)?;
+        self.secondaries.write().unwrap().push(SecondaryZone {
+            name,
+            primaries,
+            refresh: Arc::new(tokio::sync::Notify::new()),
+        });
+        Ok(())
+    }
+
+    pub async fn run_secondaries(&self) {
+        let secondaries = self.secondaries.read().unwrap().clone();
+        let tasks = secondaries
+            .into_iter()
+            .map(|secondary| {
+                let auth = self.clone();
+                tokio::spawn(async move { auth.run_secondary(secondary).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    //refresh, retry and expire of rfc1034 section 4.3.5, timers come from
+    //the soa of the local copy
+    async fn run_secondary(&self, secondary: SecondaryZone) {
+        let mut expire_at = None;
+        loop {
+            let wait = self
+                .refresh_secondary_at(&secondary, &mut expire_at, now())
+                .await;
+            tokio::select! {
+                _ = time::sleep(wait) => {}
+                _ = secondary.refresh.notified() => {}
+            }
         }
     }
 
+    //refresh the zone at now, and return how long to wait before the next
+    //refresh. expire_at is when the zone expires unless it's refreshed
+    async fn refresh_secondary_at(
+        &self,
+        secondary: &SecondaryZone,
+        expire_at: &mut Option<u32>,
+        now: u32,
+    ) -> Duration {
+        match self.refresh_secondary(secondary).await {
+            Ok(timers) => {
+                *expire_at = Some(now.saturating_add(timers.expire.as_secs() as u32));
+                timers.refresh
+            }
+            Err(e) => {
+                log::warn!("refresh secondary zone {} failed: {}", secondary.name, e);
+                let retry = self
+                    .with_zone(&secondary.name, |zone| zone.get_apex_rrset(RRType::SOA))
+                    .ok()
+                    .flatten()
+                    .and_then(|soa| soa_timers(&soa))
+                    .map_or(INITIAL_RETRY, |timers| timers.retry);
+                match *expire_at {
+                    Some(at) if at <= now => {
+                        log::warn!("secondary zone {} expired", secondary.name);
+                        let _ = self.with_zone_mut(&secondary.name, |zone| zone.set_expired(true));
+                        *expire_at = None;
+                        retry
+                    }
+                    //wake up in time to expire the zone
+                    Some(at) => retry.min(Duration::from_secs((at - now) as u64)),
+                    None => retry,
+                }
+            }
+        }
+    }
+
+    fn remote_key(&self, remote: &Remote) -> anyhow::Result<Option<TsigKey>> {
+        match remote.key {
+            Some(ref name) => match self.keyring.get(name) {
+                Some(key) => Ok(Some(key.clone())),
+                None => bail!("{}: unknown tsig key {}", remote.addr, name),
+            },
+            None => Ok(None),
+        }
+    }
+
+    //try the primaries in order until one of them answers
+    async fn refresh_secondary(&self, secondary: &SecondaryZone) -> anyhow::Result<SoaTimers> {
+        let serial = self.with_zone(&secondary.name, |zone| zone.serial())?;
+        let mut errors = Vec::new();
+        for primary in secondary.primaries.iter() {
+            let result = match self.remote_key(primary) {
+                Ok(key) => fetch_zone(primary.addr, key.as_ref(), &secondary.name, serial).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(transfer) => return self.apply_transfer(&secondary.name, transfer),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        bail!("{}", errors.join(", "))
+    }
+
+    //the new content is built aside and swapped in, so queries never see a
+    //half applied transfer
+    fn apply_transfer(&self, name: &Name, transfer: Transfer) -> anyhow::Result<SoaTimers> {
+        let zone = match transfer {
+            Transfer::UpToDate => None,
+            Transfer::Full(rrsets) => Some(MemoryZone::from_rrsets(name.clone(), rrsets)?),
+            Transfer::Incremental(diffs) => {
+                let (rrsets, mut history) =
+                    self.with_zone(name, |zone| (zone.rrsets(), zone.diffs()))?;
+                let mut zone = MemoryZone::from_rrsets(name.clone(), apply_diffs(rrsets, &diffs)?)?;
+                history.extend(diffs);
+                zone.push_diffs(history);
+                Some(zone)
+            }
+        };
+        let changed = zone.is_some();
+        let soa = self.with_zone_mut(name, |old| {
+            if let Some(mut zone) = zone {
+                zone.set_secondary(true);
+                zone.set_allow_transfer(old.allow_transfer().clone());
+                zone.set_also_notify(old.also_notify().to_vec());
+                *old = zone;
+            }
+            old.set_expired(false);
+            old.get_apex_rrset(RRType::SOA)
+        })?;
+        if changed {
+            self.notifier.push(name.clone());
+        }
+        soa.and_then(|soa| soa_timers(&soa))
+            .ok_or_else(|| anyhow::anyhow!("invalid soa of zone {}", name))
+    }
+
+    pub fn allow_transfer(&self, zone: &Name) -> anyhow::Result<Vec<String>> {
+        self.with_zone(zone, |zone| zone.allow_transfer().to_strings())
+    }
+
+    //replace the address prefixes allowed to transfer the zone
+    pub fn set_allow_transfer<T: AsRef<str>>(&self, zone: &Name, acl: &[T]) -> anyhow::Result<()> {
+        let acl = Acl::new(acl)?;
+        self.commit(Operation::SetAllowTransfer {
+            zone: zone.to_string(),
+            acl: acl.to_strings(),
+        })
+    }
+
+    pub fn allow_update(&self, zone: &Name) -> anyhow::Result<Vec<String>> {
+        self.with_zone(zone, |zone| zone.allow_update().to_strings())
+    }
+
+    //replace the address prefixes allowed to send dynamic update
+    pub fn set_allow_update<T: AsRef<str>>(&self, zone: &Name, acl: &[T]) -> anyhow::Result<()> {
+        let acl = Acl::new(acl)?;
+        self.commit(Operation::SetAllowUpdate {
+            zone: zone.to_string(),
+            acl: acl.to_strings(),
+        })
+    }
+
+    //rfc2136 update, prerequisites are checked and the changes are computed
+    //under the store lock, then applied as one journaled operation with one
+    //serial bump
+    pub fn update(&self, update: &Update, client: &Client) -> Rcode {
+        let mut store = self.store.lock().unwrap();
+        let origin = &update.zone;
+        let result = self.with_zone(origin, |zone| {
+            if zone.is_secondary() {
+                return Err(Rcode::NotImp);
+            }
+            if !zone.allow_update().allows(client.ip, client.key.as_deref()) {
+                return Err(Rcode::Refused);
+            }
+            check_prerequisites(zone, origin, &update.prerequisites)?;
+            prescan(origin, &update.updates)?;
+            Ok((apply_updates(zone, origin, &update.updates), zone.serial()))
+        });
+        let (changes, serial) = match result {
+            Ok(Ok((changes, Some(serial)))) => (changes, serial),
+            Ok(Ok((_, None))) => return Rcode::ServFail,
+            Ok(Err(rcode)) => return rcode,
+            Err(_) => return Rcode::NotAuth,
+        };
+        if changes.is_empty() {
+            return Rcode::NoError;
+        }
+
+        //newer serial in the soa of update is kept like the one set through api
+        let serial = self.serial_policy.next(serial, Utc::now());
+        let serial = changes
+            .iter()
+            .find(|(_, typ, _)| *typ == RRType::SOA)
+            .and_then(|(_, _, soa)| soa.as_ref())
+            .and_then(soa_serial)
+            .filter(|new| serial_gt(*new, serial))
+            .unwrap_or(serial);
+        let op = Operation::UpdateRRsets {
+            zone: origin.to_string(),
+            changes: changes
+                .into_iter()
+                .map(|(name, typ, rrset)| RRsetChange {
+                    name: name.to_string(),
+                    typ: typ.to_string(),
+                    rrset: rrset.map(|rrset| rrset.to_string()),
+                })
+                .collect(),
+            serial,
+        };
+        match self.do_commit(&mut store, op) {
+            Ok(_) => {
+                self.notifier.push(origin.clone());
+                Rcode::NoError
+            }
+            Err(_) => Rcode::ServFail,
+        }
+    }
+
+    pub fn also_notify(&self, zone: &Name) -> anyhow::Result<Vec<String>> {
+        self.with_zone(zone, |zone| {
+            zone.also_notify()
+                .iter()
+                .map(|remote| remote.to_string())
+                .collect()
+        })
+    }
+
+    //replace the secondaries notified when the zone changes
+    pub fn set_also_notify<T: AsRef<str>>(&self, zone: &Name, targets: &[T]) -> anyhow::Result<()> {
+        let targets = parse_remotes(targets)?;
+        self.commit(Operation::SetAlsoNotify {
+            zone: zone.to_string(),
+            targets: targets.iter().map(|remote| remote.to_string()).collect(),
+        })
+    }
+
+    //sign the zone with a new ksk and zsk of the algorithm, which replace
+    //the keys in use. negative answers are proved by nsec3 with the
+    //parameters, or by nsec without them
+    pub fn sign_zone(
+        &self,
+        zone: &Name,
+        algorithm: DnssecAlgorithm,
+        nsec3: Option<Nsec3Config>,
+    ) -> anyhow::Result<()> {
+        if let Some(ref nsec3) = nsec3 {
+            nsec3.salt()?;
+        }
+        self.commit_keys(zone, generate_keys(algorithm, now())?, nsec3)
+    }
+
+    fn commit_keys(
+        &self,
+        zone: &Name,
+        keys: Vec<ZoneKey>,
+        nsec3: Option<Nsec3Config>,
+    ) -> anyhow::Result<()> {
+        self.commit_zone_change(zone, |serial| Operation::SetDnssecKeys {
+            zone: zone.to_string(),
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            nsec3,
+            serial,
+        })
+    }
+
+    //copy of the keys of the zone to be changed
+    fn zone_keys(&self, zone: &Name) -> anyhow::Result<(Vec<ZoneKey>, Option<Nsec3Config>)> {
+        let (keys, nsec3) = self.with_zone(zone, |zone| {
+            let keys = zone
+                .keys()
+                .iter()
+                .map(|key| key.to_string())
+                .collect::<Vec<_>>();
+            (keys, zone.nsec3().cloned())
+        })?;
+        Ok((parse_keys(&keys)?, nsec3))
+    }
+
+    //start the rollover of the ksk or zsk now, the new key goes through
+    //its states by the rollover policy
+    pub fn rollover(&self, zone: &Name, role: KeyRole) -> anyhow::Result<()> {
+        let (mut keys, nsec3) = self.zone_keys(zone)?;
+        start_rollover(&mut keys, role, now())?;
+        self.commit_keys(zone, keys, nsec3)
+    }
+
+    //move the keys of the signed zones through the rollovers due at now,
+    //now is given to test the timing
+    pub fn roll_keys(&self, now: u32) {
+        for zone in self.zones() {
+            if let Err(e) = self.roll_zone_keys(&zone, now) {
+                log::warn!("key rollover of {} failed: {}", zone, e);
+            }
+        }
+    }
+
+    fn roll_zone_keys(&self, zone: &Name, now: u32) -> anyhow::Result<()> {
+        let (mut keys, nsec3) = self.zone_keys(zone)?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let ttls = self.with_zone(zone, zone_ttls)?;
+        if self.rollover_policy.roll(&mut keys, &ttls, now)? {
+            self.commit_keys(zone, keys, nsec3)?;
+        }
+        Ok(())
+    }
+
+    //sign the rrsets of the signed zones again before their signatures
+    //expire, now is given to test the timing
+    pub fn refresh_signatures(&self, now: u32) {
+        for zone in self.zones() {
+            if let Err(e) = self.refresh_zone_signatures(&zone, now) {
+                log::warn!("refresh signatures of {} failed: {}", zone, e);
+            }
+        }
+    }
+
+    fn refresh_zone_signatures(&self, zone: &Name, now: u32) -> anyhow::Result<()> {
+        if !self.with_zone(zone, |zone| zone.signatures_expiring(now))? {
+            return Ok(());
+        }
+        self.commit_zone_change(zone, |serial| Operation::RefreshSignatures {
+            zone: zone.to_string(),
+            now,
+            serial,
+        })
+    }
+
+    pub async fn run_rollover(&self) {
+        let mut interval = time::interval(ROLLOVER_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = now();
+            self.roll_keys(now);
+            self.refresh_signatures(now);
+        }
+    }
+
+    //drop the keys and all the signatures of the zone
+    pub fn unsign_zone(&self, zone: &Name) -> anyhow::Result<()> {
+        if !self.with_zone(zone, |zone| zone.is_signed())? {
+            return Ok(());
+        }
+        self.commit_keys(zone, Vec::new(), None)
+    }
+
+    //None if the zone isn't signed
+    pub fn dnssec(&self, name: &Name) -> anyhow::Result<Option<DnssecStatus>> {
+        self.with_zone(name, |zone| {
+            let ttl = zone.get_apex_rrset(RRType::DNSKEY)?.ttl.0;
+            let keys = zone.keys();
+            let ttls = zone_ttls(zone);
+            Some(DnssecStatus {
+                algorithm: keys.first()?.algorithm().to_string(),
+                ds: keys
+                    .iter()
+                    .filter(|key| key.is_ksk())
+                    .map(|key| key.ds(name, ttl))
+                    .collect(),
+                nsec3: zone.nsec3().cloned(),
+                keys: keys
+                    .iter()
+                    .map(|key| KeyStatus {
+                        tag: key.tag(),
+                        role: key.role(),
+                        state: key.state(),
+                        since: key.since(),
+                        next: self.rollover_policy.next_change(key, &ttls),
+                    })
+                    .collect(),
+            })
+        })
+    }
+
+    //send notify to the also-notify targets of the zones whose serial changed
+    pub async fn run_notify(&self) {
+        loop {
+            for zone in self.notifier.pop().await {
+                let targets = match self.with_zone(&zone, |zone| zone.also_notify().to_vec()) {
+                    Ok(targets) => targets,
+                    Err(_) => continue,
+                };
+                for target in targets {
+                    let key = match self.remote_key(&target) {
+                        Ok(key) => key,
+                        Err(e) => {
+                            log::warn!("notify {} failed: {}", zone, e);
+                            continue;
+                        }
+                    };
+                    let zone = zone.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = send_notify(target.addr, &zone, key).await {
+                            log::warn!("notify {} failed: {}", zone, e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    //serial of the soa set through api is kept if it's newer than the one
+    //from serial policy
     pub fn add_rrset(&self, zone: &Name, rrset: RRset) -> anyhow::Result<()> {
+        let serial_in_soa = if rrset.typ == RRType::SOA {
+            soa_serial(&rrset)
+        } else {
+            None
+        };
+        self.commit_zone_change(zone, |serial| Operation::AddRRset {
+            zone: zone.to_string(),
+            rrset: rrset.to_string(),
+            serial: match serial_in_soa {
+                Some(new) if serial_gt(new, serial) => new,
+                _ => serial,
+            },
+        })
+    }
+
+    pub fn delete_zone(&self, name: &Name) -> anyhow::Result<()> {
+        self.commit(Operation::DeleteZone {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn get_rrsets(&self, zone: &Name) -> anyhow::Result<Vec<RRset>> {
+        self.with_zone(zone, |zone| zone.rrsets())
+    }
+
+    pub fn get_rrset(&self, zone: &Name, name: &Name, typ: RRType) -> anyhow::Result<RRset> {
+        self.with_zone(zone, |zone| zone.get_rrset(name, typ))?
+            .ok_or_else(|| AuthError::UnknownRRset.into())
+    }
+
+    pub fn delete_rrset(&self, zone: &Name, name: &Name, typ: RRType) -> anyhow::Result<()> {
+        self.commit_zone_change(zone, |serial| Operation::DeleteRRset {
+            zone: zone.to_string(),
+            name: name.to_string(),
+            typ: typ.to_string(),
+            serial,
+        })
+    }
+
+    //delete the rrs in rrset, rrset itself is deleted with its last rr
+    pub fn delete_rr(&self, zone: &Name, rrset: RRset) -> anyhow::Result<()> {
+        self.commit_zone_change(zone, |serial| Operation::DeleteRR {
+            zone: zone.to_string(),
+            rrset: rrset.to_string(),
+            serial,
+        })
+    }
+
+    //journal the operation before applying it, the store lock also
+    //keeps the journal in the same order as the changes are applied
+    fn commit(&self, op: Operation) -> anyhow::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        self.do_commit(&mut store, op)
+    }
+
+    fn is_secondary(&self, zone: &Name) -> bool {
+        self.with_zone(zone, |zone| zone.is_secondary())
+            .unwrap_or(false)
+    }
+
+    //zone change is stamped with the next serial before it's journaled, so
+    //replay gets the same serial whatever the serial policy is
+    fn commit_zone_change<F: FnOnce(u32) -> Operation>(
+        &self,
+        zone: &Name,
+        f: F,
+    ) -> anyhow::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        let serial = self
+            .with_zone(zone, |zone| zone.serial())?
+            .ok_or_else(|| anyhow::anyhow!("no soa at zone apex"))?;
+        let op = f(self.serial_policy.next(serial, Utc::now()));
+        self.do_commit(&mut store, op)?;
+        self.notifier.push(zone.clone());
+        Ok(())
+    }
+
+    fn do_commit(&self, store: &mut Option<Store>, op: Operation) -> anyhow::Result<()> {
+        if self.is_secondary(&Name::new(op.zone())?) {
+            return Err(AuthError::SecondaryZone.into());
+        }
+        if let Some(ref mut store) = store {
+            store.append(&op)?;
+        }
+        //only the operations applied stay in the journal, so replay never
+        //meets a failed one
+        let result = self.apply(&op);
+        if result.is_err() {
+            if let Some(ref mut store) = store {
+                store.discard_last()?;
+            }
+        }
+        result
+    }
+
+    fn apply(&self, op: &Operation) -> anyhow::Result<()> {
+        match op {
+            Operation::AddZone {
+                name,
+                content,
+                allow_transfer,
+                also_notify,
+                allow_update,
+                dnssec_keys,
+                nsec3,
+            } => self.do_add_zone(&ZoneSnapshot {
+                name: name.clone(),
+                content: content.clone(),
+                allow_transfer: allow_transfer.clone(),
+                also_notify: also_notify.clone(),
+                allow_update: allow_update.clone(),
+                dnssec_keys: dnssec_keys.clone(),
+                nsec3: nsec3.clone(),
+            }),
+            Operation::AddRRset {
+                zone,
+                rrset,
+                serial,
+            } => {
+                let rrs = rrset.lines().collect::<Vec<&str>>();
+                self.do_add_rrset(&Name::new(zone)?, RRset::from_strs(&rrs)?, *serial)
+            }
+            Operation::DeleteZone { name } => self.do_delete_zone(&Name::new(name)?),
+            Operation::DeleteRRset {
+                zone,
+                name,
+                typ,
+                serial,
+            } => {
+                let (name, typ) = (Name::new(name)?, typ.parse::<RRType>()?);
+                self.with_zone_mut(&Name::new(zone)?, |zone| {
+                    zone.update(&name, typ, *serial, |zone| zone.delete_rrset(&name, typ))
+                })?
+            }
+            Operation::DeleteRR {
+                zone,
+                rrset,
+                serial,
+            } => {
+                let rrs = rrset.lines().collect::<Vec<&str>>();
+                let rrset = RRset::from_strs(&rrs)?;
+                self.with_zone_mut(&Name::new(zone)?, |zone| {
+                    zone.update(&rrset.name, rrset.typ, *serial, |zone| {
+                        zone.delete_rr(&rrset)
+                    })
+                })?
+            }
+            Operation::SetAllowTransfer { zone, acl } => {
+                let acl = Acl::new(acl)?;
+                self.with_zone_mut(&Name::new(zone)?, |zone| zone.set_allow_transfer(acl))
+            }
+            Operation::SetAlsoNotify { zone, targets } => {
+                let targets = parse_remotes(targets)?;
+                self.with_zone_mut(&Name::new(zone)?, |zone| zone.set_also_notify(targets))
+            }
+            Operation::SetAllowUpdate { zone, acl } => {
+                let acl = Acl::new(acl)?;
+                self.with_zone_mut(&Name::new(zone)?, |zone| zone.set_allow_update(acl))
+            }
+            Operation::UpdateRRsets {
+                zone,
+                changes,
+                serial,
+            } => {
+                let mut keys = Vec::with_capacity(changes.len());
+                let mut rrsets = Vec::with_capacity(changes.len());
+                for change in changes {
+                    keys.push((Name::new(&change.name)?, change.typ.parse::<RRType>()?));
+                    rrsets.push(match change.rrset {
+                        Some(ref rrset) => {
+                            let rrs = rrset.lines().collect::<Vec<&str>>();
+                            Some(RRset::from_strs(&rrs)?)
+                        }
+                        None => None,
+                    });
+                }
+                self.with_zone_mut(&Name::new(zone)?, |zone| {
+                    zone.update_rrsets(&keys, *serial, |zone| {
+                        for ((name, typ), rrset) in keys.iter().zip(rrsets) {
+                            match rrset {
+                                Some(rrset) => zone.add_rrset(rrset),
+                                None => zone.delete_rrset(name, *typ)?,
+                            }
+                        }
+                        Ok(())
+                    })
+                })?
+            }
+            Operation::SetDnssecKeys {
+                zone,
+                keys,
+                nsec3,
+                serial,
+            } => {
+                let keys = parse_keys(keys)?;
+                self.with_zone_mut(&Name::new(zone)?, |zone| {
+                    zone.update_keys(keys, nsec3.clone(), *serial)
+                })?
+            }
+            Operation::RefreshSignatures { zone, now, serial } => self
+                .with_zone_mut(&Name::new(zone)?, |zone| {
+                    zone.refresh_signatures(*now, *serial)
+                })?,
+        }
+    }
+
+    pub fn snapshot(&self) -> anyhow::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        if let Some(ref mut store) = *store {
+            if store.journal_len() > 0 {
+                store.snapshot(self.zone_snapshots())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn run_snapshot(&self) {
+        let mut interval = time::interval(SNAPSHOT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.snapshot() {
+                log::warn!("snapshot failed: {}", e);
+            }
+        }
+    }
+
+    fn zone_snapshots(&self) -> Vec<ZoneSnapshot> {
+        let zones = self.zones.read().unwrap();
+        let zone_names = self.zone_names.read().unwrap();
+        let mut snapshots = Vec::with_capacity(zone_names.len());
+        for name in zone_names.iter() {
+            let result = zones.find(name.name());
+            if result.flag == FindResultFlag::ExacatMatch {
+                if let Some(zone) = result.get_value().filter(|zone| !zone.is_secondary()) {
+                    snapshots.push(ZoneSnapshot {
+                        name: name.name().to_string(),
+                        content: zone.to_master_file(),
+                        allow_transfer: zone.allow_transfer().to_strings(),
+                        also_notify: zone
+                            .also_notify()
+                            .iter()
+                            .map(|remote| remote.to_string())
+                            .collect(),
+                        allow_update: zone.allow_update().to_strings(),
+                        dnssec_keys: zone.keys().iter().map(|key| key.to_string()).collect(),
+                        nsec3: zone.nsec3().cloned(),
+                    });
+                }
+            }
+        }
+        snapshots
+    }
+
+    fn do_add_zone(&self, snapshot: &ZoneSnapshot) -> anyhow::Result<()> {
+        let (name, zone) = zone_from_snapshot(snapshot)?;
+        self.insert_zone(name, zone)
+    }
+
+    //zone files are loaded before the data dir is restored, which decides
+    //whether the stored zone takes over
+    pub fn load_zone_file(&self, name: Name, path: &Path) -> anyhow::Result<()> {
+        let rrsets = ZoneFileParser::new(name.clone()).parse_file(path)?;
+        let zone = MemoryZone::from_rrsets(name.clone(), rrsets)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        self.insert_zone(name, zone)
+    }
+
+    //every file in the dir holds one zone, named after the file with
+    //the optional ".zone" suffix stripped, e.g. example.com.zone
+    pub fn load_zone_dir(&self, dir: &Path) -> anyhow::Result<()> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            let zone = file_name.strip_suffix(".zone").unwrap_or(&file_name);
+            let name = Name::new(zone).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            self.load_zone_file(name, &path)?;
+        }
+        Ok(())
+    }
+
+    pub fn export_zone(&self, name: &Name) -> anyhow::Result<String> {
+        self.with_zone(name, |zone| zone.to_master_file())
+    }
+
+    fn with_zone<T, F: FnOnce(&MemoryZone) -> T>(&self, name: &Name, f: F) -> anyhow::Result<T> {
+        let zones = self.zones.read().unwrap();
+        let result = zones.find(name);
+        if result.flag == FindResultFlag::ExacatMatch {
+            if let Some(zone) = result.get_value() {
+                return Ok(f(zone));
+            }
+        }
+        Err(AuthError::UnknownZone.into())
+    }
+
+    fn with_zone_mut<T, F: FnOnce(&mut MemoryZone) -> T>(
+        &self,
+        name: &Name,
+        f: F,
+    ) -> anyhow::Result<T> {
         let mut zones = self.zones.write().unwrap();
-        let result = zones.find(zone);
+        let result = zones.find(name);
         if result.flag == FindResultFlag::ExacatMatch {
             if let Some(zone) = result.get_value_mut() {
-                zone.add_rrset(rrset);
-                return Ok(());
+                return Ok(f(zone));
+            }
+        }
+        Err(AuthError::UnknownZone.into())
+    }
+
+    fn insert_zone(&self, name: Name, zone: MemoryZone) -> anyhow::Result<()> {
+        let mut zones = self.zones.write().unwrap();
+        let mut result = zones.find(&name);
+        if result.flag == FindResultFlag::ExacatMatch {
+            //node made by the tree for the common parent of other zones
+            if result.get_value().is_some() {
+                return Err(AuthError::DuplicateZone.into());
             }
+            *result.node.get_value_mut() = Some(zone);
+        } else {
+            zones.insert(name.clone(), Some(zone));
+        }
+        self.zone_names
+            .write()
+            .unwrap()
+            .insert(CanonicalName::new(name));
+        Ok(())
+    }
+
+    fn do_delete_zone(&self, name: &Name) -> anyhow::Result<()> {
+        let mut zones = self.zones.write().unwrap();
+        let result = zones.find(name);
+        if result.flag != FindResultFlag::ExacatMatch || result.get_value().is_none() {
+            return Err(AuthError::UnknownZone.into());
         }
+        let mut zone_names = self.zone_names.write().unwrap();
+        zone_names.remove(&CanonicalName::new(name.clone()));
 
-        bail!("unknown zone");
+        //nodes can't be removed from the tree, which is rebuilt with the
+        //remaining zones so the node of the deleted one doesn't linger
+        let mut tree = DomainTree::new();
+        for zone_name in zone_names.iter() {
+            let mut result = zones.find(zone_name.name());
+            let zone = result.node.get_value_mut().take();
+            tree.insert(zone_name.name().clone(), zone);
+        }
+        *zones = tree;
+        Ok(())
+    }
+
+    fn do_add_rrset(&self, zone: &Name, rrset: RRset, serial: u32) -> anyhow::Result<()> {
+        if !rrset.name.is_subdomain(zone) {
+            bail!("{} is out of zone {}", rrset.name, zone);
+        }
+        if rrset.typ == RRType::SOA && (!rrset.name.eq(zone) || rrset.rr_count() != 1) {
+            bail!("zone should have exactly one soa at zone apex");
+        }
+        let (name, typ) = (rrset.name.clone(), rrset.typ);
+        self.with_zone_mut(zone, |zone| {
+            zone.update(&name, typ, serial, |zone| {
+                zone.add_rrset(rrset);
+                Ok(())
+            })
+        })?
+    }
+}
+
+//zone added through the api has the same settings as its snapshot
+fn zone_from_snapshot(snapshot: &ZoneSnapshot) -> anyhow::Result<(Name, MemoryZone)> {
+    let name = Name::new(&snapshot.name)?;
+    let rrsets = ZoneFileParser::new(name.clone()).parse_str(&snapshot.content)?;
+    let mut zone = MemoryZone::from_rrsets(name.clone(), rrsets)?;
+    zone.set_allow_transfer(Acl::new(&snapshot.allow_transfer)?);
+    zone.set_also_notify(parse_remotes(&snapshot.also_notify)?);
+    zone.set_allow_update(Acl::new(&snapshot.allow_update)?);
+    let keys = parse_keys(&snapshot.dnssec_keys)?;
+    if !keys.is_empty() {
+        zone.set_keys(keys, snapshot.nsec3.clone())?;
+    }
+    Ok((name, zone))
+}
+
+fn zone_ttls(zone: &MemoryZone) -> ZoneTtls {
+    ZoneTtls {
+        dnskey: zone
+            .get_apex_rrset(RRType::DNSKEY)
+            .map_or(0, |dnskey| dnskey.ttl.0),
+        max: zone.max_ttl(),
     }
 }
 
+//rrset followed by its rrsigs for the client asking for dnssec records
+fn with_rrsig(zone: &MemoryZone, rrset: RRset, dnssec: bool) -> Vec<RRset> {
+    let rrsig = if dnssec { zone.get_rrsig(&rrset) } else { None };
+    let mut rrsets = vec![rrset];
+    rrsets.extend(rrsig);
+    rrsets
+}
+
 #[async_trait]
 impl Handler for Auth {
     async fn resolve(&mut self, req: Request) -> anyhow::Result<Response> {
         let zones = self.zones.read().unwrap();
-        let result = zones.find(&req.question.name);
         let mut resp = Response::with_question(req.question.name.clone(), req.question.typ);
 
+        //rrsigs are only sent to clients with the do bit
+        let dnssec = req.edns.as_ref().map_or(false, |edns| edns.dnssec_aware);
         let mut builder = ResponseBuilder::new(&mut resp);
         builder.id(req.header.id).make_response();
         builder.rcode(Rcode::Refused).done();
-        if result.flag == FindResultFlag::ExacatMatch || result.flag == FindResultFlag::PartialMatch
-        {
-            if let Some(zone) = result.get_value() {
-                match zone.find(&req.question.name, req.question.typ, FindMode::DefaultFind) {
-                    FindResult::Success(rrset) => {
-                        builder
-                            .rcode(Rcode::NoError)
-                            .add_rrset(SectionType::Answer, rrset)
-                            .done();
+
+        //cname is followed within the local zones, names already in the
+        //chain end it
+        let mut name = req.question.name.clone();
+        let mut chain = BTreeSet::new();
+        chain.insert(CanonicalName::new(name.clone()));
+        loop {
+            let result = zones.find(&name);
+            if result.flag != FindResultFlag::ExacatMatch
+                && result.flag != FindResultFlag::PartialMatch
+            {
+                break;
+            }
+            let zone = match result.get_value() {
+                Some(zone) => zone,
+                None => break,
+            };
+            if zone.is_expired() {
+                if chain.len() == 1 {
+                    builder.rcode(Rcode::ServFail).done();
+                    return Ok(resp);
+                }
+                break;
+            }
+            let target = match zone.find(&name, req.question.typ, FindMode::DefaultFind) {
+                FindResult::Success(rrset) => {
+                    builder.rcode(Rcode::NoError);
+                    //answer synthesized from wildcard comes with the
+                    //proof that the name itself doesn't exist
+                    if dnssec {
+                        for rrset in zone.wildcard_proof(&rrset.name) {
+                            builder.add_rrset(SectionType::Authority, rrset);
+                        }
                     }
-                    FindResult::Delegation(rrset) => {
-                        if let Some(glues) = zone.get_glue_for_ns(&rrset) {
-                            for rrset in glues {
-                                builder.add_rrset(SectionType::Additional, rrset);
-                            }
+                    for rrset in with_rrsig(zone, rrset, dnssec) {
+                        builder.add_rrset(SectionType::Answer, rrset);
+                    }
+                    builder.done();
+                    None
+                }
+                FindResult::CName(cname) => {
+                    builder.rcode(Rcode::NoError);
+                    if dnssec {
+                        for rrset in zone.wildcard_proof(&cname.name) {
+                            builder.add_rrset(SectionType::Authority, rrset);
+                        }
+                    }
+                    let target = rdata_target(&cname).ok();
+                    for rrset in with_rrsig(zone, cname, dnssec) {
+                        builder.add_rrset(SectionType::Answer, rrset);
+                    }
+                    builder.done();
+                    target
+                }
+                //signed dname goes with the cname, which the validator
+                //synthesizes itself
+                FindResult::DName(dname, cname) => {
+                    for rrset in with_rrsig(zone, dname, dnssec) {
+                        builder.add_rrset(SectionType::Answer, rrset);
+                    }
+                    let target = match cname {
+                        Some(cname) => {
+                            let target = rdata_target(&cname).ok();
+                            builder
+                                .rcode(Rcode::NoError)
+                                .add_rrset(SectionType::Answer, cname);
+                            target
                         }
+                        None => {
+                            builder.rcode(Rcode::YXDomain);
+                            None
+                        }
+                    };
+                    builder.done();
+                    target
+                }
+                FindResult::Delegation(rrset) => {
+                    if let Some(glues) = zone.get_glue_for_ns(&rrset) {
+                        for rrset in glues {
+                            builder.add_rrset(SectionType::Additional, rrset);
+                        }
+                    }
 
-                        builder
-                            .rcode(Rcode::NoError)
-                            .add_rrset(SectionType::Authority, rrset)
-                            .done();
+                    //ds of a signed delegation, or the proof that
+                    //there is no ds
+                    let secure = match zone.get_rrset(&rrset.name, RRType::DS) {
+                        Some(ds) if dnssec => with_rrsig(zone, ds, dnssec),
+                        None if dnssec => zone.nodata_proof(&rrset.name),
+                        _ => Vec::new(),
+                    };
+                    builder
+                        .rcode(Rcode::NoError)
+                        .add_rrset(SectionType::Authority, rrset);
+                    for rrset in secure {
+                        builder.add_rrset(SectionType::Authority, rrset);
                     }
-                    FindResult::NXDomain => {
-                        let soa = zone.get_apex_rrset(RRType::SOA).unwrap();
-                        builder
-                            .rcode(Rcode::NXDomain)
-                            .add_rrset(SectionType::Authority, soa)
-                            .done();
+                    builder.done();
+                    None
+                }
+                FindResult::NXDomain => {
+                    let soa = zone.get_apex_rrset(RRType::SOA).unwrap();
+                    builder.rcode(Rcode::NXDomain);
+                    for rrset in with_rrsig(zone, soa, dnssec) {
+                        builder.add_rrset(SectionType::Authority, rrset);
                     }
-                    FindResult::NXRRset => {
-                        builder.rcode(Rcode::NoError).done();
+                    if dnssec {
+                        for rrset in zone.nxdomain_proof(&name) {
+                            builder.add_rrset(SectionType::Authority, rrset);
+                        }
+                    }
+                    builder.done();
+                    None
+                }
+                FindResult::NXRRset => {
+                    builder.rcode(Rcode::NoError);
+                    //signed soa and the denial records for the
+                    //validator to tell the answer is authentic
+                    if dnssec {
+                        let soa = zone.get_apex_rrset(RRType::SOA).unwrap();
+                        for rrset in with_rrsig(zone, soa, dnssec) {
+                            builder.add_rrset(SectionType::Authority, rrset);
+                        }
+                        for rrset in zone.nodata_proof(&name) {
+                            builder.add_rrset(SectionType::Authority, rrset);
+                        }
                     }
+                    builder.done();
+                    None
                 }
+            };
+            match target {
+                Some(target)
+                    if chain.len() < MAX_CNAME_CHAIN
+                        && chain.insert(CanonicalName::new(target.clone())) =>
+                {
+                    name = target
+                }
+                _ => break,
             }
         }
         Ok(resp)
     }
+
+    fn keyring(&self) -> Option<&TsigKeyring> {
+        Some(&self.keyring)
+    }
+
+    //notify is accepted for secondary zones from their primaries, and the
+    //zone is refreshed right away. notify from a primary with a key has to
+    //be signed with the key
+    async fn notify(&mut self, req: &Request, client: &Client) -> Rcode {
+        let secondaries = self.secondaries.read().unwrap();
+        let secondary = match secondaries
+            .iter()
+            .find(|secondary| secondary.name.eq(&req.question.name))
+        {
+            Some(secondary) => secondary,
+            None => return Rcode::NotAuth,
+        };
+        let allowed = secondary.primaries.iter().any(|primary| {
+            Acl::new(&[primary.addr.ip().to_string()]).map_or(false, |acl| {
+                acl.contains(client.ip) && (primary.key.is_none() || primary.key == client.key)
+            })
+        });
+        if allowed {
+            secondary.refresh.notify_one();
+            Rcode::NoError
+        } else {
+            Rcode::Refused
+        }
+    }
+
+    async fn update(&mut self, update: Update, client: &Client) -> Rcode {
+        Auth::update(self, &update, client)
+    }
+
+    //axfr of the zone at query name, soa is the first and last record. ixfr
+    //gets the difference sequences, or the whole zone like axfr when the
+    //serial is older than the recorded changes
+    async fn transfer(
+        &mut self,
+        req: &Request,
+        client: &Client,
+        serial: Option<u32>,
+    ) -> Result<Vec<RRset>, Rcode> {
+        let zones = self.zones.read().unwrap();
+        let result = zones.find(&req.question.name);
+        if result.flag != FindResultFlag::ExacatMatch {
+            return Err(Rcode::NotAuth);
+        }
+        let zone = result.get_value().ok_or(Rcode::NotAuth)?;
+        if zone.is_expired() {
+            return Err(Rcode::ServFail);
+        }
+        if !zone
+            .allow_transfer()
+            .allows(client.ip, client.key.as_deref())
+        {
+            return Err(Rcode::Refused);
+        }
+        let soa = zone.get_apex_rrset(RRType::SOA).ok_or(Rcode::ServFail)?;
+        if req.question.typ == RRType::IXFR {
+            let serial = serial.ok_or(Rcode::FormErr)?;
+            let current = soa_serial(&soa).ok_or(Rcode::ServFail)?;
+            //client is up to date
+            if !serial_gt(current, serial) {
+                return Ok(vec![soa]);
+            }
+            if let Some(diffs) = zone.ixfr_diffs(serial) {
+                let mut rrsets = vec![soa.clone()];
+                rrsets.extend(diffs);
+                rrsets.push(soa);
+                return Ok(rrsets);
+            }
+        }
+        let mut rrsets = zone.rrsets();
+        rrsets.push(soa);
+        Ok(rrsets)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::dnssec::{rrsig_expiration, KeyState, SIGNATURE_REFRESH};
+    use super::super::serial::set_soa_serial;
+    use super::super::zone_content_generator::SoaConfig;
+    use super::*;
+    use crate::server::{build_edns, TcpServer, UdpServer, UpdateClass, UpdateRR};
+    use crate::test_util::temp_dir;
+    use std::str::FromStr;
+    use tokio::net::{TcpListener, UdpSocket};
+
+    fn zone_config(ip: &str) -> ZoneConfig {
+        ZoneConfig {
+            ips: vec![ip.to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_from_data_dir() {
+        let dir = temp_dir("auth");
+        let zone = Name::new("example.com").unwrap();
+        let www = Name::new("www.example.com").unwrap();
+        let ftp = Name::new("ftp.example.com").unwrap();
+
+        let auth = Auth::new().restore(&dir).unwrap();
+        auth.add_zone(zone.clone(), &zone_config("1.1.1.1"))
+            .unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+        )
+        .unwrap();
+        auth.snapshot().unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("ftp.example.com. 3600 IN A 3.3.3.3").unwrap(),
+        )
+        .unwrap();
+        assert!(auth
+            .add_zone(zone.clone(), &zone_config("1.1.1.1"))
+            .is_err());
+        drop(auth);
+
+        let mut auth = Auth::new().restore(&dir).unwrap();
+        assert_eq!(auth.zones(), vec![zone.clone()]);
+        for name in [www, ftp] {
+            let resp = auth.resolve(Request::new(name, RRType::A)).await.unwrap();
+            assert_eq!(resp.header.rcode, Rcode::NoError);
+            assert_eq!(resp.header.an_count, 1);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_over_zone_file() {
+        let dir = temp_dir("auth-file");
+        let data_dir = dir.join("data");
+        let path = dir.join("example.com.zone");
+        let zone = Name::new("example.com").unwrap();
+        let www = Name::new("www.example.com").unwrap();
+        let zone_file = |serial: u32| {
+            format!(
+                "example.com. 3600 IN SOA ns1.example.com. root.example.com. {} 3600 900 604800 300\n\
+                 example.com. 3600 IN NS ns1.example.com.\n\
+                 ns1.example.com. 3600 IN A 1.1.1.1\n",
+                serial
+            )
+        };
+        let open = || {
+            let auth = Auth::new();
+            auth.load_zone_file(zone.clone(), &path).unwrap();
+            auth.restore(&data_dir).unwrap()
+        };
+
+        fs::write(&path, zone_file(1)).unwrap();
+        let auth = open();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+        )
+        .unwrap();
+        drop(auth);
+
+        //change made through api survives the restart
+        let mut auth = open();
+        let resp = auth
+            .resolve(Request::new(www.clone(), RRType::A))
+            .await
+            .unwrap();
+        assert_eq!(resp.header.an_count, 1);
+        drop(auth);
+
+        //zone file with newer serial replaces the stored zone
+        fs::write(&path, zone_file(10)).unwrap();
+        let mut auth = open();
+        let resp = auth
+            .resolve(Request::new(www.clone(), RRType::A))
+            .await
+            .unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NXDomain);
+        drop(auth);
+        let mut auth = open();
+        let resp = auth.resolve(Request::new(www, RRType::A)).await.unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NXDomain);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_restored() {
+        let dir = temp_dir("auth-delete");
+        let com = Name::new("com").unwrap();
+        let net = Name::new("net").unwrap();
+        let www = Name::new("www.com").unwrap();
+
+        let auth = Auth::new().restore(&dir).unwrap();
+        auth.add_zone(com.clone(), &zone_config("1.1.1.1")).unwrap();
+        auth.add_zone(net.clone(), &zone_config("1.1.1.1")).unwrap();
+        auth.add_rrset(
+            &com,
+            RRset::from_strs(&["www.com. 3600 IN A 2.2.2.2", "www.com. 3600 IN A 3.3.3.3"])
+                .unwrap(),
+        )
+        .unwrap();
+        auth.delete_rr(&com, RRset::from_str("www.com. 3600 IN A 2.2.2.2").unwrap())
+            .unwrap();
+        auth.delete_zone(&net).unwrap();
+        let err = auth.delete_zone(&net).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AuthError>(),
+            Some(&AuthError::UnknownZone)
+        );
+        drop(auth);
+
+        let auth = Auth::new().restore(&dir).unwrap();
+        assert_eq!(auth.zones(), vec![com.clone()]);
+        let rrset = auth.get_rrset(&com, &www, RRType::A).unwrap();
+        assert_eq!(rrset.to_string(), "www.com.\t3600\tIN\tA\t3.3.3.3\n");
+        auth.delete_rrset(&com, &www, RRType::A).unwrap();
+        let err = auth.get_rrset(&com, &www, RRType::A).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AuthError>(),
+            Some(&AuthError::UnknownRRset)
+        );
+
+        auth.add_zone(net.clone(), &zone_config("1.1.1.1")).unwrap();
+        assert_eq!(auth.zones(), vec![com, net]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recreate_zone() {
+        let mut auth = Auth::new();
+        let com = Name::new("com").unwrap();
+        let zone = Name::new("example.com").unwrap();
+        auth.add_zone(zone.clone(), &zone_config("1.1.1.1"))
+            .unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            auth.add_zone(com.clone(), &zone_config("1.1.1.1")).unwrap();
+            auth.add_rrset(&com, RRset::from_str("www.com. 3600 IN A 3.3.3.3").unwrap())
+                .unwrap();
+            for name in ["www.com", "www.example.com"] {
+                let req = Request::new(Name::new(name).unwrap(), RRType::A);
+                let resp = auth.resolve(req).await.unwrap();
+                assert_eq!(resp.header.rcode, Rcode::NoError);
+                assert_eq!(resp.header.an_count, 1);
+            }
+
+            auth.delete_zone(&com).unwrap();
+            assert!(auth.zones.read().unwrap().find(&com).flag != FindResultFlag::ExacatMatch);
+            assert_eq!(auth.zones(), vec![zone.clone()]);
+            let req = Request::new(Name::new("www.com").unwrap(), RRType::A);
+            let resp = auth.resolve(req).await.unwrap();
+            assert_eq!(resp.header.rcode, Rcode::Refused);
+            let req = Request::new(Name::new("www.example.com").unwrap(), RRType::A);
+            let resp = auth.resolve(req).await.unwrap();
+            assert_eq!(resp.header.an_count, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serial_bump() {
+        let dir = temp_dir("auth-serial");
+        let com = Name::new("com").unwrap();
+        let www = Name::new("www.com").unwrap();
+        let serial =
+            |auth: &Auth| soa_serial(&auth.get_rrset(&com, &com, RRType::SOA).unwrap()).unwrap();
+
+        let auth = Auth::new()
+            .restore(&dir)
+            .unwrap()
+            .serial_policy(SerialPolicy::Date);
+        auth.add_zone(com.clone(), &zone_config("1.1.1.1")).unwrap();
+        assert_eq!(serial(&auth), 1);
+        auth.add_rrset(&com, RRset::from_str("www.com. 3600 IN A 2.2.2.2").unwrap())
+            .unwrap();
+        let first = serial(&auth);
+        assert!(first > 2000010100);
+        auth.delete_rrset(&com, &www, RRType::A).unwrap();
+        assert_eq!(serial(&auth), first + 1);
+
+        //failed change doesn't bump serial
+        assert!(auth.delete_rrset(&com, &www, RRType::A).is_err());
+        assert_eq!(serial(&auth), first + 1);
+
+        //newer serial in soa set through api is kept, older one is bumped
+        let soa = auth.get_rrset(&com, &com, RRType::SOA).unwrap();
+        auth.add_rrset(&com, set_soa_serial(&soa, 4000000000).unwrap())
+            .unwrap();
+        assert_eq!(serial(&auth), 4000000000);
+        auth.add_rrset(&com, set_soa_serial(&soa, 5).unwrap())
+            .unwrap();
+        assert_eq!(serial(&auth), 4000000001);
+        drop(auth);
+
+        //replay gets the same serial with a different policy
+        let auth = Auth::new().restore(&dir).unwrap();
+        assert_eq!(serial(&auth), 4000000001);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn rcode_of(auth: &Auth, name: &str) -> Rcode {
+        let req = Request::new(Name::new(name).unwrap(), RRType::A);
+        auth.clone().resolve(req).await.unwrap().header.rcode
+    }
+
+    async fn wait_until<F: Fn() -> bool>(f: F) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("timeout");
+    }
+
+    #[tokio::test]
+    async fn test_secondary_zone() {
+        let zone = Name::new("example.com").unwrap();
+        let www = Name::new("www.example.com").unwrap();
+        let ftp = Name::new("ftp.example.com").unwrap();
+        let primary = Auth::new();
+        let config = ZoneConfig {
+            soa: SoaConfig {
+                refresh: Some(1),
+                retry: Some(1),
+                expire: Some(3),
+                ..Default::default()
+            },
+            allow_transfer: vec!["127.0.0.1".to_string()],
+            ..zone_config("1.1.1.1")
+        };
+        primary.add_zone(zone.clone(), &config).unwrap();
+        primary
+            .add_rrset(
+                &zone,
+                RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = {
+            let primary = primary.clone();
+            tokio::spawn(async move { TcpServer::new(primary).serve(listener).await })
+        };
+
+        let secondary = Auth::new();
+        secondary
+            .add_secondary_zone(zone.clone(), &[addr.to_string()])
+            .unwrap();
+        assert_eq!(
+            rcode_of(&secondary, "www.example.com").await,
+            Rcode::ServFail
+        );
+        //refresh timers are driven with now instead of run_secondaries
+        let secondary_zone = secondary.secondaries.read().unwrap()[0].clone();
+        let mut expire_at = None;
+        let now = now();
+        let wait = secondary
+            .refresh_secondary_at(&secondary_zone, &mut expire_at, now)
+            .await;
+        assert_eq!(wait, Duration::from_secs(1));
+        assert_eq!(expire_at, Some(now + 3));
+        assert_eq!(
+            rcode_of(&secondary, "www.example.com").await,
+            Rcode::NoError
+        );
+        let err = secondary
+            .add_rrset(
+                &zone,
+                RRset::from_str("a.example.com. 3600 IN A 2.2.2.2").unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AuthError>(),
+            Some(&AuthError::SecondaryZone)
+        );
+
+        //changes on primary are pulled by ixfr
+        primary
+            .add_rrset(
+                &zone,
+                RRset::from_str("ftp.example.com. 3600 IN A 3.3.3.3").unwrap(),
+            )
+            .unwrap();
+        primary.delete_rrset(&zone, &www, RRType::A).unwrap();
+        secondary
+            .refresh_secondary_at(&secondary_zone, &mut expire_at, now + 1)
+            .await;
+        assert!(secondary.get_rrset(&zone, &www, RRType::A).is_err());
+        assert!(secondary.get_rrset(&zone, &ftp, RRType::A).is_ok());
+        assert_eq!(
+            secondary.export_zone(&zone).unwrap(),
+            primary.export_zone(&zone).unwrap()
+        );
+        assert_eq!(
+            secondary
+                .with_zone(&zone, |zone| zone.diffs().len())
+                .unwrap(),
+            2
+        );
+
+        //zone expires when the primary is gone, retry wakes up in time
+        server.abort();
+        let _ = server.await;
+        let wait = secondary
+            .refresh_secondary_at(&secondary_zone, &mut expire_at, now + 2)
+            .await;
+        assert_eq!(wait, Duration::from_secs(1));
+        assert_eq!(
+            rcode_of(&secondary, "ftp.example.com").await,
+            Rcode::NoError
+        );
+        secondary
+            .refresh_secondary_at(&secondary_zone, &mut expire_at, now + 4)
+            .await;
+        assert_eq!(expire_at, None);
+        assert_eq!(
+            rcode_of(&secondary, "ftp.example.com").await,
+            Rcode::ServFail
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let zone = Name::new("example.com").unwrap();
+        let www = Name::new("www.example.com").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let secondary_addr = socket.local_addr().unwrap();
+        let secondary = Auth::new();
+        {
+            let secondary = secondary.clone();
+            tokio::spawn(async move { UdpServer::new(secondary).serve(socket).await });
+        }
+
+        //refresh is too long to pick up the change without notify
+        let primary = Auth::new();
+        let config = ZoneConfig {
+            allow_transfer: vec!["127.0.0.1".to_string()],
+            also_notify: vec![secondary_addr.to_string()],
+            ..zone_config("1.1.1.1")
+        };
+        primary.add_zone(zone.clone(), &config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_addr = listener.local_addr().unwrap();
+        {
+            let primary = primary.clone();
+            tokio::spawn(async move { TcpServer::new(primary).serve(listener).await });
+        }
+        {
+            let primary = primary.clone();
+            tokio::spawn(async move { primary.run_notify().await });
+        }
+
+        secondary
+            .add_secondary_zone(zone.clone(), &[primary_addr.to_string()])
+            .unwrap();
+        {
+            let secondary = secondary.clone();
+            tokio::spawn(async move { secondary.run_secondaries().await });
+        }
+        wait_until(|| {
+            secondary
+                .with_zone(&zone, |zone| !zone.is_expired())
+                .unwrap_or(false)
+        })
+        .await;
+
+        primary
+            .add_rrset(
+                &zone,
+                RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+            )
+            .unwrap();
+        wait_until(|| secondary.get_rrset(&zone, &www, RRType::A).is_ok()).await;
+
+        //notify is only accepted for secondary zones
+        let err = send_notify(secondary_addr, &Name::new("example.org").unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("NotAuth"), "{}", err);
+        assert!(send_notify(secondary_addr, &zone, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tsig() {
+        let zone = Name::new("example.com").unwrap();
+        let key = "xfr-key:hmac-sha256:c2VjcmV0LWtleS1vZi14ZnI=";
+        let keyring = TsigKeyring::new(vec![key.parse().unwrap()]);
+        let primary = Auth::new().tsig_keys(keyring.clone());
+        let config = ZoneConfig {
+            allow_transfer: vec!["key:xfr-key".to_string()],
+            allow_update: vec!["key:xfr-key".to_string()],
+            ..zone_config("1.1.1.1")
+        };
+        primary.add_zone(zone.clone(), &config).unwrap();
+        //large enough to be sent in several messages
+        for i in 0..300 {
+            let txt = format!("a{}.example.com. 3600 IN TXT \"{}\"", i, "a".repeat(250));
+            primary
+                .add_rrset(&zone, RRset::from_str(&txt).unwrap())
+                .unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        {
+            let primary = primary.clone();
+            tokio::spawn(async move { TcpServer::new(primary).serve(listener).await });
+        }
+
+        let secondary = Auth::new().tsig_keys(keyring.clone());
+        secondary
+            .add_secondary_zone(zone.clone(), &[format!("{}@xfr-key", addr)])
+            .unwrap();
+        {
+            let secondary = secondary.clone();
+            tokio::spawn(async move { secondary.run_secondaries().await });
+        }
+        wait_until(|| {
+            secondary.get_rrsets(&zone).map_or(0, |rrsets| rrsets.len())
+                == primary.get_rrsets(&zone).unwrap().len()
+        })
+        .await;
+
+        //unsigned, unknown key and wrong secret
+        let err = fetch_zone(addr, None, &zone, None).await.unwrap_err();
+        assert!(err.to_string().contains("Refused"), "{}", err);
+        let other = "other-key:hmac-sha256:c2VjcmV0LWtleS1vZi14ZnI="
+            .parse::<TsigKey>()
+            .unwrap();
+        let err = fetch_zone(addr, Some(&other), &zone, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("BADKEY"), "{}", err);
+        let wrong = "xfr-key:hmac-sha256:d3Jvbmctc2VjcmV0"
+            .parse::<TsigKey>()
+            .unwrap();
+        let err = fetch_zone(addr, Some(&wrong), &zone, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("BADSIG"), "{}", err);
+
+        //update from any address is allowed with the key
+        let update = Update {
+            zone: zone.clone(),
+            prerequisites: vec![],
+            updates: vec![UpdateRR {
+                name: Name::new("www.example.com").unwrap(),
+                typ: RRType::A,
+                class: UpdateClass::Any,
+                ttl: 0,
+                rdata: None,
+            }],
+        };
+        let mut client = Client {
+            ip: "10.0.0.1".parse().unwrap(),
+            key: None,
+        };
+        assert_eq!(primary.update(&update, &client), Rcode::Refused);
+        client.key = Some("xfr-key.".to_string());
+        assert_eq!(primary.update(&update, &client), Rcode::NoError);
+    }
+
+    #[test]
+    fn test_update() {
+        let zone = Name::new("example.com").unwrap();
+        let www = Name::new("www.example.com").unwrap();
+        let ftp = Name::new("ftp.example.com").unwrap();
+        let auth = Auth::new();
+        let config = ZoneConfig {
+            allow_update: vec!["127.0.0.0/8".to_string()],
+            ..zone_config("1.1.1.1")
+        };
+        auth.add_zone(zone.clone(), &config).unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+        )
+        .unwrap();
+        let serial = auth
+            .with_zone(&zone, |zone| zone.serial())
+            .unwrap()
+            .unwrap();
+
+        let rr = |s: &str, class: UpdateClass| {
+            let rrset = RRset::from_str(s).unwrap();
+            UpdateRR {
+                name: rrset.name.clone(),
+                typ: rrset.typ,
+                class,
+                ttl: rrset.ttl.0,
+                rdata: rrset.rdatas.first().cloned(),
+            }
+        };
+        let empty = |name: &Name, typ: RRType, class: UpdateClass| UpdateRR {
+            name: name.clone(),
+            typ,
+            class,
+            ttl: 0,
+            rdata: None,
+        };
+        let update = |prerequisites: Vec<UpdateRR>, updates: Vec<UpdateRR>| Update {
+            zone: zone.clone(),
+            prerequisites,
+            updates,
+        };
+        let client = |ip: &str| Client {
+            ip: ip.parse().unwrap(),
+            key: None,
+        };
+        let localhost = client("127.0.0.1");
+
+        let add_ftp = rr("ftp.example.com. 300 IN A 3.3.3.3", UpdateClass::Zone);
+        assert_eq!(
+            auth.update(&update(vec![], vec![add_ftp.clone()]), &client("10.0.0.1")),
+            Rcode::Refused
+        );
+        for (prerequisite, rcode) in [
+            (empty(&ftp, RRType::ANY, UpdateClass::Any), Rcode::NXDomain),
+            (empty(&www, RRType::ANY, UpdateClass::None), Rcode::YXDomain),
+            (empty(&www, RRType::AAAA, UpdateClass::Any), Rcode::NXRRset),
+            (empty(&www, RRType::A, UpdateClass::None), Rcode::YXRRset),
+            (
+                rr("www.example.com. 0 IN A 1.1.1.1", UpdateClass::Zone),
+                Rcode::NXRRset,
+            ),
+        ] {
+            let req = update(vec![prerequisite], vec![add_ftp.clone()]);
+            assert_eq!(auth.update(&req, &localhost), rcode);
+        }
+        let out_of_zone = rr("www.example.org. 300 IN A 3.3.3.3", UpdateClass::Zone);
+        assert_eq!(
+            auth.update(&update(vec![], vec![out_of_zone]), &localhost),
+            Rcode::NotZone
+        );
+        assert_eq!(
+            auth.with_zone(&zone, |zone| zone.serial()).unwrap(),
+            Some(serial)
+        );
+
+        //all the changes are applied as one version
+        let req = update(
+            vec![
+                rr("www.example.com. 0 IN A 2.2.2.2", UpdateClass::Zone),
+                empty(&ftp, RRType::ANY, UpdateClass::None),
+            ],
+            vec![
+                add_ftp,
+                rr("www.example.com. 3600 IN A 4.4.4.4", UpdateClass::Zone),
+                rr("www.example.com. 0 NONE A 2.2.2.2", UpdateClass::None),
+                //cname can't be added to a name with other data
+                rr(
+                    "ftp.example.com. 300 IN CNAME www.example.com.",
+                    UpdateClass::Zone,
+                ),
+                //ns at apex isn't deleted
+                empty(&zone, RRType::NS, UpdateClass::Any),
+            ],
+        );
+        assert_eq!(auth.update(&req, &localhost), Rcode::NoError);
+        assert_eq!(
+            auth.get_rrset(&zone, &www, RRType::A).unwrap().to_string(),
+            "www.example.com.\t3600\tIN\tA\t4.4.4.4\n"
+        );
+        assert!(auth.get_rrset(&zone, &ftp, RRType::A).is_ok());
+        assert!(auth.get_rrset(&zone, &ftp, RRType::CNAME).is_err());
+        assert!(auth.get_rrset(&zone, &zone, RRType::NS).is_ok());
+        assert_eq!(
+            auth.with_zone(&zone, |zone| zone.serial()).unwrap(),
+            Some(serial + 1)
+        );
+        let diffs = auth
+            .with_zone(&zone, |zone| zone.ixfr_diffs(serial))
+            .unwrap();
+        assert_eq!(diffs.unwrap().len(), 5);
+
+        //update without change doesn't bump serial
+        let req = update(vec![], vec![empty(&www, RRType::AAAA, UpdateClass::Any)]);
+        assert_eq!(auth.update(&req, &localhost), Rcode::NoError);
+        assert_eq!(
+            auth.with_zone(&zone, |zone| zone.serial()).unwrap(),
+            Some(serial + 1)
+        );
+        let unknown = Update {
+            zone: Name::new("example.org").unwrap(),
+            prerequisites: vec![],
+            updates: vec![],
+        };
+        assert_eq!(auth.update(&unknown, &localhost), Rcode::NotAuth);
+    }
+
+    #[tokio::test]
+    async fn test_dnssec() {
+        let dir = temp_dir("auth-dnssec");
+        let zone = Name::new("example.com").unwrap();
+        let www = Name::new("www.example.com").unwrap();
+        let query = |name: &Name, typ: RRType, dnssec: bool| {
+            let mut req = Request::new(name.clone(), typ);
+            req.edns = Some(build_edns(1232, dnssec));
+            req
+        };
+        let types = |resp: &Response, section: SectionType| {
+            resp.section(section)
+                .map(|rrsets| rrsets.iter().map(|rrset| rrset.typ).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+
+        let mut auth = Auth::new().restore(&dir).unwrap();
+        let config = ZoneConfig {
+            dnssec: Some("ed25519".to_string()),
+            ..zone_config("1.1.1.1")
+        };
+        auth.add_zone(zone.clone(), &config).unwrap();
+        let serial = auth
+            .with_zone(&zone, |zone| zone.serial())
+            .unwrap()
+            .unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("www.example.com. 300 IN A 2.2.2.2").unwrap(),
+        )
+        .unwrap();
+        let ds = auth.dnssec(&zone).unwrap().unwrap().ds;
+        assert_eq!(ds.len(), 1);
+
+        let resp = auth.resolve(query(&www, RRType::A, true)).await.unwrap();
+        assert_eq!(
+            types(&resp, SectionType::Answer),
+            vec![RRType::A, RRType::RRSIG]
+        );
+        let rrsig = &resp.section(SectionType::Answer).unwrap()[1];
+        assert_eq!((rrsig.rr_count(), rrsig.ttl.0), (1, 300));
+        let resp = auth.resolve(query(&www, RRType::A, false)).await.unwrap();
+        assert_eq!(types(&resp, SectionType::Answer), vec![RRType::A]);
+        let resp = auth
+            .resolve(query(&zone, RRType::DNSKEY, true))
+            .await
+            .unwrap();
+        assert_eq!(
+            types(&resp, SectionType::Answer),
+            vec![RRType::DNSKEY, RRType::RRSIG]
+        );
+        let resp = auth.resolve(query(&www, RRType::AAAA, true)).await.unwrap();
+        assert_eq!(
+            types(&resp, SectionType::Authority),
+            vec![RRType::SOA, RRType::RRSIG, RRType::NSEC, RRType::RRSIG]
+        );
+        let nsec = &resp.section(SectionType::Authority).unwrap()[2];
+        assert_eq!(nsec.name, www);
+        //nsec covering the name and the one covering the wildcard
+        let nx = Name::new("nx.example.com").unwrap();
+        let resp = auth.resolve(query(&nx, RRType::A, true)).await.unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NXDomain);
+        let authority = types(&resp, SectionType::Authority);
+        assert!(authority.len() >= 4);
+        assert!(authority[2..]
+            .chunks(2)
+            .all(|pair| pair == [RRType::NSEC, RRType::RRSIG]));
+
+        //wildcard answer is signed for the query name and comes with the
+        //nsec covering it
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("*.example.com. 300 IN TXT \"wild\"").unwrap(),
+        )
+        .unwrap();
+        let resp = auth.resolve(query(&nx, RRType::TXT, true)).await.unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NoError);
+        assert_eq!(
+            types(&resp, SectionType::Answer),
+            vec![RRType::TXT, RRType::RRSIG]
+        );
+        let answer = resp.section(SectionType::Answer).unwrap();
+        assert!(answer.iter().all(|rrset| rrset.name.eq(&nx)));
+        assert_eq!(
+            types(&resp, SectionType::Authority),
+            vec![RRType::NSEC, RRType::RRSIG]
+        );
+        //nodata from the wildcard, the nsec of the wildcard also covers
+        //the name
+        let resp = auth.resolve(query(&nx, RRType::A, true)).await.unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NoError);
+        assert_eq!(
+            types(&resp, SectionType::Authority),
+            vec![RRType::SOA, RRType::RRSIG, RRType::NSEC, RRType::RRSIG]
+        );
+        let nsec = &resp.section(SectionType::Authority).unwrap()[2];
+        assert_eq!(nsec.name.to_string(), "*.example.com.");
+        let wildcard = Name::new("*.example.com").unwrap();
+        auth.delete_rrset(&zone, &wildcard, RRType::TXT).unwrap();
+
+        //signatures follow the rrsets and go to ixfr with them
+        auth.delete_rrset(&zone, &www, RRType::A).unwrap();
+        assert!(auth.get_rrset(&zone, &www, RRType::RRSIG).is_err());
+        let diffs = auth
+            .with_zone(&zone, |zone| zone.ixfr_diffs(serial).unwrap())
+            .unwrap();
+        assert!(diffs.iter().any(|rrset| rrset.typ == RRType::RRSIG));
+        assert!(diffs
+            .iter()
+            .any(|rrset| rrset.typ == RRType::NSEC && rrset.name.eq(&www)));
+        assert!(auth
+            .add_rrset(
+                &zone,
+                RRset::from_str("example.com. 3600 IN DNSKEY 256 3 15 l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=").unwrap(),
+            )
+            .is_err());
+        drop(auth);
+
+        //keys are restored with the zone
+        let auth = Auth::new().restore(&dir).unwrap();
+        assert_eq!(auth.dnssec(&zone).unwrap().unwrap().ds, ds);
+        auth.snapshot().unwrap();
+        drop(auth);
+        let auth = Auth::new().restore(&dir).unwrap();
+        assert_eq!(auth.dnssec(&zone).unwrap().unwrap().ds, ds);
+
+        auth.sign_zone(&zone, DnssecAlgorithm::EcdsaP256Sha256, None)
+            .unwrap();
+        let status = auth.dnssec(&zone).unwrap().unwrap();
+        assert_eq!(status.algorithm, "ecdsap256sha256");
+        assert_ne!(status.ds, ds);
+        auth.unsign_zone(&zone).unwrap();
+        assert!(auth.dnssec(&zone).unwrap().is_none());
+        assert!(auth
+            .get_rrsets(&zone)
+            .unwrap()
+            .iter()
+            .all(|rrset| rrset.typ != RRType::RRSIG && rrset.typ != RRType::DNSKEY));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nsec3() {
+        let mut auth = Auth::new();
+        let zone = Name::new("example.com").unwrap();
+        let sub = Name::new("sub.example.com").unwrap();
+        let query = |name: &Name, typ: RRType| {
+            let mut req = Request::new(name.clone(), typ);
+            req.edns = Some(build_edns(1232, true));
+            req
+        };
+        let authority = |resp: &Response| {
+            resp.section(SectionType::Authority)
+                .map(|rrsets| rrsets.iter().map(|rrset| rrset.typ).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        let is_proof = |types: &[RRType]| {
+            !types.is_empty()
+                && types
+                    .chunks(2)
+                    .all(|pair| pair == [RRType::NSEC3, RRType::RRSIG])
+        };
+
+        let config = ZoneConfig {
+            dnssec: Some("ecdsap256sha256".to_string()),
+            nsec3: Some(Nsec3Config {
+                iterations: 1,
+                salt: "aabbccdd".to_string(),
+                opt_out: true,
+            }),
+            ..zone_config("1.1.1.1")
+        };
+        auth.add_zone(zone.clone(), &config).unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("a.b.example.com. 300 IN A 2.2.2.2").unwrap(),
+        )
+        .unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("sub.example.com. 300 IN NS ns.sub.example.com.").unwrap(),
+        )
+        .unwrap();
+        assert!(auth.get_rrset(&zone, &zone, RRType::NSEC3PARAM).is_ok());
+        assert_eq!(
+            auth.dnssec(&zone).unwrap().unwrap().nsec3,
+            config.nsec3.clone()
+        );
+        let rrsets = auth.get_rrsets(&zone).unwrap();
+        assert!(rrsets.iter().all(|rrset| rrset.typ != RRType::NSEC));
+        //apex, name servers, a.b, and the empty non-terminal b, the
+        //delegation without ds is left out by opt-out
+        let names = rrsets
+            .iter()
+            .filter(|rrset| !matches!(rrset.typ, RRType::NSEC3 | RRType::RRSIG))
+            .map(|rrset| CanonicalName::new(rrset.name.clone()))
+            .filter(|name| !name.name().eq(&sub))
+            .collect::<BTreeSet<_>>();
+        let nsec3s = rrsets
+            .iter()
+            .filter(|rrset| rrset.typ == RRType::NSEC3)
+            .count();
+        assert_eq!(nsec3s, names.len() + 1);
+
+        //empty non-terminal has its own nsec3
+        let b = Name::new("b.example.com").unwrap();
+        let resp = auth.resolve(query(&b, RRType::A)).await.unwrap();
+        let types = authority(&resp);
+        assert_eq!(&types[..2], &[RRType::SOA, RRType::RRSIG]);
+        assert_eq!(&types[2..], &[RRType::NSEC3, RRType::RRSIG]);
+
+        let nx = Name::new("x.b.example.com").unwrap();
+        let resp = auth.resolve(query(&nx, RRType::A)).await.unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NXDomain);
+        let types = authority(&resp);
+        assert!(types.len() >= 4 && is_proof(&types[2..]));
+
+        //insecure delegation is proved by the closest provable encloser
+        let www = Name::new("www.sub.example.com").unwrap();
+        let resp = auth.resolve(query(&www, RRType::A)).await.unwrap();
+        let types = authority(&resp);
+        assert_eq!(types[0], RRType::NS);
+        assert!(is_proof(&types[1..]));
+
+        //nsec3 is changed to nsec by signing again without parameters
+        auth.sign_zone(&zone, DnssecAlgorithm::Ed25519, None)
+            .unwrap();
+        let rrsets = auth.get_rrsets(&zone).unwrap();
+        assert!(rrsets
+            .iter()
+            .all(|rrset| rrset.typ != RRType::NSEC3 && rrset.typ != RRType::NSEC3PARAM));
+        assert!(rrsets.iter().any(|rrset| rrset.typ == RRType::NSEC));
+        assert!(auth
+            .sign_zone(
+                &zone,
+                DnssecAlgorithm::Ed25519,
+                Some(Nsec3Config {
+                    salt: "xyz".to_string(),
+                    ..Default::default()
+                })
+            )
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_rollover() {
+        let auth = Auth::new().rollover_policy(RolloverPolicy {
+            zsk_lifetime: 1000,
+            ksk_lifetime: 0,
+            propagation_delay: 10,
+            parent_ds_ttl: 500,
+        });
+        let zone = Name::new("example.com").unwrap();
+        let config = ZoneConfig {
+            dnssec: Some("ed25519".to_string()),
+            ..zone_config("1.1.1.1")
+        };
+        auth.add_zone(zone.clone(), &config).unwrap();
+        let count = |typ: RRType| auth.get_rrset(&zone, &zone, typ).unwrap().rr_count();
+        let states = || {
+            auth.dnssec(&zone)
+                .unwrap()
+                .unwrap()
+                .keys
+                .iter()
+                .map(|key| (key.role, key.state))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            (
+                count(RRType::DNSKEY),
+                count(RRType::CDS),
+                count(RRType::CDNSKEY)
+            ),
+            (2, 1, 1)
+        );
+
+        //new zsk is in dnskey before it signs
+        let zsk = auth.dnssec(&zone).unwrap().unwrap().keys[1].clone();
+        auth.roll_keys(zsk.next.unwrap() - 1);
+        assert_eq!(count(RRType::DNSKEY), 2);
+        auth.roll_keys(zsk.next.unwrap());
+        assert_eq!(count(RRType::DNSKEY), 3);
+        let next = auth.dnssec(&zone).unwrap().unwrap().keys[2].next.unwrap();
+        auth.roll_keys(next);
+        assert_eq!(
+            states(),
+            vec![
+                (KeyRole::Ksk, KeyState::Active),
+                (KeyRole::Zsk, KeyState::Retired),
+                (KeyRole::Zsk, KeyState::Active),
+            ]
+        );
+
+        //ds of the new ksk is published by cds before the ksk is in dnskey
+        auth.rollover(&zone, KeyRole::Ksk).unwrap();
+        assert!(auth.rollover(&zone, KeyRole::Ksk).is_err());
+        assert_eq!((count(RRType::DNSKEY), count(RRType::CDS)), (3, 2));
+        assert_eq!(auth.dnssec(&zone).unwrap().unwrap().ds.len(), 2);
+    }
+
+    #[test]
+    fn test_refresh_signatures() {
+        let auth = Auth::new();
+        let zone = Name::new("example.com").unwrap();
+        let config = ZoneConfig {
+            dnssec: Some("ed25519".to_string()),
+            ..zone_config("1.1.1.1")
+        };
+        auth.add_zone(zone.clone(), &config).unwrap();
+        let status = || {
+            auth.with_zone(&zone, |zone| {
+                let rrsigs = zone.get_apex_rrset(RRType::RRSIG).unwrap();
+                (zone.serial().unwrap(), rrsig_expiration(&rrsigs).unwrap())
+            })
+            .unwrap()
+        };
+        let (serial, expiration) = status();
+
+        //fresh signatures are left alone
+        auth.refresh_signatures(now());
+        assert_eq!(status(), (serial, expiration));
+
+        //signatures are made again once the refresh window is reached,
+        //as a new version of the zone
+        let later = expiration - SIGNATURE_REFRESH + 1;
+        auth.refresh_signatures(later);
+        let (new_serial, new_expiration) = status();
+        assert!(serial_gt(new_serial, serial));
+        assert!(new_expiration > expiration);
+        let diffs = auth
+            .with_zone(&zone, |zone| zone.ixfr_diffs(serial).unwrap())
+            .unwrap();
+        assert!(diffs.iter().any(|rrset| rrset.typ == RRType::RRSIG));
+        auth.refresh_signatures(later);
+        assert_eq!(status(), (new_serial, new_expiration));
+    }
+
+    #[tokio::test]
+    async fn test_cname_chain() {
+        let mut auth = Auth::new();
+        let com = Name::new("example.com").unwrap();
+        let org = Name::new("example.org").unwrap();
+        auth.add_zone(com.clone(), &zone_config("1.1.1.1")).unwrap();
+        auth.add_zone(org.clone(), &zone_config("2.2.2.2")).unwrap();
+        let long = format!(
+            "long.example.com. 300 IN DNAME {}.{}.{}.{}.example.org.",
+            "a".repeat(50),
+            "b".repeat(50),
+            "c".repeat(50),
+            "d".repeat(50)
+        );
+        for rrset in [
+            "www.example.com. 300 IN CNAME web.example.org.",
+            "loop1.example.com. 300 IN CNAME loop2.example.com.",
+            "loop2.example.com. 300 IN CNAME loop1.example.com.",
+            "ext.example.com. 300 IN CNAME www.example.net.",
+            "old.example.com. 300 IN DNAME example.org.",
+            &long,
+            "web.example.org. 300 IN A 3.3.3.3",
+        ] {
+            let rrset = RRset::from_str(rrset).unwrap();
+            let zone = if rrset.name.is_subdomain(&org) {
+                &org
+            } else {
+                &com
+            };
+            auth.add_rrset(zone, rrset).unwrap();
+        }
+        async fn resolve(auth: &mut Auth, name: &str, typ: RRType) -> (Rcode, Vec<RRType>) {
+            let req = Request::new(Name::new(name).unwrap(), typ);
+            let resp = auth.resolve(req).await.unwrap();
+            let answer = resp
+                .section(SectionType::Answer)
+                .map(|rrsets| rrsets.iter().map(|rrset| rrset.typ).collect::<Vec<_>>())
+                .unwrap_or_default();
+            (resp.header.rcode, answer)
+        }
+
+        //cname is followed into the other local zone
+        assert_eq!(
+            resolve(&mut auth, "www.example.com", RRType::A).await,
+            (Rcode::NoError, vec![RRType::CNAME, RRType::A])
+        );
+        assert_eq!(
+            resolve(&mut auth, "www.example.com", RRType::CNAME).await,
+            (Rcode::NoError, vec![RRType::CNAME])
+        );
+        assert_eq!(
+            resolve(&mut auth, "loop1.example.com", RRType::A).await,
+            (Rcode::NoError, vec![RRType::CNAME, RRType::CNAME])
+        );
+        assert_eq!(
+            resolve(&mut auth, "ext.example.com", RRType::A).await,
+            (Rcode::NoError, vec![RRType::CNAME])
+        );
+
+        //dname is answered with the cname synthesized for the name
+        assert_eq!(
+            resolve(&mut auth, "web.old.example.com", RRType::A).await,
+            (
+                Rcode::NoError,
+                vec![RRType::DNAME, RRType::CNAME, RRType::A]
+            )
+        );
+        assert_eq!(
+            resolve(&mut auth, "nx.old.example.com", RRType::A).await,
+            (Rcode::NXDomain, vec![RRType::DNAME, RRType::CNAME])
+        );
+        assert_eq!(
+            resolve(&mut auth, "old.example.com", RRType::A).await,
+            (Rcode::NoError, Vec::new())
+        );
+        assert_eq!(
+            resolve(
+                &mut auth,
+                &format!("{}.long.example.com", "e".repeat(63)),
+                RRType::A
+            )
+            .await,
+            (Rcode::YXDomain, vec![RRType::DNAME])
+        );
+    }
 }
@@ -0,0 +1,151 @@
+use r53::Name;
+use std::cmp::Ordering;
+
+//name sorted in dns canonical order(rfc4034 section 6.1), labels are
+//compared from the right most one, case insensitive
+#[derive(Clone, Debug)]
+pub(crate) struct CanonicalName {
+    name: Name,
+    labels: Vec<Vec<u8>>,
+}
+
+impl CanonicalName {
+    pub fn new(name: Name) -> Self {
+        let mut labels = wire_labels(&name.to_string());
+        labels.reverse();
+        CanonicalName { name, labels }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+}
+
+impl PartialEq for CanonicalName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels == other.labels
+    }
+}
+
+impl Eq for CanonicalName {}
+
+impl PartialOrd for CanonicalName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CanonicalName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.labels.cmp(&other.labels)
+    }
+}
+
+//uncompressed wire format of the name in canonical form, which is the
+//form covered by dnssec signatures
+pub(crate) fn name_wire(name: &Name) -> Vec<u8> {
+    labels_wire(&wire_labels(&name.to_string()))
+}
+
+pub(crate) fn labels_wire(labels: &[Vec<u8>]) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in labels {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label);
+    }
+    wire.push(0);
+    wire
+}
+
+//name of the raw labels, octets other than letters, digits, hyphen,
+//underscore and asterisk are escaped in presentation format
+pub(crate) fn labels_name(labels: &[Vec<u8>]) -> anyhow::Result<Name> {
+    let mut name = String::new();
+    for label in labels {
+        for b in label {
+            if b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_' || *b == b'*' {
+                name.push(*b as char);
+            } else {
+                name.push_str(&format!("\\{:03}", b));
+            }
+        }
+        name.push('.');
+    }
+    if name.is_empty() {
+        name.push('.');
+    }
+    Ok(Name::new(&name)?)
+}
+
+//split presentation format name into lowercased raw labels,
+//escapes like \. and \DDD are decoded
+pub(crate) fn wire_labels(name: &str) -> Vec<Vec<u8>> {
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(u8::is_ascii_digit) => {
+                let v = (bytes[i + 1] - b'0') as u16 * 100
+                    + (bytes[i + 2] - b'0') as u16 * 10
+                    + (bytes[i + 3] - b'0') as u16;
+                label.push((v as u8).to_ascii_lowercase());
+                i += 4;
+                continue;
+            }
+            b'\\' if i + 1 < bytes.len() => {
+                label.push(bytes[i + 1].to_ascii_lowercase());
+                i += 2;
+                continue;
+            }
+            b'.' => {
+                if !label.is_empty() {
+                    labels.push(label);
+                    label = Vec::new();
+                }
+            }
+            c => label.push(c.to_ascii_lowercase()),
+        }
+        i += 1;
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_order() {
+        //example from rfc4034 section 6.1
+        let names = vec![
+            "example.",
+            "a.example.",
+            "yljkjljk.a.example.",
+            "Z.a.example.",
+            "zABC.a.EXAMPLE.",
+            "z.example.",
+            "\\001.z.example.",
+            "*.z.example.",
+            "\\200.z.example.",
+        ];
+        let mut sorted = names
+            .iter()
+            .rev()
+            .map(|n| CanonicalName::new(Name::new(n).unwrap()))
+            .collect::<Vec<CanonicalName>>();
+        sorted.sort();
+        for (i, name) in sorted.iter().enumerate() {
+            assert_eq!(name, &CanonicalName::new(Name::new(names[i]).unwrap()));
+        }
+        assert_eq!(
+            CanonicalName::new(Name::new("A.example.").unwrap()),
+            CanonicalName::new(Name::new("a.EXAMPLE").unwrap())
+        );
+    }
+}
@@ -0,0 +1,956 @@
+use anyhow::{self, bail};
+use r53::{Name, RRClass, RRTtl, RRType, RRset, Response, ResponseBuilder, SectionType};
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::canonical::{labels_name, name_wire, wire_labels};
+use crate::server::{read_labels, render_message};
+
+pub(crate) const ZSK_FLAGS: u16 = 256;
+//zone key with the secure entry point bit, which the ds in parent points to
+pub(crate) const KSK_FLAGS: u16 = 257;
+const DNSKEY_PROTOCOL: u8 = 3;
+const DIGEST_SHA256: u8 = 2;
+const CLASS_IN: u16 = 1;
+//signatures are valid from a while ago, so validators with a slow clock
+//accept them too
+const INCEPTION_OFFSET: u32 = 3600;
+const SIGNATURE_VALIDITY: u32 = 30 * 86400;
+//signatures expiring sooner are made again
+pub(crate) const SIGNATURE_REFRESH: u32 = SIGNATURE_VALIDITY / 4;
+const NSEC3_SHA1: u8 = 1;
+const NSEC3_OPT_OUT: u8 = 1;
+//validators treat zones with more iterations as insecure
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnssecAlgorithm {
+    EcdsaP256Sha256,
+    Ed25519,
+}
+
+impl DnssecAlgorithm {
+    //algorithm number in dnskey, rrsig and ds
+    fn number(self) -> u8 {
+        match self {
+            DnssecAlgorithm::EcdsaP256Sha256 => 13,
+            DnssecAlgorithm::Ed25519 => 15,
+        }
+    }
+}
+
+impl FromStr for DnssecAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ecdsap256sha256" | "13" => Ok(DnssecAlgorithm::EcdsaP256Sha256),
+            "ed25519" | "15" => Ok(DnssecAlgorithm::Ed25519),
+            _ => bail!(
+                "unsupported dnssec algorithm {}, should be ecdsap256sha256 or ed25519",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DnssecAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnssecAlgorithm::EcdsaP256Sha256 => write!(f, "ecdsap256sha256"),
+            DnssecAlgorithm::Ed25519 => write!(f, "ed25519"),
+        }
+    }
+}
+
+//signing state of a zone, ds of the ksks published by cds goes to the
+//parent zone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DnssecStatus {
+    pub algorithm: String,
+    pub ds: Vec<String>,
+    pub nsec3: Option<Nsec3Config>,
+    pub keys: Vec<KeyStatus>,
+}
+
+//state of one key, next is when it moves to the next state by the
+//rollover policy
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyStatus {
+    pub tag: u16,
+    pub role: KeyRole,
+    pub state: KeyState,
+    pub since: u32,
+    pub next: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRole {
+    Ksk,
+    Zsk,
+}
+
+impl FromStr for KeyRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ksk" => Ok(KeyRole::Ksk),
+            "zsk" => Ok(KeyRole::Zsk),
+            _ => bail!("unknown key role {}, should be ksk or zsk", s),
+        }
+    }
+}
+
+impl fmt::Display for KeyRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyRole::Ksk => write!(f, "ksk"),
+            KeyRole::Zsk => write!(f, "zsk"),
+        }
+    }
+}
+
+//zsk is published in dnskey before it signs and stays there after it
+//retires until its signatures expire from caches. ksk is published by cds
+//for the parent to add its ds before it's in dnskey(double-ds), and stays
+//in cds after it's removed from dnskey
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyState {
+    Published,
+    Active,
+    Retired,
+}
+
+impl FromStr for KeyState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "published" => Ok(KeyState::Published),
+            "active" => Ok(KeyState::Active),
+            "retired" => Ok(KeyState::Retired),
+            _ => bail!("unknown key state {}", s),
+        }
+    }
+}
+
+impl fmt::Display for KeyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyState::Published => write!(f, "published"),
+            KeyState::Active => write!(f, "active"),
+            KeyState::Retired => write!(f, "retired"),
+        }
+    }
+}
+
+//parameters of the nsec3 chain, nsec is used when a signed zone has none.
+//salt is in hex, and with opt-out the delegations without ds are left out
+//of the chain
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Nsec3Config {
+    pub iterations: u16,
+    pub salt: String,
+    pub opt_out: bool,
+}
+
+impl Nsec3Config {
+    pub fn salt(&self) -> anyhow::Result<Vec<u8>> {
+        if self.iterations > MAX_NSEC3_ITERATIONS {
+            bail!(
+                "nsec3 iterations {} is more than {}",
+                self.iterations,
+                MAX_NSEC3_ITERATIONS
+            );
+        }
+        let hex = if self.salt == "-" { "" } else { &self.salt };
+        if hex.len() % 2 != 0 || hex.len() > 255 * 2 {
+            bail!("invalid nsec3 salt {}", self.salt);
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&hex[i..i + 2], 16)
+                    .map_err(|_| anyhow::anyhow!("invalid nsec3 salt {}", self.salt))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+enum SigningKey {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+//private key of a zone, ksk signs the dnskey, cds and cdnskey rrsets and
+//zsk signs the others. the key is kept in pkcs8 to be persisted with the
+//zone, along with its state and the time it entered the state
+#[derive(Debug, Clone)]
+pub(crate) struct ZoneKey {
+    flags: u16,
+    algorithm: DnssecAlgorithm,
+    pkcs8: Vec<u8>,
+    key: Arc<SigningKey>,
+    tag: u16,
+    state: KeyState,
+    since: u32,
+}
+
+impl ZoneKey {
+    pub fn new(flags: u16, algorithm: DnssecAlgorithm, pkcs8: Vec<u8>) -> anyhow::Result<Self> {
+        if flags != ZSK_FLAGS && flags != KSK_FLAGS {
+            bail!("invalid dnssec key flags {}", flags);
+        }
+        let key = match algorithm {
+            DnssecAlgorithm::EcdsaP256Sha256 => {
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+                    .map(SigningKey::Ecdsa)
+            }
+            //pkcs8 v1 made by other tools has no public key
+            DnssecAlgorithm::Ed25519 => {
+                Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8).map(SigningKey::Ed25519)
+            }
+        };
+        let key = match key {
+            Ok(key) => key,
+            Err(e) => bail!("invalid {} private key: {}", algorithm, e),
+        };
+        let mut zone_key = ZoneKey {
+            flags,
+            algorithm,
+            pkcs8,
+            key: Arc::new(key),
+            tag: 0,
+            state: KeyState::Active,
+            since: 0,
+        };
+        zone_key.tag = key_tag(&zone_key.dnskey_rdata());
+        Ok(zone_key)
+    }
+
+    pub fn generate(flags: u16, algorithm: DnssecAlgorithm) -> anyhow::Result<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match algorithm {
+            DnssecAlgorithm::EcdsaP256Sha256 => {
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            }
+            DnssecAlgorithm::Ed25519 => Ed25519KeyPair::generate_pkcs8(&rng),
+        };
+        match pkcs8 {
+            Ok(pkcs8) => ZoneKey::new(flags, algorithm, pkcs8.as_ref().to_vec()),
+            Err(_) => bail!("generate {} key failed", algorithm),
+        }
+    }
+
+    pub fn with_state(mut self, state: KeyState, since: u32) -> Self {
+        self.set_state(state, since);
+        self
+    }
+
+    pub fn set_state(&mut self, state: KeyState, since: u32) {
+        self.state = state;
+        self.since = since;
+    }
+
+    #[inline]
+    pub fn is_ksk(&self) -> bool {
+        self.flags == KSK_FLAGS
+    }
+
+    #[inline]
+    pub fn role(&self) -> KeyRole {
+        if self.is_ksk() {
+            KeyRole::Ksk
+        } else {
+            KeyRole::Zsk
+        }
+    }
+
+    #[inline]
+    pub fn state(&self) -> KeyState {
+        self.state
+    }
+
+    #[inline]
+    pub fn since(&self) -> u32 {
+        self.since
+    }
+
+    #[inline]
+    pub fn tag(&self) -> u16 {
+        self.tag
+    }
+
+    #[inline]
+    fn is_active(&self) -> bool {
+        self.state == KeyState::Active
+    }
+
+    //ksk is only in dnskey while it's active
+    fn in_dnskey(&self) -> bool {
+        !self.is_ksk() || self.is_active()
+    }
+
+    #[inline]
+    pub fn algorithm(&self) -> DnssecAlgorithm {
+        self.algorithm
+    }
+
+    //ecdsa public key is the point without the leading 0x04 (rfc6605)
+    fn public_key(&self) -> &[u8] {
+        match *self.key {
+            SigningKey::Ecdsa(ref key) => &key.public_key().as_ref()[1..],
+            SigningKey::Ed25519(ref key) => key.public_key().as_ref(),
+        }
+    }
+
+    pub fn dnskey_rdata(&self) -> Vec<u8> {
+        let mut rdata = Vec::with_capacity(4 + self.public_key().len());
+        rdata.extend_from_slice(&self.flags.to_be_bytes());
+        rdata.push(DNSKEY_PROTOCOL);
+        rdata.push(self.algorithm.number());
+        rdata.extend_from_slice(self.public_key());
+        rdata
+    }
+
+    //ds with sha256 digest in master file format, which is given to the
+    //parent zone
+    pub fn ds(&self, zone: &Name, ttl: u32) -> String {
+        let digest = self.ds_rdata(zone)[4..]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        format!(
+            "{} {} IN DS {} {} {} {}",
+            zone,
+            ttl,
+            self.tag,
+            self.algorithm.number(),
+            DIGEST_SHA256,
+            digest
+        )
+    }
+
+    fn ds_rdata(&self, zone: &Name) -> Vec<u8> {
+        let mut data = name_wire(zone);
+        data.extend_from_slice(&self.dnskey_rdata());
+        let mut rdata = Vec::with_capacity(36);
+        rdata.extend_from_slice(&self.tag.to_be_bytes());
+        rdata.push(self.algorithm.number());
+        rdata.push(DIGEST_SHA256);
+        rdata.extend_from_slice(digest::digest(&digest::SHA256, &data).as_ref());
+        rdata
+    }
+
+    fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match *self.key {
+            SigningKey::Ecdsa(ref key) => match key.sign(&SystemRandom::new(), data) {
+                Ok(signature) => Ok(signature.as_ref().to_vec()),
+                Err(_) => bail!("ecdsa signing failed"),
+            },
+            SigningKey::Ed25519(ref key) => Ok(key.sign(data).as_ref().to_vec()),
+        }
+    }
+}
+
+//key in format <flags> <algorithm> <base64 pkcs8> [<state> <since>], key
+//without state is active
+impl FromStr for ZoneKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 3 && fields.len() != 5 {
+            bail!("dnssec key should be <flags> <algorithm> <private key> [<state> <since>]");
+        }
+        let flags = match fields[0].parse::<u16>() {
+            Ok(flags) => flags,
+            Err(_) => bail!("invalid dnssec key flags {}", fields[0]),
+        };
+        let pkcs8 = match base64::decode(fields[2]) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => bail!("dnssec private key isn't base64"),
+        };
+        let key = ZoneKey::new(flags, fields[1].parse()?, pkcs8)?;
+        if fields.len() == 3 {
+            return Ok(key);
+        }
+        let since = match fields[4].parse::<u32>() {
+            Ok(since) => since,
+            Err(_) => bail!("invalid dnssec key time {}", fields[4]),
+        };
+        Ok(key.with_state(fields[3].parse()?, since))
+    }
+}
+
+impl fmt::Display for ZoneKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.flags,
+            self.algorithm,
+            base64::encode(&self.pkcs8),
+            self.state,
+            self.since
+        )
+    }
+}
+
+//keys of a newly signed zone, one active ksk and one active zsk
+pub(crate) fn generate_keys(algorithm: DnssecAlgorithm, now: u32) -> anyhow::Result<Vec<ZoneKey>> {
+    Ok(vec![
+        ZoneKey::generate(KSK_FLAGS, algorithm)?.with_state(KeyState::Active, now),
+        ZoneKey::generate(ZSK_FLAGS, algorithm)?.with_state(KeyState::Active, now),
+    ])
+}
+
+//keys of a zone should have at least one active ksk and one active zsk
+pub(crate) fn parse_keys<T: AsRef<str>>(keys: &[T]) -> anyhow::Result<Vec<ZoneKey>> {
+    let keys = keys
+        .iter()
+        .map(|key| key.as_ref().parse::<ZoneKey>())
+        .collect::<anyhow::Result<Vec<ZoneKey>>>()?;
+    let has_active = |ksk: bool| {
+        keys.iter()
+            .any(|key| key.is_ksk() == ksk && key.is_active())
+    };
+    if !keys.is_empty() && !(has_active(true) && has_active(false)) {
+        bail!("dnssec keys should have both active ksk and zsk");
+    }
+    Ok(keys)
+}
+
+//rfc4034 appendix B
+fn key_tag(dnskey: &[u8]) -> u16 {
+    let mut ac = 0u32;
+    for (i, b) in dnskey.iter().enumerate() {
+        if i & 1 == 0 {
+            ac += (*b as u32) << 8;
+        } else {
+            ac += *b as u32;
+        }
+    }
+    ac += (ac >> 16) & 0xffff;
+    (ac & 0xffff) as u16
+}
+
+pub(crate) fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+pub(crate) fn dnskey_rrset(keys: &[ZoneKey], zone: &Name, ttl: u32) -> anyhow::Result<RRset> {
+    let rdatas = keys
+        .iter()
+        .filter(|key| key.in_dnskey())
+        .map(|key| key.dnskey_rdata())
+        .collect::<Vec<_>>();
+    rrset_from_rdatas(zone, RRType::DNSKEY, ttl, &rdatas)
+}
+
+//cds and cdnskey(rfc7344) of all the ksks, for the parent to keep its ds
+//in sync with them
+pub(crate) fn cds_rrsets(keys: &[ZoneKey], zone: &Name, ttl: u32) -> anyhow::Result<[RRset; 2]> {
+    let ksks = keys.iter().filter(|key| key.is_ksk()).collect::<Vec<_>>();
+    let cds = ksks
+        .iter()
+        .map(|key| key.ds_rdata(zone))
+        .collect::<Vec<_>>();
+    let cdnskey = ksks
+        .iter()
+        .map(|key| key.dnskey_rdata())
+        .collect::<Vec<_>>();
+    Ok([
+        rrset_from_rdatas(zone, RRType::CDS, ttl, &cds)?,
+        rrset_from_rdatas(zone, RRType::CDNSKEY, ttl, &cdnskey)?,
+    ])
+}
+
+//type bit maps field of nsec and nsec3(rfc4034 section 4.1.2), types are
+//grouped in windows of 256
+fn type_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+    let mut bitmap = Vec::new();
+    let mut window: Option<(u8, Vec<u8>)> = None;
+    for typ in types {
+        let (number, bit) = ((typ >> 8) as u8, (typ & 0xff) as usize);
+        if window.as_ref().map_or(true, |(n, _)| *n != number) {
+            if let Some((n, bits)) = window.take() {
+                bitmap.push(n);
+                bitmap.push(bits.len() as u8);
+                bitmap.extend(bits);
+            }
+            window = Some((number, Vec::new()));
+        }
+        let bits = &mut window.as_mut().unwrap().1;
+        if bits.len() <= bit / 8 {
+            bits.resize(bit / 8 + 1, 0);
+        }
+        bits[bit / 8] |= 0x80 >> (bit % 8);
+    }
+    if let Some((n, bits)) = window {
+        bitmap.push(n);
+        bitmap.push(bits.len() as u8);
+        bitmap.extend(bits);
+    }
+    bitmap
+}
+
+pub(crate) fn nsec_rdata(next: &Name, types: &[u16]) -> Vec<u8> {
+    let mut rdata = name_wire(next);
+    rdata.extend(type_bitmap(types));
+    rdata
+}
+
+pub(crate) fn nsec3_rdata(
+    salt: &[u8],
+    iterations: u16,
+    opt_out: bool,
+    next: &[u8],
+    types: &[u16],
+) -> Vec<u8> {
+    let mut rdata = nsec3param_rdata(salt, iterations);
+    if opt_out {
+        rdata[1] = NSEC3_OPT_OUT;
+    }
+    rdata.push(next.len() as u8);
+    rdata.extend_from_slice(next);
+    rdata.extend(type_bitmap(types));
+    rdata
+}
+
+pub(crate) fn nsec3param_rdata(salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut rdata = vec![NSEC3_SHA1, 0];
+    rdata.extend_from_slice(&iterations.to_be_bytes());
+    rdata.push(salt.len() as u8);
+    rdata.extend_from_slice(salt);
+    rdata
+}
+
+//salt and iterations in nsec3param rdata
+pub(crate) fn parse_nsec3param(rdata: &[u8]) -> Option<(Vec<u8>, u16)> {
+    let len = *rdata.get(4)? as usize;
+    if rdata[0] != NSEC3_SHA1 {
+        return None;
+    }
+    let salt = rdata.get(5..5 + len)?.to_vec();
+    Some((salt, u16::from_be_bytes([rdata[2], rdata[3]])))
+}
+
+//iterated sha1 of the name in canonical wire format(rfc5155 section 5)
+pub(crate) fn nsec3_hash(name: &[u8], salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut data = name.to_vec();
+    data.extend_from_slice(salt);
+    let mut hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+    for _ in 0..iterations {
+        let mut data = hash.as_ref().to_vec();
+        data.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+    }
+    hash.as_ref().to_vec()
+}
+
+//base32 with extended hex alphabet without padding, which keeps the order
+//of the hashes in the owner names of nsec3
+pub(crate) fn base32hex(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut encoded = String::with_capacity(data.len() * 8 / 5 + 1);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for b in data {
+        buffer = (buffer << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+//rrsig rrset of the rrsets owned by one name made by the active keys,
+//dnskey, cds and cdnskey are signed by the ksks and the others by the zsks.
+//ttl of the rrsig rrset is the smallest one of the rrsets, the answer gets
+//the ttl of the rrset it covers
+pub(crate) fn sign_rrsets(
+    keys: &[ZoneKey],
+    zone: &Name,
+    rrsets: &[RRset],
+    now: u32,
+) -> anyhow::Result<Option<RRset>> {
+    let (inception, expiration) = (
+        now.wrapping_sub(INCEPTION_OFFSET),
+        now.wrapping_add(SIGNATURE_VALIDITY),
+    );
+    let mut rdatas = Vec::new();
+    let mut ttl = u32::MAX;
+    for rrset in rrsets {
+        let by_ksk = matches!(rrset.typ, RRType::DNSKEY | RRType::CDS | RRType::CDNSKEY);
+        for key in keys
+            .iter()
+            .filter(|key| key.is_ksk() == by_ksk && key.is_active())
+        {
+            rdatas.push(rrsig_rdata(rrset, zone, key, inception, expiration)?);
+        }
+        ttl = ttl.min(rrset.ttl.0);
+    }
+    if rdatas.is_empty() {
+        return Ok(None);
+    }
+    rrset_from_rdatas(&rrsets[0].name, RRType::RRSIG, ttl, &rdatas).map(Some)
+}
+
+//rfc4034 section 3.1.8.1, the signature covers the rrsig rdata without
+//the signature followed by the rrs in canonical form and order
+fn rrsig_rdata(
+    rrset: &RRset,
+    zone: &Name,
+    key: &ZoneKey,
+    inception: u32,
+    expiration: u32,
+) -> anyhow::Result<Vec<u8>> {
+    //wildcard label isn't counted
+    let mut labels = wire_labels(&rrset.name.to_string());
+    if labels.first().map_or(false, |label| label == b"*") {
+        labels.remove(0);
+    }
+    let mut rdata = Vec::new();
+    rdata.extend_from_slice(&rrset.typ.as_u16().to_be_bytes());
+    rdata.push(key.algorithm.number());
+    rdata.push(labels.len() as u8);
+    rdata.extend_from_slice(&rrset.ttl.0.to_be_bytes());
+    rdata.extend_from_slice(&expiration.to_be_bytes());
+    rdata.extend_from_slice(&inception.to_be_bytes());
+    rdata.extend_from_slice(&key.tag.to_be_bytes());
+    rdata.extend_from_slice(&name_wire(zone));
+
+    let owner = name_wire(&rrset.name);
+    let mut rdatas = rdata_wires(rrset)?;
+    rdatas.sort();
+    rdatas.dedup();
+    let mut data = rdata.clone();
+    for rr in rdatas {
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&rrset.typ.as_u16().to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+        data.extend_from_slice(&rrset.ttl.0.to_be_bytes());
+        data.extend_from_slice(&(rr.len() as u16).to_be_bytes());
+        data.extend_from_slice(&rr);
+    }
+    rdata.extend_from_slice(&key.sign(&data)?);
+    Ok(rdata)
+}
+
+//earliest signature expiration of the rrsigs
+pub(crate) fn rrsig_expiration(rrsigs: &RRset) -> Option<u32> {
+    rdata_wires(rrsigs)
+        .ok()?
+        .iter()
+        .filter_map(|wire| wire.get(8..12))
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .min()
+}
+
+//rrsigs in the rrsig rrset which cover the rrset, with the ttl of the rrset
+pub(crate) fn covering_rrsigs(rrsigs: &RRset, rrset: &RRset) -> Option<RRset> {
+    let wires = rdata_wires(rrsigs).ok()?;
+    let typ = rrset.typ.as_u16().to_be_bytes();
+    let rdatas = rrsigs
+        .rdatas
+        .iter()
+        .zip(wires)
+        .filter(|(_, wire)| wire.get(..2) == Some(&typ[..]))
+        .map(|(rdata, _)| rdata.clone())
+        .collect::<Vec<_>>();
+    if rdatas.is_empty() {
+        return None;
+    }
+    Some(RRset {
+        name: rrsigs.name.clone(),
+        typ: RRType::RRSIG,
+        class: RRClass::IN,
+        ttl: rrset.ttl,
+        rdatas,
+    })
+}
+
+//rdata of each rr in canonical form(rfc4034 section 6.2), uncompressed
+//with the names lowercased, except the next name of nsec. rrs are rendered by r53 one at a time and
+//owned by root, so the names in rdata have little to be compressed against
+pub(crate) fn rdata_wires(rrset: &RRset) -> anyhow::Result<Vec<Vec<u8>>> {
+    let root = Name::new(".")?;
+    let mut rr = rrset.clone();
+    rr.name = root.clone();
+    let mut wires = Vec::with_capacity(rrset.rdatas.len());
+    for rdata in rrset.rdatas.iter() {
+        rr.rdatas = vec![rdata.clone()];
+        let mut resp = Response::with_question(root.clone(), rrset.typ);
+        ResponseBuilder::new(&mut resp)
+            .add_rrset(SectionType::Answer, rr.clone())
+            .done();
+        let message = match render_message(&resp) {
+            Some(message) => message,
+            None => bail!("render {} {} failed", rrset.name, rrset.typ),
+        };
+        //header and root question, then owner, type, class, ttl and rdlength
+        let pos = read_labels(&message, 17)?.1 + 8;
+        let len = match message.get(pos..pos + 2) {
+            Some(b) => u16::from_be_bytes([b[0], b[1]]) as usize,
+            None => bail!("render {} {} failed", rrset.name, rrset.typ),
+        };
+        wires.push(canonical_rdata(&message, rrset.typ, pos + 2, len)?);
+    }
+    Ok(wires)
+}
+
+fn canonical_rdata(wire: &[u8], typ: RRType, pos: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+    let end = pos + len;
+    if wire.len() < end {
+        bail!("rdata is truncated");
+    }
+    //octets before the names and the count of names. the next name of nsec
+    //keeps its case(rfc6840 section 5.1). names in RP, AFSDB, KX, NAPTR and
+    //the other types of rfc4034 section 6.2 which aren't supported here are
+    //signed as they are rendered
+    let (fixed, names) = match typ {
+        RRType::NS | RRType::CNAME | RRType::PTR | RRType::DNAME | RRType::NSEC => (0, 1),
+        RRType::MX => (2, 1),
+        RRType::SRV => (6, 1),
+        RRType::RRSIG => (18, 1),
+        RRType::SOA => (0, 2),
+        _ => return Ok(wire[pos..end].to_vec()),
+    };
+    let lowercase = typ != RRType::NSEC;
+    if pos + fixed > end {
+        bail!("invalid rdata of {}", typ);
+    }
+    let mut rdata = Vec::with_capacity(len);
+    rdata.extend_from_slice(&wire[pos..pos + fixed]);
+    let mut next = pos + fixed;
+    for _ in 0..names {
+        let (labels, after) = read_labels(wire, next)?;
+        for label in labels {
+            rdata.push(label.len() as u8);
+            if lowercase {
+                rdata.extend(label.iter().map(u8::to_ascii_lowercase));
+            } else {
+                rdata.extend_from_slice(label);
+            }
+        }
+        rdata.push(0);
+        next = after;
+    }
+    if next > end {
+        bail!("invalid rdata of {}", typ);
+    }
+    rdata.extend_from_slice(&wire[next..end]);
+    Ok(rdata)
+}
+
+//rrset of the rdatas in wire format, which are parsed by r53 from a
+//response holding them
+pub(crate) fn rrset_from_rdatas(
+    name: &Name,
+    typ: RRType,
+    ttl: u32,
+    rdatas: &[Vec<u8>],
+) -> anyhow::Result<RRset> {
+    //response with root question and the rrs owned by root
+    let mut message = vec![0, 0, 0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+    message[6..8].copy_from_slice(&(rdatas.len() as u16).to_be_bytes());
+    message.extend_from_slice(&typ.as_u16().to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    for rdata in rdatas {
+        message.push(0);
+        message.extend_from_slice(&typ.as_u16().to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(rdata);
+    }
+    let mut resp = Response::from_wire(&message)?;
+    let mut rrsets = resp
+        .take_section(SectionType::Answer)
+        .unwrap_or_default()
+        .into_iter();
+    let mut rrset = match rrsets.next() {
+        Some(rrset) => rrset,
+        None => bail!("invalid rdata of {}", typ),
+    };
+    for other in rrsets {
+        rrset.rdatas.extend(other.rdatas);
+    }
+    rrset.name = name.clone();
+    rrset.ttl = RRTtl(ttl);
+    Ok(rrset)
+}
+
+//target of cname or dname, whose rdata is only the name
+pub(crate) fn rdata_target(rrset: &RRset) -> anyhow::Result<Name> {
+    let wires = rdata_wires(rrset)?;
+    let wire = match wires.first() {
+        Some(wire) => wire,
+        None => bail!("{} {} has no rdata", rrset.name, rrset.typ),
+    };
+    let labels = read_labels(wire, 0)?
+        .0
+        .into_iter()
+        .map(|label| label.to_vec())
+        .collect::<Vec<_>>();
+    labels_name(&labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //example 1 of rfc8080
+    const ED25519_PRIVATE_KEY: &str = "ODIyNjAzODQ2MjgwODAxMjI2NDUxOTAyMDQxNDIyNjI=";
+
+    fn ed25519_key(flags: u16) -> ZoneKey {
+        //pkcs8 v1 prefix of ed25519 private key
+        let mut pkcs8 = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        pkcs8.extend(base64::decode(ED25519_PRIVATE_KEY).unwrap());
+        ZoneKey::new(flags, DnssecAlgorithm::Ed25519, pkcs8).unwrap()
+    }
+
+    #[test]
+    fn test_zone_key() {
+        let key = ed25519_key(KSK_FLAGS);
+        assert_eq!(key.tag, 3613);
+        assert_eq!(
+            base64::encode(key.public_key()),
+            "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4="
+        );
+        let zone = Name::new("example.com.").unwrap();
+        assert_eq!(
+            key.ds(&zone, 3600),
+            "example.com. 3600 IN DS 3613 15 2 3AA5AB37EFCE57F737FC1627013FEE07BDF241BD10F3B1964AB55C78E79A304B"
+        );
+
+        for algorithm in [DnssecAlgorithm::EcdsaP256Sha256, DnssecAlgorithm::Ed25519] {
+            let keys = generate_keys(algorithm, 1).unwrap();
+            let keys = keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+            let parsed = parse_keys(&keys).unwrap();
+            assert_eq!(parsed[0].tag, keys[0].parse::<ZoneKey>().unwrap().tag);
+            assert!(parsed[0].is_ksk() && !parsed[1].is_ksk());
+            assert_eq!(
+                (parsed[1].state(), parsed[1].since()),
+                (KeyState::Active, 1)
+            );
+            assert!(parse_keys(&keys[1..]).is_err());
+
+            //zsk which doesn't sign yet
+            let published = parsed[1].to_string().replace("active", "published");
+            let key = published.parse::<ZoneKey>().unwrap();
+            assert_eq!(key.state(), KeyState::Published);
+            assert!(parse_keys(&[keys[0].clone(), published]).is_err());
+        }
+        assert!("257 rsasha256 AAAA".parse::<ZoneKey>().is_err());
+        assert!("256 ed25519 AAAA".parse::<ZoneKey>().is_err());
+    }
+
+    #[test]
+    fn test_sign_rrset() {
+        let key = ed25519_key(KSK_FLAGS);
+        let zone = Name::new("example.com.").unwrap();
+        let mx = RRset::from_str("example.com. 3600 IN MX 10 mail.example.com.").unwrap();
+        let rrsig = rrsig_rdata(&mx, &zone, &key, 1438207200, 1440021600).unwrap();
+        let signature = "oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==";
+        assert_eq!(base64::encode(&rrsig[rrsig.len() - 64..]), signature);
+        assert_eq!(&rrsig[..4], &[0, 15, 15, 2]);
+
+        //mx is signed by zsk only
+        let keys = [key, ed25519_key(ZSK_FLAGS)];
+        let rrsigs = sign_rrsets(&keys, &zone, &[mx.clone()], now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rrsigs.rr_count(), 1);
+        assert!(covering_rrsigs(&rrsigs, &mx).is_some());
+        let soa = RRset::from_str(
+            "example.com. 3600 IN SOA ns.example.com. root.example.com. 1 3600 900 604800 300",
+        )
+        .unwrap();
+        assert!(covering_rrsigs(&rrsigs, &soa).is_none());
+    }
+
+    #[test]
+    fn test_canonical_rdata() {
+        let name = b"\x03WWW\x07Example\x03com\x00";
+        let canonical = canonical_rdata(name, RRType::NS, 0, name.len()).unwrap();
+        assert_eq!(canonical, b"\x03www\x07example\x03com\x00".to_vec());
+
+        let mut nsec = name.to_vec();
+        nsec.extend_from_slice(&[0, 1, 0x40]);
+        let canonical = canonical_rdata(&nsec, RRType::NSEC, 0, nsec.len()).unwrap();
+        assert_eq!(canonical, nsec);
+    }
+
+    #[test]
+    fn test_denial_rdata() {
+        //example in rfc4034 section 4.3
+        let mut bitmap = vec![0, 6, 0x40, 0x01, 0, 0, 0, 0x03, 4, 27];
+        bitmap.extend([0; 26]);
+        bitmap.push(0x20);
+        assert_eq!(type_bitmap(&[47, 1234, 1, 15, 46, 1]), bitmap);
+
+        //examples in rfc5155 appendix A
+        let salt = Nsec3Config {
+            iterations: 12,
+            salt: "aabbccdd".to_string(),
+            opt_out: false,
+        }
+        .salt()
+        .unwrap();
+        let hash = |name: &[u8]| base32hex(&nsec3_hash(name, &salt, 12));
+        assert_eq!(hash(b"\x07example\x00"), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom");
+        assert_eq!(
+            hash(b"\x01a\x07example\x00"),
+            "35mthgpgcu1qg68fab165klnsnk3dpvl"
+        );
+        assert_eq!(
+            hash(b"\x01*\x01w\x07example\x00"),
+            "r53bq7cc2uvmubfu5ocmm6pers9tk9en"
+        );
+
+        let param = nsec3param_rdata(&salt, 12);
+        assert_eq!(param, vec![1, 0, 0, 12, 4, 0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(parse_nsec3param(&param), Some((salt.clone(), 12)));
+        let nsec3 = nsec3_rdata(&salt, 12, true, &[0xff; 20], &[1]);
+        assert_eq!(&nsec3[..2], &[1, 1]);
+        assert_eq!(&nsec3[nsec3.len() - 3..], &[0, 1, 0x40]);
+
+        for salt in ["abc", "zz"] {
+            let config = Nsec3Config {
+                salt: salt.to_string(),
+                ..Default::default()
+            };
+            assert!(config.salt().is_err());
+        }
+        let config = Nsec3Config {
+            iterations: 1000,
+            ..Default::default()
+        };
+        assert!(config.salt().is_err());
+    }
+}
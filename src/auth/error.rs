@@ -0,0 +1,30 @@
+use std::fmt;
+
+//errors the api reports with a specific status code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    UnknownZone,
+    DuplicateZone,
+    UnknownRRset,
+    UnknownRR,
+    //soa and ns at zone apex can be replaced but not deleted
+    ApexRRset,
+    //secondary zone is only changed by transfer from its primaries
+    SecondaryZone,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            AuthError::UnknownZone => "unknown zone",
+            AuthError::DuplicateZone => "add duplicate zone",
+            AuthError::UnknownRRset => "unknown rrset",
+            AuthError::UnknownRR => "unknown rr",
+            AuthError::ApexRRset => "soa and ns at zone apex can't be deleted",
+            AuthError::SecondaryZone => "secondary zone can't be changed",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for AuthError {}
@@ -1,5 +1,23 @@
+mod acl;
 mod auth;
+mod canonical;
+mod dnssec;
+mod error;
+mod notify;
+mod rollover;
+mod secondary;
+mod serial;
+mod store;
+mod update;
 mod zone;
 mod zone_content_generator;
+mod zone_file;
 
 pub use auth::Auth;
+pub use dnssec::{DnssecAlgorithm, KeyRole, Nsec3Config};
+pub use error::AuthError;
+pub(crate) use notify::parse_remotes;
+pub use rollover::RolloverPolicy;
+pub(crate) use serial::soa_negative_ttl;
+pub use serial::SerialPolicy;
+pub use zone_content_generator::{ZoneConfig, ZoneTemplate};
@@ -0,0 +1,181 @@
+use anyhow::{self, bail};
+use r53::{
+    header_flag::{self, HeaderFlag},
+    MessageRender, Name, Opcode, RRType, Rcode, Request, Response,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+use super::canonical::CanonicalName;
+use super::secondary::random_id;
+use crate::server::{key_name, TsigKey, TsigSession};
+
+const NOTIFY_PORT: u16 = 53;
+//rfc1996 section 3.6, notify is retried until the target answers, the
+//timeout is doubled on every retry
+const NOTIFY_ATTEMPTS: usize = 5;
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+//zones whose serial changed and the targets haven't been notified, a
+//zone changed several times before it's picked up is notified once
+#[derive(Default)]
+pub(crate) struct Notifier {
+    pending: Mutex<BTreeSet<CanonicalName>>,
+    wakeup: Notify,
+}
+
+impl Notifier {
+    pub fn push(&self, zone: Name) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(CanonicalName::new(zone));
+        self.wakeup.notify_one();
+    }
+
+    pub async fn pop(&self) -> Vec<Name> {
+        loop {
+            let zones = std::mem::take(&mut *self.pending.lock().unwrap());
+            if !zones.is_empty() {
+                return zones.into_iter().map(|zone| zone.name().clone()).collect();
+            }
+            self.wakeup.notified().await;
+        }
+    }
+}
+
+//primary or notify target, messages to it are signed with the tsig key
+//if there is one
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Remote {
+    pub addr: SocketAddr,
+    pub key: Option<String>,
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key {
+            Some(ref key) => write!(f, "{}@{}", self.addr, key),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+//remote is an address with optional port, 53 by default, followed by
+//@<key> when messages to it are signed
+pub(crate) fn parse_remotes<T: AsRef<str>>(remotes: &[T]) -> anyhow::Result<Vec<Remote>> {
+    let mut parsed: Vec<Remote> = Vec::with_capacity(remotes.len());
+    for remote in remotes {
+        let (addr, key) = match remote.as_ref().split_once('@') {
+            Some((addr, key)) => (addr, Some(key_name(key)?)),
+            None => (remote.as_ref(), None),
+        };
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => match addr.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, NOTIFY_PORT),
+                Err(_) => bail!("invalid address {}", remote.as_ref()),
+            },
+        };
+        if !parsed.iter().any(|remote| remote.addr == addr) {
+            parsed.push(Remote { addr, key });
+        }
+    }
+    Ok(parsed)
+}
+
+pub(crate) async fn send_notify(
+    target: SocketAddr,
+    zone: &Name,
+    key: Option<TsigKey>,
+) -> anyhow::Result<()> {
+    let mut wait = NOTIFY_TIMEOUT;
+    for _ in 0..NOTIFY_ATTEMPTS {
+        match timeout(wait, notify_once(target, zone, key.clone())).await {
+            Ok(result) => return result,
+            Err(_) => wait *= 2,
+        }
+    }
+    bail!("notify {} to {} timeout", zone, target)
+}
+
+async fn notify_once(target: SocketAddr, zone: &Name, key: Option<TsigKey>) -> anyhow::Result<()> {
+    let local: SocketAddr = if target.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0; 16], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    let mut req = Request::new(zone.clone(), RRType::SOA);
+    req.header.id = random_id();
+    req.header.opcode = Opcode::Notify;
+    header_flag::set_flag(&mut req.header.flag, HeaderFlag::AuthAnswer);
+    let mut buf = vec![0; 512];
+    let len = {
+        let mut render = MessageRender::new(&mut buf[..]);
+        req.to_wire(&mut render)?
+    };
+    let mut wire = buf[..len].to_vec();
+    let mut session = key.map(TsigSession::new);
+    if let Some(session) = session.as_mut() {
+        session.sign(&mut wire);
+    }
+    socket.send(&wire).await?;
+
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        if len < 2 || buf[..2] != req.header.id.to_be_bytes() {
+            continue;
+        }
+        let resp = match session.as_mut() {
+            Some(session) => Response::from_wire(&session.verify(&buf[..len])?),
+            None => Response::from_wire(&buf[..len]),
+        };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(_) => continue,
+        };
+        if resp.header.opcode != Opcode::Notify {
+            bail!(
+                "notify {} to {} gets opcode {:?}",
+                zone,
+                target,
+                resp.header.opcode
+            );
+        }
+        if resp.header.rcode != Rcode::NoError {
+            bail!("notify {} to {} gets {:?}", zone, target, resp.header.rcode);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remotes() {
+        let remotes = parse_remotes(&[
+            "10.0.0.1",
+            "10.0.0.2:5353@XFR-Key",
+            "2001:db8::1",
+            "10.0.0.1",
+        ])
+        .unwrap();
+        assert_eq!(
+            remotes.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec!["10.0.0.1:53", "10.0.0.2:5353@xfr-key.", "[2001:db8::1]:53",]
+        );
+        assert_eq!(remotes[1].key, Some("xfr-key.".to_string()));
+        assert!(parse_remotes(&["10.0.0"]).is_err());
+        assert!(parse_remotes(&["10.0.0.1@"]).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{self, bail};
+
+use super::dnssec::{KeyRole, KeyState, ZoneKey, KSK_FLAGS, ZSK_FLAGS};
+
+const DAY: u32 = 86400;
+
+//timing of the key rollovers in seconds, lifetime 0 means the keys of
+//the role are only rolled on request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RolloverPolicy {
+    pub zsk_lifetime: u32,
+    pub ksk_lifetime: u32,
+    //time for a change to reach the secondaries and the parent
+    pub propagation_delay: u32,
+    //ttl of the ds in parent zone
+    pub parent_ds_ttl: u32,
+}
+
+//ksk rollover waits for the parent, which isn't automated by default
+impl Default for RolloverPolicy {
+    fn default() -> Self {
+        Self {
+            zsk_lifetime: 90 * DAY,
+            ksk_lifetime: 0,
+            propagation_delay: 3600,
+            parent_ds_ttl: DAY,
+        }
+    }
+}
+
+//ttls of the zone the rollover waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ZoneTtls {
+    pub dnskey: u32,
+    //largest ttl of the rrsets, signatures may be cached that long
+    pub max: u32,
+}
+
+impl RolloverPolicy {
+    //time the key moves to its next state, None if it stays
+    pub fn next_change(&self, key: &ZoneKey, ttls: &ZoneTtls) -> Option<u32> {
+        let wait = match (key.role(), key.state()) {
+            //pre-publish zsk rollover, the new zsk signs once it's in the
+            //cached dnskey, the old one is removed once its signatures
+            //expire from caches
+            (KeyRole::Zsk, KeyState::Published) => ttls.dnskey + self.propagation_delay,
+            (KeyRole::Zsk, KeyState::Active) if self.zsk_lifetime > 0 => self.zsk_lifetime,
+            (KeyRole::Zsk, KeyState::Retired) => ttls.max + self.propagation_delay,
+            //double-ds ksk rollover, the new ksk replaces the old one in
+            //dnskey once its ds is in the parent, the old ds is withdrawn
+            //once the new dnskey is cached
+            (KeyRole::Ksk, KeyState::Published) => self.parent_ds_ttl + self.propagation_delay,
+            (KeyRole::Ksk, KeyState::Active) if self.ksk_lifetime > 0 => self.ksk_lifetime,
+            (KeyRole::Ksk, KeyState::Retired) => ttls.dnskey + self.propagation_delay,
+            _ => return None,
+        };
+        Some(key.since().saturating_add(wait))
+    }
+
+    //move the keys through the rollovers due at now, true if any of the
+    //keys changed
+    pub fn roll(&self, keys: &mut Vec<ZoneKey>, ttls: &ZoneTtls, now: u32) -> anyhow::Result<bool> {
+        let mut changed = false;
+        for role in [KeyRole::Zsk, KeyRole::Ksk] {
+            let due = |key: &ZoneKey, state: KeyState| {
+                key.role() == role
+                    && key.state() == state
+                    && self
+                        .next_change(key, ttls)
+                        .map_or(false, |next| next <= now)
+            };
+            let len = keys.len();
+            keys.retain(|key| !due(key, KeyState::Retired));
+            changed |= keys.len() != len;
+
+            if let Some(i) = keys.iter().position(|key| due(key, KeyState::Published)) {
+                for key in keys.iter_mut() {
+                    if key.role() == role && key.state() == KeyState::Active {
+                        key.set_state(KeyState::Retired, now);
+                    }
+                }
+                keys[i].set_state(KeyState::Active, now);
+                changed = true;
+            } else if let Some(key) = keys.iter().find(|key| due(key, KeyState::Active)) {
+                let in_progress = keys
+                    .iter()
+                    .any(|key| key.role() == role && key.state() == KeyState::Published);
+                if !in_progress {
+                    let key = new_key(role, key, now)?;
+                    keys.push(key);
+                    changed = true;
+                }
+            }
+        }
+        Ok(changed)
+    }
+}
+
+//start the rollover of the role at now, only one rollover of a role is in
+//progress at a time
+pub(crate) fn start_rollover(
+    keys: &mut Vec<ZoneKey>,
+    role: KeyRole,
+    now: u32,
+) -> anyhow::Result<()> {
+    if keys
+        .iter()
+        .any(|key| key.role() == role && key.state() == KeyState::Published)
+    {
+        bail!("{} rollover is in progress", role);
+    }
+    let key = match keys
+        .iter()
+        .find(|key| key.role() == role && key.state() == KeyState::Active)
+    {
+        Some(key) => new_key(role, key, now)?,
+        None => bail!("zone isn't signed"),
+    };
+    keys.push(key);
+    Ok(())
+}
+
+//successor of the active key with the same algorithm
+fn new_key(role: KeyRole, active: &ZoneKey, now: u32) -> anyhow::Result<ZoneKey> {
+    let flags = match role {
+        KeyRole::Ksk => KSK_FLAGS,
+        KeyRole::Zsk => ZSK_FLAGS,
+    };
+    Ok(ZoneKey::generate(flags, active.algorithm())?.with_state(KeyState::Published, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::dnssec::{generate_keys, DnssecAlgorithm};
+
+    fn states(keys: &[ZoneKey], role: KeyRole) -> Vec<KeyState> {
+        keys.iter()
+            .filter(|key| key.role() == role)
+            .map(|key| key.state())
+            .collect()
+    }
+
+    #[test]
+    fn test_zsk_rollover() {
+        let policy = RolloverPolicy {
+            zsk_lifetime: 1000,
+            ksk_lifetime: 0,
+            propagation_delay: 10,
+            parent_ds_ttl: 500,
+        };
+        let ttls = ZoneTtls {
+            dnskey: 100,
+            max: 300,
+        };
+        let mut keys = generate_keys(DnssecAlgorithm::Ed25519, 0).unwrap();
+        let old = keys[1].tag();
+        assert!(!policy.roll(&mut keys, &ttls, 999).unwrap());
+
+        //new zsk is published at the end of the lifetime
+        assert!(policy.roll(&mut keys, &ttls, 1000).unwrap());
+        assert_eq!(
+            states(&keys, KeyRole::Zsk),
+            vec![KeyState::Active, KeyState::Published]
+        );
+        assert_eq!(policy.next_change(&keys[2], &ttls), Some(1110));
+        assert!(!policy.roll(&mut keys, &ttls, 1109).unwrap());
+
+        //it signs once the dnskey with it is cached
+        assert!(policy.roll(&mut keys, &ttls, 1110).unwrap());
+        assert_eq!(
+            states(&keys, KeyRole::Zsk),
+            vec![KeyState::Retired, KeyState::Active]
+        );
+        assert!(!policy.roll(&mut keys, &ttls, 1419).unwrap());
+        assert!(policy.roll(&mut keys, &ttls, 1420).unwrap());
+        assert_eq!(states(&keys, KeyRole::Zsk), vec![KeyState::Active]);
+        assert!(keys.iter().all(|key| key.tag() != old));
+        assert_eq!(states(&keys, KeyRole::Ksk), vec![KeyState::Active]);
+    }
+
+    #[test]
+    fn test_ksk_rollover() {
+        let policy = RolloverPolicy {
+            zsk_lifetime: 0,
+            ksk_lifetime: 0,
+            propagation_delay: 10,
+            parent_ds_ttl: 500,
+        };
+        let ttls = ZoneTtls {
+            dnskey: 100,
+            max: 300,
+        };
+        let mut keys = generate_keys(DnssecAlgorithm::EcdsaP256Sha256, 0).unwrap();
+        //no automatic rollover without lifetime
+        assert!(!policy.roll(&mut keys, &ttls, u32::MAX).unwrap());
+
+        start_rollover(&mut keys, KeyRole::Ksk, 50).unwrap();
+        assert!(start_rollover(&mut keys, KeyRole::Ksk, 60).is_err());
+        assert_eq!(
+            states(&keys, KeyRole::Ksk),
+            vec![KeyState::Active, KeyState::Published]
+        );
+        assert_eq!(keys[2].algorithm(), DnssecAlgorithm::EcdsaP256Sha256);
+
+        //new ksk replaces the old one once its ds is in the parent
+        assert!(!policy.roll(&mut keys, &ttls, 559).unwrap());
+        assert!(policy.roll(&mut keys, &ttls, 560).unwrap());
+        assert_eq!(
+            states(&keys, KeyRole::Ksk),
+            vec![KeyState::Retired, KeyState::Active]
+        );
+        assert!(!policy.roll(&mut keys, &ttls, 669).unwrap());
+        assert!(policy.roll(&mut keys, &ttls, 670).unwrap());
+        assert_eq!(states(&keys, KeyRole::Ksk), vec![KeyState::Active]);
+        assert_eq!(states(&keys, KeyRole::Zsk), vec![KeyState::Active]);
+    }
+}
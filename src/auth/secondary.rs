@@ -0,0 +1,450 @@
+use anyhow::{self, bail};
+use r53::{MessageRender, Name, RRType, RRset, Rcode, Request, Response, SectionType};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+use super::notify::Remote;
+use super::serial::{serial_gt, soa_serial};
+use super::zone::ZoneDiff;
+use crate::server::{read_message, write_message, TsigKey, TsigSession};
+
+const MAX_MESSAGE_LEN: usize = 65535;
+//whole exchange with one primary, include the soa query and the transfer
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+//retry interval before the zone is transferred for the first time
+pub(crate) const INITIAL_RETRY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub(crate) struct SecondaryZone {
+    pub name: Name,
+    pub primaries: Vec<Remote>,
+    //wakes up the refresh loop when notify is received
+    pub refresh: Arc<Notify>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Transfer {
+    UpToDate,
+    //zone content in axfr
+    Full(Vec<RRset>),
+    Incremental(Vec<ZoneDiff>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SoaTimers {
+    pub refresh: Duration,
+    pub retry: Duration,
+    pub expire: Duration,
+}
+
+//refresh, retry and expire are the 4th to 6th fields of soa rdata
+pub(crate) fn soa_timers(soa: &RRset) -> Option<SoaTimers> {
+    let rdata = soa.rdatas.first()?.to_string();
+    let fields = rdata.split_whitespace().collect::<Vec<&str>>();
+    let timer = |i: usize| {
+        let secs = fields.get(i)?.parse::<u64>().ok()?;
+        Some(Duration::from_secs(secs.max(1)))
+    };
+    Some(SoaTimers {
+        refresh: timer(3)?,
+        retry: timer(4)?,
+        expire: timer(5)?,
+    })
+}
+
+//get the zone from primary when its serial is newer than the local one,
+//ixfr is tried first with a local copy and axfr is the fallback. all the
+//messages are signed with the key if there is one
+pub(crate) async fn fetch_zone(
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+    zone: &Name,
+    serial: Option<u32>,
+) -> anyhow::Result<Transfer> {
+    let fetch = async {
+        let mut stream = TcpStream::connect(primary).await?;
+        let serial = match serial {
+            Some(serial) => serial,
+            None => return axfr(&mut stream, key, zone).await,
+        };
+        let resp = exchange(&mut stream, key, &query_wire(zone, RRType::SOA, None)).await?;
+        let soa = resp
+            .section(SectionType::Answer)
+            .and_then(|answer| answer.iter().find(|rrset| rrset.typ == RRType::SOA))
+            .and_then(soa_serial);
+        match soa {
+            Some(primary_serial) if !serial_gt(primary_serial, serial) => Ok(Transfer::UpToDate),
+            //the broken ixfr may still be streaming, axfr uses a new connection
+            Some(_) => match ixfr(&mut stream, key, zone, serial).await {
+                Ok(transfer) => Ok(transfer),
+                Err(_) => axfr(&mut TcpStream::connect(primary).await?, key, zone).await,
+            },
+            None => bail!("no soa of {} on primary", zone),
+        }
+    };
+    match timeout(TRANSFER_TIMEOUT, fetch).await {
+        Ok(result) => result.map_err(|e| anyhow::anyhow!("{}: {}", primary, e)),
+        Err(_) => bail!("{}: transfer timeout", primary),
+    }
+}
+
+async fn axfr(
+    stream: &mut TcpStream,
+    key: Option<&TsigKey>,
+    zone: &Name,
+) -> anyhow::Result<Transfer> {
+    let rrs = transfer(stream, key, &query_wire(zone, RRType::AXFR, None), |rrs| {
+        rrs.len() > 1 && rrs.last().unwrap().typ == RRType::SOA
+    })
+    .await?;
+    if rrs[0].typ != RRType::SOA {
+        bail!("axfr doesn't start with soa");
+    }
+    Ok(Transfer::Full(group_rrs(&rrs[..rrs.len() - 1])))
+}
+
+async fn ixfr(
+    stream: &mut TcpStream,
+    key: Option<&TsigKey>,
+    zone: &Name,
+    serial: u32,
+) -> anyhow::Result<Transfer> {
+    let wire = query_wire(zone, RRType::IXFR, Some(serial));
+    let rrs = transfer(stream, key, &wire, |rrs| {
+        matches!(parse_ixfr(rrs, serial), Ok(Some(_)) | Err(_))
+    })
+    .await?;
+    parse_ixfr(&rrs, serial)?.ok_or_else(|| anyhow::anyhow!("incomplete ixfr"))
+}
+
+//send the query and read the answers until they are complete, rrsets
+//are split into single rrs to keep the order of ixfr sequences
+async fn transfer<F: Fn(&[RRset]) -> bool>(
+    stream: &mut TcpStream,
+    key: Option<&TsigKey>,
+    wire: &[u8],
+    is_complete: F,
+) -> anyhow::Result<Vec<RRset>> {
+    let id = u16::from_be_bytes([wire[0], wire[1]]);
+    let mut session = send_query(stream, key, wire).await?;
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let mut rrs = Vec::new();
+    loop {
+        let resp = read_response(stream, &mut buf, session.as_mut()).await?;
+        check_response(&resp, id)?;
+        if let Some(answer) = resp.section(SectionType::Answer) {
+            rrs.extend(answer.iter().flat_map(split_rrset));
+        }
+        if rrs.is_empty() {
+            bail!("empty transfer");
+        }
+        if is_complete(&rrs) {
+            if let Some(session) = session {
+                session.finish()?;
+            }
+            return Ok(rrs);
+        }
+    }
+}
+
+async fn exchange(
+    stream: &mut TcpStream,
+    key: Option<&TsigKey>,
+    wire: &[u8],
+) -> anyhow::Result<Response> {
+    let mut session = send_query(stream, key, wire).await?;
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let resp = read_response(stream, &mut buf, session.as_mut()).await?;
+    check_response(&resp, u16::from_be_bytes([wire[0], wire[1]]))?;
+    Ok(resp)
+}
+
+//the session verifies the responses to the signed query
+async fn send_query(
+    stream: &mut TcpStream,
+    key: Option<&TsigKey>,
+    wire: &[u8],
+) -> anyhow::Result<Option<TsigSession>> {
+    let mut session = key.cloned().map(TsigSession::new);
+    match session.as_mut() {
+        Some(session) => {
+            let mut wire = wire.to_vec();
+            session.sign(&mut wire);
+            write_message(stream, &wire).await?;
+        }
+        None => write_message(stream, wire).await?,
+    }
+    Ok(session)
+}
+
+async fn read_response(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    session: Option<&mut TsigSession>,
+) -> anyhow::Result<Response> {
+    let len = read_message(stream, buf).await?;
+    match session {
+        Some(session) => Response::from_wire(&session.verify(&buf[..len])?),
+        None => Response::from_wire(&buf[..len]),
+    }
+}
+
+fn check_response(resp: &Response, id: u16) -> anyhow::Result<()> {
+    if resp.header.id != id {
+        bail!("response id mismatch");
+    }
+    if resp.header.rcode != Rcode::NoError {
+        bail!("primary returns {:?}", resp.header.rcode);
+    }
+    Ok(())
+}
+
+//id of the queries sent to other servers, it can't be guessed so an off
+//path attacker can't forge the answer
+pub(crate) fn random_id() -> u16 {
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .expect("system random generator failed");
+    u16::from_be_bytes(id)
+}
+
+//ixfr query carries the local soa in authority section, only its serial
+//is looked at by the primary, so the other fields are left empty
+fn query_wire(zone: &Name, typ: RRType, serial: Option<u32>) -> Vec<u8> {
+    let mut req = Request::new(zone.clone(), typ);
+    req.header.id = random_id();
+    let mut buf = vec![0; 512];
+    let len = {
+        let mut render = MessageRender::new(&mut buf[..]);
+        req.to_wire(&mut render).unwrap()
+    };
+    buf.truncate(len);
+    if let Some(serial) = serial {
+        buf[9] = 1;
+        buf.extend_from_slice(&[0xc0, 12, 0, 6, 0, 1, 0, 0, 0, 0, 0, 22, 0, 0]);
+        buf.extend_from_slice(&serial.to_be_bytes());
+        buf.extend_from_slice(&[0; 16]);
+    }
+    buf
+}
+
+fn split_rrset(rrset: &RRset) -> Vec<RRset> {
+    rrset
+        .rdatas
+        .iter()
+        .map(|rdata| {
+            let mut rr = rrset.clone();
+            rr.rdatas = vec![rdata.clone()];
+            rr
+        })
+        .collect()
+}
+
+fn rr_key(rr: &RRset) -> (String, u16) {
+    (rr.name.to_string().to_lowercase(), rr.typ.as_u16())
+}
+
+//merge rrs with the same name and type into rrsets, in the order the
+//rrsets first appear
+fn group_rrs(rrs: &[RRset]) -> Vec<RRset> {
+    let mut rrsets: Vec<RRset> = Vec::new();
+    let mut index: HashMap<(String, u16), usize> = HashMap::new();
+    for rr in rrs {
+        match index.get(&rr_key(rr)) {
+            Some(&i) => rrsets[i].rdatas.extend(rr.rdatas.iter().cloned()),
+            None => {
+                index.insert(rr_key(rr), rrsets.len());
+                rrsets.push(rr.clone());
+            }
+        }
+    }
+    rrsets
+}
+
+//rfc1995 response, Ok(None) means more messages are expected. a response
+//with the second rr not being soa is the whole zone like axfr
+fn parse_ixfr(rrs: &[RRset], serial: u32) -> anyhow::Result<Option<Transfer>> {
+    if rrs[0].typ != RRType::SOA {
+        bail!("ixfr doesn't start with soa");
+    }
+    let new_serial = soa_serial(&rrs[0]).ok_or_else(|| anyhow::anyhow!("invalid soa"))?;
+    if rrs.len() == 1 {
+        if serial_gt(new_serial, serial) {
+            return Ok(None);
+        }
+        return Ok(Some(Transfer::UpToDate));
+    }
+    if rrs[1].typ != RRType::SOA {
+        if rrs.last().unwrap().typ != RRType::SOA {
+            return Ok(None);
+        }
+        return Ok(Some(Transfer::Full(group_rrs(&rrs[..rrs.len() - 1]))));
+    }
+
+    let mut diffs = Vec::new();
+    let mut pos = 1;
+    loop {
+        //from soa of the next difference or the last soa
+        if pos == rrs.len() - 1 && soa_serial(&rrs[pos]) == Some(new_serial) && !diffs.is_empty() {
+            return Ok(Some(Transfer::Incremental(diffs)));
+        }
+        let from_soa = rrs[pos].clone();
+        let deleted = rrs[pos + 1..]
+            .iter()
+            .take_while(|rr| rr.typ != RRType::SOA)
+            .cloned()
+            .collect::<Vec<RRset>>();
+        pos += deleted.len() + 1;
+        let to_soa = match rrs.get(pos) {
+            Some(soa) => soa.clone(),
+            None => return Ok(None),
+        };
+        let added = rrs[pos + 1..]
+            .iter()
+            .take_while(|rr| rr.typ != RRType::SOA)
+            .cloned()
+            .collect::<Vec<RRset>>();
+        pos += added.len() + 1;
+        if pos >= rrs.len() {
+            return Ok(None);
+        }
+        diffs.push(ZoneDiff {
+            from_soa,
+            deleted: group_rrs(&deleted),
+            to_soa,
+            added: group_rrs(&added),
+        });
+    }
+}
+
+//zone content after the differences are applied in order, the serials
+//of the differences must follow the current one
+pub(crate) fn apply_diffs(rrsets: Vec<RRset>, diffs: &[ZoneDiff]) -> anyhow::Result<Vec<RRset>> {
+    let mut rrsets = rrsets;
+    let mut index = rrsets
+        .iter()
+        .enumerate()
+        .map(|(i, rrset)| (rr_key(rrset), i))
+        .collect::<HashMap<(String, u16), usize>>();
+    let soa = *index
+        .values()
+        .find(|&&i| rrsets[i].typ == RRType::SOA)
+        .ok_or_else(|| anyhow::anyhow!("no soa at zone apex"))?;
+
+    for diff in diffs {
+        if soa_serial(&diff.from_soa) != soa_serial(&rrsets[soa]) {
+            bail!("difference doesn't start from the current serial");
+        }
+        for rr in diff.deleted.iter() {
+            let rrset = index
+                .get(&rr_key(rr))
+                .map(|&i| &mut rrsets[i])
+                .ok_or_else(|| {
+                    anyhow::anyhow!("deleted rrset {} {} isn't found", rr.name, rr.typ)
+                })?;
+            if !rr.rdatas.iter().all(|rdata| rrset.rdatas.contains(rdata)) {
+                bail!("deleted rr {} {} isn't found", rr.name, rr.typ);
+            }
+            rrset.rdatas.retain(|rdata| !rr.rdatas.contains(rdata));
+        }
+        for rr in diff.added.iter() {
+            match index.get(&rr_key(rr)) {
+                Some(&i) => {
+                    let rrset = &mut rrsets[i];
+                    rrset.ttl = rr.ttl;
+                    for rdata in rr.rdatas.iter() {
+                        if !rrset.rdatas.contains(rdata) {
+                            rrset.rdatas.push(rdata.clone());
+                        }
+                    }
+                }
+                None => {
+                    index.insert(rr_key(rr), rrsets.len());
+                    rrsets.push(rr.clone());
+                }
+            }
+        }
+        rrsets[soa] = diff.to_soa.clone();
+    }
+    rrsets.retain(|rrset| !rrset.rdatas.is_empty());
+    Ok(rrsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn soa(serial: u32) -> RRset {
+        RRset::from_str(&format!(
+            "example.com. 3600 IN SOA ns.example.com. root.example.com. {} 1 1 3 600",
+            serial
+        ))
+        .unwrap()
+    }
+
+    fn rr(s: &str) -> RRset {
+        RRset::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_soa_timers() {
+        let timers = soa_timers(&soa(1)).unwrap();
+        assert_eq!(timers.refresh, Duration::from_secs(1));
+        assert_eq!(timers.retry, Duration::from_secs(1));
+        assert_eq!(timers.expire, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_parse_ixfr() {
+        let a1 = rr("www.example.com. 3600 IN A 1.1.1.1");
+        let a2 = rr("www.example.com. 3600 IN A 2.2.2.2");
+        let ns = rr("example.com. 3600 IN NS ns.example.com.");
+        let rrs = vec![
+            soa(3),
+            soa(1),
+            soa(2),
+            a1.clone(),
+            a2.clone(),
+            soa(2),
+            a1.clone(),
+            soa(3),
+            soa(3),
+        ];
+        assert!(parse_ixfr(&rrs[..5], 1).unwrap().is_none());
+        assert!(parse_ixfr(&rrs[..8], 1).unwrap().is_none());
+        let diffs = match parse_ixfr(&rrs, 1).unwrap() {
+            Some(Transfer::Incremental(diffs)) => diffs,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(
+            diffs[0].added,
+            vec![group_rrs(&[a1.clone(), a2.clone()])[0].clone()]
+        );
+        assert_eq!(diffs[1].deleted, vec![a1.clone()]);
+
+        let rrsets = apply_diffs(vec![soa(1), ns.clone()], &diffs).unwrap();
+        assert_eq!(rrsets, vec![soa(3), ns.clone(), a2.clone()]);
+        assert!(apply_diffs(vec![soa(2), ns.clone()], &diffs).is_err());
+
+        //up to date and axfr style responses
+        assert!(matches!(
+            parse_ixfr(&[soa(1)], 1).unwrap(),
+            Some(Transfer::UpToDate)
+        ));
+        let full = vec![soa(3), ns.clone(), a1, a2, soa(3)];
+        assert!(parse_ixfr(&full[..4], 1).unwrap().is_none());
+        match parse_ixfr(&full, 1).unwrap() {
+            Some(Transfer::Full(rrsets)) => assert_eq!(rrsets.len(), 3),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{self, bail};
+use chrono::{DateTime, Utc};
+use r53::RRset;
+use std::str::FromStr;
+
+const HALF_SERIAL_SPACE: u32 = 1 << 31;
+
+//how the soa serial is bumped on each zone change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialPolicy {
+    Increment,
+    //seconds since epoch
+    UnixTime,
+    //YYYYMMDDnn, nn counts the changes in the day
+    Date,
+}
+
+impl FromStr for SerialPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "increment" => Ok(SerialPolicy::Increment),
+            "unixtime" => Ok(SerialPolicy::UnixTime),
+            "date" => Ok(SerialPolicy::Date),
+            _ => bail!(
+                "unknown serial policy {}, should be increment, unixtime or date",
+                s
+            ),
+        }
+    }
+}
+
+impl SerialPolicy {
+    //serial after current, time based serial falls back to increment when
+    //it isn't newer than current, so serial never goes backward
+    pub fn next(&self, current: u32, now: DateTime<Utc>) -> u32 {
+        let candidate = match self {
+            SerialPolicy::Increment => None,
+            SerialPolicy::UnixTime => Some(now.timestamp() as u32),
+            SerialPolicy::Date => now
+                .format("%Y%m%d")
+                .to_string()
+                .parse::<u32>()
+                .ok()
+                .map(|date| date * 100),
+        };
+        match candidate {
+            Some(serial) if serial_gt(serial, current) => serial,
+            _ => serial_add(current, 1),
+        }
+    }
+}
+
+//serial number arithmetic in rfc1982, n should be less than 2^31
+pub fn serial_add(serial: u32, n: u32) -> u32 {
+    debug_assert!(n < HALF_SERIAL_SPACE);
+    serial.wrapping_add(n)
+}
+
+//whether s1 is newer than s2 in rfc1982, the comparison of serials which
+//are 2^31 apart is undefined and treated as not greater
+pub fn serial_gt(s1: u32, s2: u32) -> bool {
+    (s1 < s2 && s2 - s1 > HALF_SERIAL_SPACE) || (s1 > s2 && s1 - s2 < HALF_SERIAL_SPACE)
+}
+
+//serial is the third field of soa rdata
+pub fn soa_serial(soa: &RRset) -> Option<u32> {
+    soa.rdatas
+        .first()?
+        .to_string()
+        .split_whitespace()
+        .nth(2)?
+        .parse::<u32>()
+        .ok()
+}
+
+//minimum is the last field of soa rdata, the ttl of negative answers is
+//the smaller one of it and the soa ttl(rfc2308)
+pub fn soa_negative_ttl(soa: &RRset) -> Option<u32> {
+    let minimum = soa
+        .rdatas
+        .first()?
+        .to_string()
+        .split_whitespace()
+        .nth(6)?
+        .parse::<u32>()
+        .ok()?;
+    Some(minimum.min(soa.ttl.0))
+}
+
+pub fn set_soa_serial(soa: &RRset, serial: u32) -> anyhow::Result<RRset> {
+    let rdata = soa
+        .rdatas
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("soa without rdata"))?
+        .to_string();
+    let mut fields = rdata.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != 7 {
+        bail!("invalid soa rdata {}", rdata);
+    }
+    let serial = serial.to_string();
+    fields[2] = &serial;
+    RRset::from_str(&format!(
+        "{} {} IN SOA {}",
+        soa.name,
+        soa.ttl.0,
+        fields.join(" ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_arithmetic() {
+        assert!(serial_gt(1, 0));
+        assert!(!serial_gt(0, 1));
+        assert!(!serial_gt(5, 5));
+        assert!(serial_gt(0, u32::MAX));
+        assert!(serial_gt(100, u32::MAX - 100));
+        assert!(!serial_gt(u32::MAX, 0));
+        assert!(serial_gt(HALF_SERIAL_SPACE - 1, 0));
+        assert!(!serial_gt(HALF_SERIAL_SPACE, 0));
+        assert!(!serial_gt(0, HALF_SERIAL_SPACE));
+        assert_eq!(serial_add(u32::MAX, 1), 0);
+        assert_eq!(serial_add(u32::MAX - 1, 10), 8);
+    }
+
+    #[test]
+    fn test_serial_policy() {
+        let now = "2022-06-01T08:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(SerialPolicy::Increment.next(1, now), 2);
+        assert_eq!(SerialPolicy::Increment.next(u32::MAX, now), 0);
+
+        let unixtime = now.timestamp() as u32;
+        assert_eq!(SerialPolicy::UnixTime.next(1, now), unixtime);
+        assert_eq!(SerialPolicy::UnixTime.next(unixtime, now), unixtime + 1);
+
+        assert_eq!(SerialPolicy::Date.next(1, now), 2022060100);
+        assert_eq!(SerialPolicy::Date.next(2022060100, now), 2022060101);
+        assert_eq!(SerialPolicy::Date.next(2022060199, now), 2022060200);
+        //serial from other policy is never moved backward
+        assert_eq!(SerialPolicy::Date.next(3000000000, now), 3000000001);
+
+        assert_eq!("date".parse::<SerialPolicy>().unwrap(), SerialPolicy::Date);
+        assert!("day".parse::<SerialPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_soa_serial() {
+        let soa = RRset::from_str(
+            "com. 3600 IN SOA a.gtld-servers.net. nstld.verisign-grs.com. 1653038001 1800 900 604800 86400",
+        )
+        .unwrap();
+        assert_eq!(soa_serial(&soa), Some(1653038001));
+        let soa = set_soa_serial(&soa, 1653038002).unwrap();
+        assert_eq!(
+            soa.to_string(),
+            "com.\t3600\tIN\tSOA\ta.gtld-servers.net. nstld.verisign-grs.com. 1653038002 1800 900 604800 86400\n"
+        );
+    }
+}
@@ -0,0 +1,583 @@
+use anyhow::{self, bail};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::dnssec::Nsec3Config;
+
+const JOURNAL_FILE: &str = "journal";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+
+//mutation made through the api, which is journaled before it's applied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Operation {
+    //content of the new zone in master file format
+    AddZone {
+        name: String,
+        content: String,
+        #[serde(default)]
+        allow_transfer: Vec<String>,
+        #[serde(default)]
+        also_notify: Vec<String>,
+        #[serde(default)]
+        allow_update: Vec<String>,
+        #[serde(default)]
+        dnssec_keys: Vec<String>,
+        #[serde(default)]
+        nsec3: Option<Nsec3Config>,
+    },
+    //zone changes carry the soa serial after the change
+    AddRRset {
+        zone: String,
+        rrset: String,
+        serial: u32,
+    },
+    DeleteZone {
+        name: String,
+    },
+    DeleteRRset {
+        zone: String,
+        name: String,
+        typ: String,
+        serial: u32,
+    },
+    DeleteRR {
+        zone: String,
+        rrset: String,
+        serial: u32,
+    },
+    SetAllowTransfer {
+        zone: String,
+        acl: Vec<String>,
+    },
+    SetAlsoNotify {
+        zone: String,
+        targets: Vec<String>,
+    },
+    SetAllowUpdate {
+        zone: String,
+        acl: Vec<String>,
+    },
+    //rrsets changed by one dynamic update
+    UpdateRRsets {
+        zone: String,
+        changes: Vec<RRsetChange>,
+        serial: u32,
+    },
+    //private keys the zone is signed with, the zone is unsigned when
+    //there is no key
+    SetDnssecKeys {
+        zone: String,
+        keys: Vec<String>,
+        #[serde(default)]
+        nsec3: Option<Nsec3Config>,
+        serial: u32,
+    },
+    //signatures expiring soon after now are made again
+    RefreshSignatures {
+        zone: String,
+        now: u32,
+        serial: u32,
+    },
+}
+
+//rrset of the name and type after the change, None if it's deleted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RRsetChange {
+    pub name: String,
+    pub typ: String,
+    pub rrset: Option<String>,
+}
+
+impl Operation {
+    //name of the zone the operation changes
+    pub fn zone(&self) -> &str {
+        match self {
+            Operation::AddZone { name, .. } | Operation::DeleteZone { name } => name,
+            Operation::AddRRset { zone, .. }
+            | Operation::DeleteRRset { zone, .. }
+            | Operation::DeleteRR { zone, .. }
+            | Operation::SetAllowTransfer { zone, .. }
+            | Operation::SetAlsoNotify { zone, .. }
+            | Operation::SetAllowUpdate { zone, .. }
+            | Operation::UpdateRRsets { zone, .. }
+            | Operation::SetDnssecKeys { zone, .. }
+            | Operation::RefreshSignatures { zone, .. } => zone,
+        }
+    }
+
+    //soa serial of the zone after the change, None if the operation
+    //doesn't change the content
+    pub fn serial(&self) -> Option<u32> {
+        match self {
+            Operation::AddRRset { serial, .. }
+            | Operation::DeleteRRset { serial, .. }
+            | Operation::DeleteRR { serial, .. }
+            | Operation::UpdateRRsets { serial, .. }
+            | Operation::SetDnssecKeys { serial, .. }
+            | Operation::RefreshSignatures { serial, .. } => Some(*serial),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JournalEntry {
+    seq: u64,
+    #[serde(flatten)]
+    op: Operation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ZoneSnapshot {
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub allow_transfer: Vec<String>,
+    #[serde(default)]
+    pub also_notify: Vec<String>,
+    #[serde(default)]
+    pub allow_update: Vec<String>,
+    #[serde(default)]
+    pub dnssec_keys: Vec<String>,
+    #[serde(default)]
+    pub nsec3: Option<Nsec3Config>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Snapshot {
+    seq: u64,
+    zones: Vec<ZoneSnapshot>,
+}
+
+//durable storage of auth data: a snapshot of all the zones plus a journal of
+//the operations after it. each journal line is "<crc32> <json>", a torn or
+//corrupted tail left by a crash is detected by the checksum and discarded
+pub(crate) struct Store {
+    dir: PathBuf,
+    journal: File,
+    seq: u64,
+    journal_len: u64,
+    //length of the last entry, which could still be discarded
+    last_len: u64,
+}
+
+//what has to be replayed to rebuild the state
+pub(crate) struct Recovery {
+    pub zones: Vec<ZoneSnapshot>,
+    pub operations: Vec<Operation>,
+}
+
+impl Store {
+    pub fn open(dir: &Path) -> anyhow::Result<(Self, Recovery)> {
+        fs::create_dir_all(dir)?;
+        let snapshot = match fs::read_to_string(dir.join(SNAPSHOT_FILE)) {
+            Ok(content) => serde_json::from_str::<Snapshot>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => bail!(e),
+        };
+
+        let mut journal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(JOURNAL_FILE))?;
+        let mut content = Vec::new();
+        journal.read_to_end(&mut content)?;
+        let (entries, valid_len) = parse_journal(&content);
+        if valid_len < content.len() {
+            journal.set_len(valid_len as u64)?;
+            journal.sync_all()?;
+        }
+
+        let mut seq = snapshot.seq;
+        let mut operations = Vec::new();
+        for entry in entries {
+            //entries already in the snapshot, left by a crash between
+            //snapshot and journal truncation
+            if entry.seq <= snapshot.seq {
+                continue;
+            }
+            seq = entry.seq;
+            operations.push(entry.op);
+        }
+
+        Ok((
+            Store {
+                dir: dir.to_path_buf(),
+                journal,
+                seq,
+                journal_len: valid_len as u64,
+                last_len: 0,
+            },
+            Recovery {
+                zones: snapshot.zones,
+                operations,
+            },
+        ))
+    }
+
+    pub fn append(&mut self, op: &Operation) -> anyhow::Result<()> {
+        let entry = JournalEntry {
+            seq: self.seq + 1,
+            op: op.clone(),
+        };
+        let json = serde_json::to_string(&entry)?;
+        let line = format!("{:08x} {}\n", crc32fast::hash(json.as_bytes()), json);
+        if let Err(e) = self
+            .journal
+            .write_all(line.as_bytes())
+            .and_then(|_| self.journal.sync_data())
+        {
+            //drop the partial write so later entries are still readable
+            let _ = self.journal.set_len(self.journal_len);
+            bail!(e);
+        }
+        self.seq += 1;
+        self.journal_len += line.len() as u64;
+        self.last_len = line.len() as u64;
+        Ok(())
+    }
+
+    //drop the entry just appended, whose operation failed to apply
+    pub fn discard_last(&mut self) -> anyhow::Result<()> {
+        if self.last_len == 0 {
+            bail!("no entry to discard");
+        }
+        self.journal.set_len(self.journal_len - self.last_len)?;
+        self.journal.sync_data()?;
+        self.seq -= 1;
+        self.journal_len -= self.last_len;
+        self.last_len = 0;
+        Ok(())
+    }
+
+    pub fn snapshot(&mut self, zones: Vec<ZoneSnapshot>) -> anyhow::Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            zones,
+        };
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(serde_json::to_string(&snapshot)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.journal.set_len(0)?;
+        self.journal.sync_all()?;
+        self.journal_len = 0;
+        self.last_len = 0;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn journal_len(&self) -> u64 {
+        self.journal_len
+    }
+}
+
+//returns the valid entries and the length of the valid prefix
+fn parse_journal(content: &[u8]) -> (Vec<JournalEntry>, usize) {
+    let mut entries = Vec::new();
+    let mut valid_len = 0;
+    while valid_len < content.len() {
+        let rest = &content[valid_len..];
+        let end = match rest.iter().position(|c| *c == b'\n') {
+            Some(end) => end,
+            None => break,
+        };
+        match parse_journal_line(&rest[..end]) {
+            Some(entry) => entries.push(entry),
+            None => break,
+        }
+        valid_len += end + 1;
+    }
+    (entries, valid_len)
+}
+
+fn parse_journal_line(line: &[u8]) -> Option<JournalEntry> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (crc, json) = line.split_once(' ')?;
+    if u32::from_str_radix(crc, 16).ok()? != crc32fast::hash(json.as_bytes()) {
+        return None;
+    }
+    serde_json::from_str(json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn add_rrset_op(i: usize) -> Operation {
+        Operation::AddRRset {
+            zone: "example.com.".to_string(),
+            rrset: format!("a{}.example.com. 3600 IN A 1.1.1.1", i),
+            serial: i as u32 + 2,
+        }
+    }
+
+    //one operation of each kind, adding a kind fails to compile until it's
+    //added here and to JOURNAL
+    fn every_operation() -> Vec<Operation> {
+        let zone = "example.com.".to_string();
+        let nsec3 = Nsec3Config {
+            iterations: 1,
+            salt: "aabb".to_string(),
+            opt_out: true,
+        };
+        let ops = vec![
+            Operation::AddZone {
+                name: zone.clone(),
+                content: "content".to_string(),
+                allow_transfer: vec!["10.0.0.0/8".to_string()],
+                also_notify: vec!["10.0.0.2:53".to_string()],
+                allow_update: vec!["10.0.0.3/32".to_string()],
+                dnssec_keys: vec!["key".to_string()],
+                nsec3: Some(nsec3.clone()),
+            },
+            add_rrset_op(0),
+            Operation::DeleteZone { name: zone.clone() },
+            Operation::DeleteRRset {
+                zone: zone.clone(),
+                name: "www.example.com.".to_string(),
+                typ: "A".to_string(),
+                serial: 3,
+            },
+            Operation::DeleteRR {
+                zone: zone.clone(),
+                rrset: "www.example.com. 3600 IN A 2.2.2.2".to_string(),
+                serial: 4,
+            },
+            Operation::SetAllowTransfer {
+                zone: zone.clone(),
+                acl: vec!["10.0.0.0/8".to_string()],
+            },
+            Operation::SetAlsoNotify {
+                zone: zone.clone(),
+                targets: vec!["10.0.0.2:53".to_string()],
+            },
+            Operation::SetAllowUpdate {
+                zone: zone.clone(),
+                acl: vec!["10.0.0.3/32".to_string()],
+            },
+            Operation::UpdateRRsets {
+                zone: zone.clone(),
+                changes: vec![
+                    RRsetChange {
+                        name: "www.example.com.".to_string(),
+                        typ: "A".to_string(),
+                        rrset: Some("www.example.com. 3600 IN A 2.2.2.2".to_string()),
+                    },
+                    RRsetChange {
+                        name: "ftp.example.com.".to_string(),
+                        typ: "A".to_string(),
+                        rrset: None,
+                    },
+                ],
+                serial: 5,
+            },
+            Operation::SetDnssecKeys {
+                zone: zone.clone(),
+                keys: vec!["key".to_string()],
+                nsec3: Some(nsec3),
+                serial: 6,
+            },
+            Operation::RefreshSignatures {
+                zone,
+                now: 1000,
+                serial: 7,
+            },
+        ];
+        for op in ops.iter() {
+            match op {
+                Operation::AddZone { .. }
+                | Operation::AddRRset { .. }
+                | Operation::DeleteZone { .. }
+                | Operation::DeleteRRset { .. }
+                | Operation::DeleteRR { .. }
+                | Operation::SetAllowTransfer { .. }
+                | Operation::SetAlsoNotify { .. }
+                | Operation::SetAllowUpdate { .. }
+                | Operation::UpdateRRsets { .. }
+                | Operation::SetDnssecKeys { .. }
+                | Operation::RefreshSignatures { .. } => {}
+            }
+        }
+        ops
+    }
+
+    //every_operation as written to the journal, existing journals have to
+    //stay readable whatever the format changes into
+    const JOURNAL: &str = r#"{"op":"add_zone","name":"example.com.","content":"content","allow_transfer":["10.0.0.0/8"],"also_notify":["10.0.0.2:53"],"allow_update":["10.0.0.3/32"],"dnssec_keys":["key"],"nsec3":{"iterations":1,"salt":"aabb","opt_out":true}}
+{"op":"add_r_rset","zone":"example.com.","rrset":"a0.example.com. 3600 IN A 1.1.1.1","serial":2}
+{"op":"delete_zone","name":"example.com."}
+{"op":"delete_r_rset","zone":"example.com.","name":"www.example.com.","typ":"A","serial":3}
+{"op":"delete_r_r","zone":"example.com.","rrset":"www.example.com. 3600 IN A 2.2.2.2","serial":4}
+{"op":"set_allow_transfer","zone":"example.com.","acl":["10.0.0.0/8"]}
+{"op":"set_also_notify","zone":"example.com.","targets":["10.0.0.2:53"]}
+{"op":"set_allow_update","zone":"example.com.","acl":["10.0.0.3/32"]}
+{"op":"update_r_rsets","zone":"example.com.","changes":[{"name":"www.example.com.","typ":"A","rrset":"www.example.com. 3600 IN A 2.2.2.2"},{"name":"ftp.example.com.","typ":"A","rrset":null}],"serial":5}
+{"op":"set_dnssec_keys","zone":"example.com.","keys":["key"],"nsec3":{"iterations":1,"salt":"aabb","opt_out":true},"serial":6}
+{"op":"refresh_signatures","zone":"example.com.","now":1000,"serial":7}
+"#;
+
+    #[test]
+    fn test_operation_format() {
+        let ops = every_operation();
+        let lines = JOURNAL.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), ops.len());
+        for (op, line) in ops.iter().zip(lines) {
+            assert_eq!(serde_json::to_string(op).unwrap(), line);
+            assert_eq!(&serde_json::from_str::<Operation>(line).unwrap(), op);
+        }
+
+        //written before the optional fields were added
+        let op = serde_json::from_str::<Operation>(
+            r#"{"op":"add_zone","name":"example.com.","content":"content"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            op,
+            Operation::AddZone {
+                name: "example.com.".to_string(),
+                content: "content".to_string(),
+                allow_transfer: Vec::new(),
+                also_notify: Vec::new(),
+                allow_update: Vec::new(),
+                dnssec_keys: Vec::new(),
+                nsec3: None,
+            }
+        );
+        let op = serde_json::from_str::<Operation>(
+            r#"{"op":"set_dnssec_keys","zone":"example.com.","keys":[],"serial":6}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            op,
+            Operation::SetDnssecKeys {
+                zone: "example.com.".to_string(),
+                keys: Vec::new(),
+                nsec3: None,
+                serial: 6,
+            }
+        );
+
+        let dir = temp_dir("store-format");
+        let (mut store, _) = Store::open(&dir).unwrap();
+        for op in ops.iter() {
+            store.append(op).unwrap();
+        }
+        drop(store);
+        let (_, recovery) = Store::open(&dir).unwrap();
+        assert_eq!(recovery.operations, ops);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_replay() {
+        let dir = temp_dir("store-replay");
+        let (mut store, recovery) = Store::open(&dir).unwrap();
+        assert!(recovery.zones.is_empty() && recovery.operations.is_empty());
+        for i in 0..3 {
+            store.append(&add_rrset_op(i)).unwrap();
+        }
+        drop(store);
+
+        let (mut store, recovery) = Store::open(&dir).unwrap();
+        assert_eq!(
+            recovery.operations,
+            (0..3).map(add_rrset_op).collect::<Vec<_>>()
+        );
+
+        let zones = vec![ZoneSnapshot {
+            name: "example.com.".to_string(),
+            content: "content".to_string(),
+            allow_transfer: vec!["10.0.0.0/8".to_string()],
+            also_notify: vec!["10.0.0.2:53".to_string()],
+            allow_update: vec!["10.0.0.3/32".to_string()],
+            dnssec_keys: Vec::new(),
+            nsec3: None,
+        }];
+        store.snapshot(zones.clone()).unwrap();
+        assert_eq!(store.journal_len(), 0);
+        store.append(&add_rrset_op(3)).unwrap();
+        //entry of the operation failed to apply is dropped
+        let len = store.journal_len();
+        store.append(&add_rrset_op(4)).unwrap();
+        store.discard_last().unwrap();
+        assert_eq!(store.journal_len(), len);
+        assert!(store.discard_last().is_err());
+        drop(store);
+
+        let (_, recovery) = Store::open(&dir).unwrap();
+        assert_eq!(recovery.zones, zones);
+        assert_eq!(recovery.operations, vec![add_rrset_op(3)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let dir = temp_dir("store-torn");
+        let (mut store, _) = Store::open(&dir).unwrap();
+        store.append(&add_rrset_op(0)).unwrap();
+        let valid_len = store.journal_len() as usize;
+        store.append(&add_rrset_op(1)).unwrap();
+        drop(store);
+
+        //crash at any point while writing the second entry
+        let journal_path = dir.join(JOURNAL_FILE);
+        let content = fs::read(&journal_path).unwrap();
+        for cut in valid_len..content.len() {
+            fs::write(&journal_path, &content[..cut]).unwrap();
+            let (mut store, recovery) = Store::open(&dir).unwrap();
+            assert_eq!(recovery.operations, vec![add_rrset_op(0)]);
+            assert_eq!(
+                fs::metadata(&journal_path).unwrap().len() as usize,
+                valid_len
+            );
+
+            //journal is still appendable after recovery
+            store.append(&add_rrset_op(2)).unwrap();
+            drop(store);
+            let (_, recovery) = Store::open(&dir).unwrap();
+            assert_eq!(recovery.operations, vec![add_rrset_op(0), add_rrset_op(2)]);
+        }
+
+        //corrupted entry is treated the same as a torn one
+        let mut corrupted = content.clone();
+        corrupted[valid_len + 12] ^= 0xff;
+        fs::write(&journal_path, &corrupted).unwrap();
+        let (_, recovery) = Store::open(&dir).unwrap();
+        assert_eq!(recovery.operations, vec![add_rrset_op(0)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_before_journal_truncated() {
+        let dir = temp_dir("store-snapshot");
+        let (mut store, _) = Store::open(&dir).unwrap();
+        store.append(&add_rrset_op(0)).unwrap();
+        store.append(&add_rrset_op(1)).unwrap();
+        let journal = fs::read(dir.join(JOURNAL_FILE)).unwrap();
+        store.snapshot(Vec::new()).unwrap();
+        drop(store);
+
+        //snapshot is renamed but the journal isn't truncated yet
+        fs::write(dir.join(JOURNAL_FILE), &journal).unwrap();
+        let (mut store, recovery) = Store::open(&dir).unwrap();
+        assert!(recovery.operations.is_empty());
+        store.append(&add_rrset_op(2)).unwrap();
+        drop(store);
+
+        let (_, recovery) = Store::open(&dir).unwrap();
+        assert_eq!(recovery.operations, vec![add_rrset_op(2)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use r53::{Name, RRClass, RRTtl, RRType, RRset, Rcode};
+
+use super::serial::{serial_gt, soa_serial};
+use super::zone::MemoryZone;
+use crate::server::{UpdateClass, UpdateRR};
+
+//rrset of the name and type after the update, None if it's deleted
+pub(crate) type RRsetChanges = Vec<(Name, RRType, Option<RRset>)>;
+
+fn is_meta_type(typ: RRType) -> bool {
+    matches!(
+        typ,
+        RRType::ANY | RRType::AXFR | RRType::IXFR | RRType::OPT | RRType::TSIG
+    )
+}
+
+fn to_rrset(rr: &UpdateRR) -> Option<RRset> {
+    Some(RRset {
+        name: rr.name.clone(),
+        typ: rr.typ,
+        class: RRClass::IN,
+        ttl: RRTtl(rr.ttl),
+        rdatas: vec![rr.rdata.clone()?],
+    })
+}
+
+//rfc2136 section 3.2
+pub(crate) fn check_prerequisites(
+    zone: &MemoryZone,
+    origin: &Name,
+    prerequisites: &[UpdateRR],
+) -> Result<(), Rcode> {
+    //rrsets which must exist with exactly the given rrs
+    let mut values: Vec<RRset> = Vec::new();
+    for rr in prerequisites {
+        if rr.ttl != 0 {
+            return Err(Rcode::FormErr);
+        }
+        if !rr.name.is_subdomain(origin) {
+            return Err(Rcode::NotZone);
+        }
+        match rr.class {
+            UpdateClass::Any | UpdateClass::None if rr.rdata.is_some() => {
+                return Err(Rcode::FormErr);
+            }
+            UpdateClass::Any if rr.typ == RRType::ANY => {
+                if zone.get_rrsets(&rr.name).is_empty() {
+                    return Err(Rcode::NXDomain);
+                }
+            }
+            UpdateClass::Any => {
+                if zone.get_rrset(&rr.name, rr.typ).is_none() {
+                    return Err(Rcode::NXRRset);
+                }
+            }
+            UpdateClass::None if rr.typ == RRType::ANY => {
+                if !zone.get_rrsets(&rr.name).is_empty() {
+                    return Err(Rcode::YXDomain);
+                }
+            }
+            UpdateClass::None => {
+                if zone.get_rrset(&rr.name, rr.typ).is_some() {
+                    return Err(Rcode::YXRRset);
+                }
+            }
+            UpdateClass::Zone => {
+                let rrset = to_rrset(rr).ok_or(Rcode::FormErr)?;
+                match values
+                    .iter_mut()
+                    .find(|value| value.typ == rr.typ && value.name.eq(&rr.name))
+                {
+                    Some(value) => value.rdatas.extend(rrset.rdatas),
+                    None => values.push(rrset),
+                }
+            }
+        }
+    }
+
+    for value in values {
+        let rrset = zone
+            .get_rrset(&value.name, value.typ)
+            .ok_or(Rcode::NXRRset)?;
+        let same = value
+            .rdatas
+            .iter()
+            .all(|rdata| rrset.rdatas.contains(rdata))
+            && rrset
+                .rdatas
+                .iter()
+                .all(|rdata| value.rdatas.contains(rdata));
+        if !same {
+            return Err(Rcode::NXRRset);
+        }
+    }
+    Ok(())
+}
+
+//rfc2136 section 3.4.1, the whole update is rejected before any change
+pub(crate) fn prescan(origin: &Name, updates: &[UpdateRR]) -> Result<(), Rcode> {
+    for rr in updates {
+        if !rr.name.is_subdomain(origin) {
+            return Err(Rcode::NotZone);
+        }
+        let valid = match rr.class {
+            UpdateClass::Zone => !is_meta_type(rr.typ) && rr.rdata.is_some(),
+            UpdateClass::Any => {
+                rr.ttl == 0
+                    && rr.rdata.is_none()
+                    && (rr.typ == RRType::ANY || !is_meta_type(rr.typ))
+            }
+            UpdateClass::None => rr.ttl == 0 && !is_meta_type(rr.typ) && rr.rdata.is_some(),
+        };
+        if !valid {
+            return Err(Rcode::FormErr);
+        }
+    }
+    Ok(())
+}
+
+//the zone with changes made so far by the update
+struct Overlay<'a> {
+    zone: &'a MemoryZone,
+    changes: RRsetChanges,
+}
+
+impl<'a> Overlay<'a> {
+    fn get(&self, name: &Name, typ: RRType) -> Option<RRset> {
+        match self
+            .changes
+            .iter()
+            .find(|(n, t, _)| *t == typ && n.eq(name))
+        {
+            Some((_, _, rrset)) => rrset.clone(),
+            None => self.zone.get_rrset(name, typ),
+        }
+    }
+
+    fn types(&self, name: &Name) -> Vec<RRType> {
+        let mut types = self
+            .zone
+            .get_rrsets(name)
+            .iter()
+            .map(|rrset| rrset.typ)
+            .collect::<Vec<RRType>>();
+        for (n, typ, _) in self.changes.iter() {
+            if n.eq(name) && !types.contains(typ) {
+                types.push(*typ);
+            }
+        }
+        types.retain(|typ| self.get(name, *typ).is_some());
+        types
+    }
+
+    fn set(&mut self, name: &Name, typ: RRType, rrset: Option<RRset>) {
+        match self
+            .changes
+            .iter_mut()
+            .find(|(n, t, _)| *t == typ && n.eq(name))
+        {
+            Some(change) => change.2 = rrset,
+            None => self.changes.push((name.clone(), typ, rrset)),
+        }
+    }
+}
+
+//rfc2136 section 3.4.2, rrs which can't be applied are ignored. the
+//changes which leave an rrset as it was are dropped
+pub(crate) fn apply_updates(
+    zone: &MemoryZone,
+    origin: &Name,
+    updates: &[UpdateRR],
+) -> RRsetChanges {
+    let mut overlay = Overlay {
+        zone,
+        changes: Vec::new(),
+    };
+    for rr in updates {
+        let at_apex = rr.name.eq(origin);
+        match rr.class {
+            UpdateClass::Zone => {
+                let rrset = to_rrset(rr).unwrap();
+                let types = overlay.types(&rr.name);
+                if rr.typ == RRType::SOA {
+                    let current = overlay
+                        .get(origin, RRType::SOA)
+                        .and_then(|soa| soa_serial(&soa));
+                    let newer = match (soa_serial(&rrset), current) {
+                        (Some(new), Some(current)) => serial_gt(new, current),
+                        _ => false,
+                    };
+                    if at_apex && newer {
+                        overlay.set(&rr.name, rr.typ, Some(rrset));
+                    }
+                } else if rr.typ == RRType::CNAME {
+                    if types.iter().all(|typ| *typ == RRType::CNAME) {
+                        overlay.set(&rr.name, rr.typ, Some(rrset));
+                    }
+                } else if !types.contains(&RRType::CNAME) {
+                    let mut new = overlay
+                        .get(&rr.name, rr.typ)
+                        .unwrap_or_else(|| rrset.clone());
+                    new.ttl = rrset.ttl;
+                    for rdata in rrset.rdatas {
+                        if !new.rdatas.contains(&rdata) {
+                            new.rdatas.push(rdata);
+                        }
+                    }
+                    overlay.set(&rr.name, rr.typ, Some(new));
+                }
+            }
+            UpdateClass::Any => {
+                let types = if rr.typ == RRType::ANY {
+                    overlay.types(&rr.name)
+                } else {
+                    vec![rr.typ]
+                };
+                for typ in types {
+                    if !(at_apex && (typ == RRType::SOA || typ == RRType::NS)) {
+                        overlay.set(&rr.name, typ, None);
+                    }
+                }
+            }
+            UpdateClass::None => {
+                if at_apex && rr.typ == RRType::SOA {
+                    continue;
+                }
+                if let Some(mut rrset) = overlay.get(&rr.name, rr.typ) {
+                    rrset
+                        .rdatas
+                        .retain(|rdata| Some(rdata) != rr.rdata.as_ref());
+                    if !rrset.rdatas.is_empty() {
+                        overlay.set(&rr.name, rr.typ, Some(rrset));
+                    } else if !(at_apex && rr.typ == RRType::NS) {
+                        overlay.set(&rr.name, rr.typ, None);
+                    }
+                }
+            }
+        }
+    }
+
+    overlay
+        .changes
+        .into_iter()
+        .filter(|(name, typ, rrset)| zone.get_rrset(name, *typ) != *rrset)
+        .collect()
+}
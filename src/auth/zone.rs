@@ -1,6 +1,20 @@
+use anyhow::{self, bail};
 use r53::{DomainTree, FindResultFlag, Name, NodeChain, NodePtr, RRType, RRset};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Bound::{Excluded, Unbounded};
 use std::sync::RwLock;
 
+use super::acl::Acl;
+use super::canonical::{labels_name, labels_wire, name_wire, wire_labels, CanonicalName};
+use super::dnssec::{
+    base32hex, cds_rrsets, covering_rrsigs, dnskey_rrset, now, nsec3_hash, nsec3_rdata,
+    nsec3param_rdata, nsec_rdata, parse_nsec3param, rdata_target, rdata_wires, rrset_from_rdatas,
+    rrsig_expiration, sign_rrsets, Nsec3Config, ZoneKey, SIGNATURE_REFRESH,
+};
+use super::error::AuthError;
+use super::notify::Remote;
+use super::serial::{set_soa_serial, soa_negative_ttl, soa_serial};
+
 #[derive(Debug)]
 pub(crate) enum FindMode {
     DefaultFind,
@@ -11,15 +25,64 @@ pub(crate) enum FindMode {
 pub(crate) enum FindResult {
     Success(RRset),
     Delegation(RRset),
+    //cname owned by the name, which has no rrset of the type
+    CName(RRset),
+    //dname above the name and the cname synthesized from it, None when the
+    //substituted name is too long
+    DName(RRset, Option<RRset>),
     NXDomain,
     NXRRset,
 }
 
 type RRsets = Vec<RRset>;
 
+//rrsets before a change by name and type, to record the difference
+type OldRRsets = BTreeMap<(CanonicalName, u16), (Name, RRType, Option<RRset>)>;
+
+//nsec or nsec3 records of the chain by owner name, with type, ttl and rdata
+type DenialChain = BTreeMap<CanonicalName, (Name, RRType, u32, Vec<u8>)>;
+
+//changes kept for ixfr, older ones are dropped and the clients behind
+//them get the whole zone
+const MAX_ZONE_DIFFS: usize = 100;
+
+//difference between two versions of the zone in ixfr format
+#[derive(Debug, Clone)]
+pub(crate) struct ZoneDiff {
+    pub from_soa: RRset,
+    pub deleted: Vec<RRset>,
+    pub to_soa: RRset,
+    pub added: Vec<RRset>,
+}
+
 pub(crate) struct MemoryZone {
     origin: Name,
     domains: RwLock<DomainTree<RRsets>>,
+    //names with rrsets in canonical order, to walk the tree in order
+    names: BTreeSet<CanonicalName>,
+    //clients allowed to transfer the zone
+    allow_transfer: Acl,
+    //clients allowed to send dynamic update
+    allow_update: Acl,
+    //secondaries notified when the serial changes
+    also_notify: Vec<Remote>,
+    diffs: VecDeque<ZoneDiff>,
+    //secondary zone is pulled from primaries, and isn't served once expired
+    secondary: bool,
+    expired: bool,
+    //keys of a signed zone, every authoritative rrset has its rrsig kept
+    //up to date as the zone changes
+    keys: Vec<ZoneKey>,
+    //signed zone uses nsec3 with the parameters, or nsec without them
+    nsec3: Option<Nsec3Config>,
+    //records of the chain made for the last version, the new chain is
+    //compared with them so only the changed records are replaced
+    chain: BTreeMap<CanonicalName, (RRType, u32, Vec<u8>)>,
+    //owners of the nsec or nsec3 records, made by signing or transferred
+    //from primaries, which prove the negative answers
+    denial_names: BTreeSet<CanonicalName>,
+    //salt and iterations of nsec3param at apex
+    nsec3_param: Option<(Vec<u8>, u16)>,
 }
 
 impl MemoryZone {
@@ -27,16 +90,593 @@ impl MemoryZone {
         Self {
             origin: name,
             domains: RwLock::new(DomainTree::new()),
+            names: BTreeSet::new(),
+            allow_transfer: Acl::default(),
+            allow_update: Acl::default(),
+            also_notify: Vec::new(),
+            diffs: VecDeque::new(),
+            secondary: false,
+            expired: false,
+            keys: Vec::new(),
+            nsec3: None,
+            chain: BTreeMap::new(),
+            denial_names: BTreeSet::new(),
+            nsec3_param: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_secondary(&self) -> bool {
+        self.secondary
+    }
+
+    pub fn set_secondary(&mut self, secondary: bool) {
+        self.secondary = secondary;
+    }
+
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    pub fn set_expired(&mut self, expired: bool) {
+        self.expired = expired;
+    }
+
+    #[inline]
+    pub fn allow_transfer(&self) -> &Acl {
+        &self.allow_transfer
+    }
+
+    pub fn set_allow_transfer(&mut self, acl: Acl) {
+        self.allow_transfer = acl;
+    }
+
+    #[inline]
+    pub fn allow_update(&self) -> &Acl {
+        &self.allow_update
+    }
+
+    pub fn set_allow_update(&mut self, acl: Acl) {
+        self.allow_update = acl;
+    }
+
+    #[inline]
+    pub fn also_notify(&self) -> &[Remote] {
+        &self.also_notify
+    }
+
+    pub fn set_also_notify(&mut self, targets: Vec<Remote>) {
+        self.also_notify = targets;
+    }
+
+    #[inline]
+    pub fn is_signed(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    #[inline]
+    pub fn keys(&self) -> &[ZoneKey] {
+        &self.keys
+    }
+
+    #[inline]
+    pub fn nsec3(&self) -> Option<&Nsec3Config> {
+        self.nsec3.as_ref()
+    }
+
+    //sign the whole zone with the keys, or remove the signatures if there
+    //is no key. it isn't a new version of the zone, which is for loading
+    pub fn set_keys(
+        &mut self,
+        keys: Vec<ZoneKey>,
+        nsec3: Option<Nsec3Config>,
+    ) -> anyhow::Result<()> {
+        //denial records in the content are made again
+        let owners = self
+            .denial_names
+            .iter()
+            .map(|owner| owner.name().clone())
+            .collect::<Vec<_>>();
+        for owner in owners {
+            for typ in [RRType::NSEC, RRType::NSEC3] {
+                if self.get_rrset(&owner, typ).is_some() {
+                    self.delete_rrset(&owner, typ)?;
+                }
+            }
+        }
+        self.chain.clear();
+        self.install_keys(keys, nsec3)?;
+        self.update_chain(&mut OldRRsets::new())?;
+        let names = self
+            .names
+            .iter()
+            .map(|name| name.name().clone())
+            .collect::<Vec<_>>();
+        let now = now();
+        for name in names {
+            self.sign_name(&name, now)?;
+        }
+        Ok(())
+    }
+
+    //replace the keys as a new version of the zone, all the signatures
+    //change with it
+    pub fn update_keys(
+        &mut self,
+        keys: Vec<ZoneKey>,
+        nsec3: Option<Nsec3Config>,
+        serial: u32,
+    ) -> anyhow::Result<()> {
+        let mut changes = self
+            .names
+            .iter()
+            .map(|name| (name.name().clone(), RRType::RRSIG))
+            .collect::<Vec<_>>();
+        for typ in [
+            RRType::DNSKEY,
+            RRType::CDS,
+            RRType::CDNSKEY,
+            RRType::NSEC3PARAM,
+        ] {
+            changes.push((self.origin.clone(), typ));
+        }
+        self.change_rrsets(&changes, serial, now(), |zone| {
+            zone.install_keys(keys, nsec3)
+        })
+    }
+
+    fn install_keys(
+        &mut self,
+        keys: Vec<ZoneKey>,
+        nsec3: Option<Nsec3Config>,
+    ) -> anyhow::Result<()> {
+        let ttl = self
+            .get_apex_rrset(RRType::SOA)
+            .ok_or_else(|| anyhow::anyhow!("no soa at zone apex"))?
+            .ttl
+            .0;
+        let origin = self.origin.clone();
+        self.keys = keys;
+        self.nsec3 = nsec3.filter(|_| self.is_signed());
+        if self.is_signed() {
+            self.add_rrset(dnskey_rrset(&self.keys, &origin, ttl)?);
+            for rrset in cds_rrsets(&self.keys, &origin, ttl)? {
+                self.add_rrset(rrset);
+            }
+        } else {
+            for typ in [RRType::DNSKEY, RRType::CDS, RRType::CDNSKEY] {
+                if self.get_apex_rrset(typ).is_some() {
+                    self.delete_rrset(&origin, typ)?;
+                }
+            }
+        }
+        match self.nsec3 {
+            Some(ref config) => {
+                let rdata = nsec3param_rdata(&config.salt()?, config.iterations);
+                self.add_rrset(rrset_from_rdatas(
+                    &origin,
+                    RRType::NSEC3PARAM,
+                    ttl,
+                    &[rdata],
+                )?);
+            }
+            None => {
+                if self.get_apex_rrset(RRType::NSEC3PARAM).is_some() {
+                    self.delete_rrset(&origin, RRType::NSEC3PARAM)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //rrsigs at the name covering the rrset, rrset synthesized from wildcard
+    //has the rrsigs of the wildcard with its own owner
+    pub fn get_rrsig(&self, rrset: &RRset) -> Option<RRset> {
+        if let Some(rrsigs) = self.get_rrset(&rrset.name, RRType::RRSIG) {
+            return covering_rrsigs(&rrsigs, rrset);
+        }
+        let wildcard = self.source_of_synthesis(&rrset.name)?;
+        let mut rrsig = self
+            .get_rrset(&wildcard, RRType::RRSIG)
+            .and_then(|rrsigs| covering_rrsigs(&rrsigs, rrset))?;
+        rrsig.name = rrset.name.clone();
+        Some(rrsig)
+    }
+
+    //delegation point at or above the name
+    fn zone_cut(&self, name: &Name) -> Option<Name> {
+        if !name.eq(&self.origin) && self.get_rrset(name, RRType::NS).is_some() {
+            return Some(name.clone());
+        }
+        match self.find(name, RRType::NS, FindMode::DefaultFind) {
+            FindResult::Delegation(ns) => Some(ns.name),
+            _ => None,
+        }
+    }
+
+    //sign the authoritative rrsets owned by the name, only ds and nsec are
+    //signed at delegation point and nothing is signed below it
+    fn sign_name(&mut self, name: &Name, now: u32) -> anyhow::Result<()> {
+        let cut = self.zone_cut(name);
+        let rrsets = self
+            .get_rrsets(name)
+            .into_iter()
+            .filter(|rrset| match cut {
+                None => rrset.typ != RRType::RRSIG,
+                Some(ref cut) => {
+                    cut.eq(name) && (rrset.typ == RRType::DS || rrset.typ == RRType::NSEC)
+                }
+            })
+            .collect::<Vec<_>>();
+        match sign_rrsets(&self.keys, &self.origin, &rrsets, now)? {
+            Some(rrsig) => self.add_rrset(rrsig),
+            None => {
+                if self.get_rrset(name, RRType::RRSIG).is_some() {
+                    self.delete_rrset(name, RRType::RRSIG)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //names whose signatures may change with the rrsets, soa at apex always
+    //changes, and names below a delegation point change with its ns
+    fn signed_names(&self, keys: &[(Name, RRType)]) -> BTreeSet<CanonicalName> {
+        let mut names = BTreeSet::new();
+        names.insert(CanonicalName::new(self.origin.clone()));
+        for (name, typ) in keys {
+            let name = CanonicalName::new(name.clone());
+            if *typ == RRType::NS && !name.name().eq(&self.origin) {
+                names.extend(
+                    self.names
+                        .range(&name..)
+                        .take_while(|below| below.name().is_subdomain(name.name()))
+                        .cloned(),
+                );
+            }
+            names.insert(name);
+        }
+        names
+    }
+
+    //nsec or nsec3 chain over the authoritative names of a signed zone.
+    //delegation points only have ns and ds in the type bit maps, and with
+    //nsec3 opt-out the ones without ds are left out
+    fn build_chain(&self) -> anyhow::Result<DenialChain> {
+        let mut chain = DenialChain::new();
+        if !self.is_signed() {
+            return Ok(chain);
+        }
+        let ttl = self
+            .get_apex_rrset(RRType::SOA)
+            .and_then(|soa| soa_negative_ttl(&soa))
+            .ok_or_else(|| anyhow::anyhow!("invalid soa at zone apex"))?;
+        //names in canonical order with their types, and whether they have
+        //signed rrsets
+        let mut names = Vec::new();
+        for name in self.names.iter() {
+            let mut types = self
+                .get_rrsets(name.name())
+                .iter()
+                .map(|rrset| rrset.typ)
+                .filter(|typ| !matches!(typ, RRType::RRSIG | RRType::NSEC | RRType::NSEC3))
+                .collect::<Vec<_>>();
+            if types.is_empty() {
+                continue;
+            }
+            let secure = match self.zone_cut(name.name()) {
+                None => true,
+                Some(cut) if cut.eq(name.name()) => {
+                    types.retain(|typ| *typ == RRType::NS || *typ == RRType::DS);
+                    types.contains(&RRType::DS)
+                }
+                Some(_) => continue,
+            };
+            let types = types.iter().map(RRType::as_u16).collect::<Vec<_>>();
+            names.push((name.name().clone(), types, secure));
+        }
+
+        let config = match self.nsec3 {
+            Some(ref config) => config,
+            None => {
+                for (i, (name, types, _)) in names.iter().enumerate() {
+                    let next = &names[(i + 1) % names.len()].0;
+                    let mut types = types.clone();
+                    types.extend([RRType::RRSIG.as_u16(), RRType::NSEC.as_u16()]);
+                    chain.insert(
+                        CanonicalName::new(name.clone()),
+                        (name.clone(), RRType::NSEC, ttl, nsec_rdata(next, &types)),
+                    );
+                }
+                return Ok(chain);
+            }
+        };
+        //hashes of the names, empty non-terminals have no types
+        let salt = config.salt()?;
+        let origin_labels = wire_labels(&self.origin.to_string()).len();
+        let mut hashes = BTreeMap::new();
+        for (name, mut types, secure) in names {
+            if config.opt_out && !secure {
+                continue;
+            }
+            let labels = wire_labels(&name.to_string());
+            for i in 1..labels.len() - origin_labels {
+                hashes
+                    .entry(nsec3_hash(
+                        &labels_wire(&labels[i..]),
+                        &salt,
+                        config.iterations,
+                    ))
+                    .or_insert_with(Vec::new);
+            }
+            if secure {
+                types.push(RRType::RRSIG.as_u16());
+            }
+            hashes.insert(
+                nsec3_hash(&name_wire(&name), &salt, config.iterations),
+                types,
+            );
+        }
+        let nexts = hashes.keys().cloned().collect::<Vec<_>>();
+        for (i, (hash, types)) in hashes.into_iter().enumerate() {
+            let next = &nexts[(i + 1) % nexts.len()];
+            let owner = self.hashed_owner(&hash)?;
+            let rdata = nsec3_rdata(&salt, config.iterations, config.opt_out, next, &types);
+            chain.insert(
+                CanonicalName::new(owner.clone()),
+                (owner, RRType::NSEC3, ttl, rdata),
+            );
+        }
+        Ok(chain)
+    }
+
+    //nsec3 owner name is the base32hex hash under the origin
+    fn hashed_owner(&self, hash: &[u8]) -> anyhow::Result<Name> {
+        let mut labels = vec![base32hex(hash).into_bytes()];
+        labels.extend(wire_labels(&self.origin.to_string()));
+        labels_name(&labels)
+    }
+
+    //replace the nsec or nsec3 records which differ from the chain of the
+    //current zone, owners of the changed records are returned to be signed
+    fn update_chain(&mut self, olds: &mut OldRRsets) -> anyhow::Result<Vec<Name>> {
+        let chain = self.build_chain()?;
+        let mut changed = Vec::new();
+        let stale = self
+            .chain
+            .iter()
+            .filter(|(owner, (typ, _, _))| {
+                chain
+                    .get(*owner)
+                    .map_or(true, |(_, new_typ, _, _)| new_typ != typ)
+            })
+            .map(|(owner, (typ, _, _))| (owner.name().clone(), *typ))
+            .collect::<Vec<_>>();
+        for (owner, typ) in stale {
+            self.remember(olds, &owner, typ);
+            if self.get_rrset(&owner, typ).is_some() {
+                self.delete_rrset(&owner, typ)?;
+            }
+            changed.push(owner);
+        }
+        let mut records = BTreeMap::new();
+        for (owner, (name, typ, ttl, rdata)) in chain {
+            let same = self
+                .chain
+                .get(&owner)
+                .map_or(false, |(old_typ, old_ttl, old)| {
+                    *old_typ == typ && *old_ttl == ttl && *old == rdata
+                });
+            if !same {
+                self.remember(olds, &name, typ);
+                self.add_rrset(rrset_from_rdatas(
+                    &name,
+                    typ,
+                    ttl,
+                    std::slice::from_ref(&rdata),
+                )?);
+                changed.push(name);
+            }
+            records.insert(owner, (typ, ttl, rdata));
+        }
+        self.chain = records;
+        Ok(changed)
+    }
+
+    //nsec or nsec3 records with their rrsigs proving the name doesn't
+    //exist, the wildcard at the closest encloser doesn't exist either
+    pub fn nxdomain_proof(&self, name: &Name) -> Vec<RRset> {
+        if self.nsec3_param.is_some() {
+            return self.nsec3_proof(name, true);
+        }
+        let mut wildcard = vec![b"*".to_vec()];
+        wildcard.extend(self.closest_encloser(name));
+        let wildcard = labels_name(&wildcard).ok().map(CanonicalName::new);
+        self.denial_records(vec![
+            self.covering_owner(&CanonicalName::new(name.clone())),
+            wildcard.and_then(|wildcard| self.covering_owner(&wildcard)),
+        ])
+    }
+
+    //records proving the name has no rrset of the query type, nsec of an
+    //empty non-terminal covers the name instead of matching it. for the
+    //name matching a wildcard, the nsec of the wildcard is added
+    pub fn nodata_proof(&self, name: &Name) -> Vec<RRset> {
+        if self.nsec3_param.is_some() {
+            return self.nsec3_proof(name, false);
         }
+        let wildcard = self.source_of_synthesis(name).map(CanonicalName::new);
+        self.denial_records(vec![
+            self.covering_owner(&CanonicalName::new(name.clone())),
+            wildcard.and_then(|wildcard| self.covering_owner(&wildcard)),
+        ])
+    }
+
+    //records proving the name of the answer synthesized from wildcard
+    //doesn't exist(rfc4035 section 3.1.3.3, rfc5155 section 7.2.6), which
+    //the validator needs besides the rrsigs of the wildcard
+    pub fn wildcard_proof(&self, name: &Name) -> Vec<RRset> {
+        let wildcard = match self.source_of_synthesis(name) {
+            Some(wildcard) => wildcard,
+            None => return Vec::new(),
+        };
+        if self.nsec3_param.is_none() {
+            return self
+                .denial_records(vec![self.covering_owner(&CanonicalName::new(name.clone()))]);
+        }
+        //next closer name is one label below the closest encloser
+        let labels = wire_labels(&name.to_string());
+        let encloser = wire_labels(&wildcard.to_string()).len() - 1;
+        self.denial_records(vec![self
+            .nsec3_owner(&labels[labels.len() - encloser - 1..])
+            .and_then(|next| self.covering_owner(&next))])
+    }
+
+    //closest provable encloser proof(rfc5155 section 7.2.1), nsec3
+    //matching the closest ancestor in the chain and the one covering the
+    //next closer name. nsec3 matching the name itself is nodata proof, it's
+    //the ancestor for a delegation left out by opt-out. nsec3 of the
+    //wildcard covers it for nxdomain, and matches it for wildcard nodata
+    fn nsec3_proof(&self, name: &Name, nxdomain: bool) -> Vec<RRset> {
+        let labels = wire_labels(&name.to_string());
+        for i in 0..labels.len() {
+            let owner = match self.nsec3_owner(&labels[i..]) {
+                Some(owner) => owner,
+                None => return Vec::new(),
+            };
+            if !self.denial_names.contains(&owner) {
+                continue;
+            }
+            let mut owners = vec![Some(owner)];
+            if i > 0 {
+                owners.push(
+                    self.nsec3_owner(&labels[i - 1..])
+                        .and_then(|next| self.covering_owner(&next)),
+                );
+            }
+            if nxdomain || self.source_of_synthesis(name).is_some() {
+                let mut wildcard = vec![b"*".to_vec()];
+                wildcard.extend_from_slice(&labels[i..]);
+                owners.push(
+                    self.nsec3_owner(&wildcard)
+                        .and_then(|wildcard| self.covering_owner(&wildcard)),
+                );
+            }
+            return self.denial_records(owners);
+        }
+        Vec::new()
+    }
+
+    fn nsec3_owner(&self, labels: &[Vec<u8>]) -> Option<CanonicalName> {
+        let (salt, iterations) = self.nsec3_param.as_ref()?;
+        let hash = nsec3_hash(&labels_wire(labels), salt, *iterations);
+        self.hashed_owner(&hash).ok().map(CanonicalName::new)
+    }
+
+    //closest existing ancestor of the name, in labels
+    fn closest_encloser(&self, name: &Name) -> Vec<Vec<u8>> {
+        let labels = wire_labels(&name.to_string());
+        for i in 1..labels.len() {
+            if let Ok(ancestor) = labels_name(&labels[i..]) {
+                if self.name_exists(&ancestor) {
+                    return labels[i..].to_vec();
+                }
+            }
+        }
+        wire_labels(&self.origin.to_string())
+    }
+
+    //owner of the denial record matching or covering the name, the last
+    //one covers the names before the first one
+    fn covering_owner(&self, name: &CanonicalName) -> Option<CanonicalName> {
+        self.denial_names
+            .range(..=name)
+            .next_back()
+            .or_else(|| self.denial_names.iter().next_back())
+            .cloned()
+    }
+
+    //records at the owners followed by their rrsigs, each owner only once
+    fn denial_records(&self, owners: Vec<Option<CanonicalName>>) -> Vec<RRset> {
+        let typ = if self.nsec3_param.is_some() {
+            RRType::NSEC3
+        } else {
+            RRType::NSEC
+        };
+        let mut seen = BTreeSet::new();
+        let mut rrsets = Vec::new();
+        for owner in owners.into_iter().flatten() {
+            if !seen.insert(owner.clone()) {
+                continue;
+            }
+            if let Some(rrset) = self.get_rrset(owner.name(), typ) {
+                let rrsig = self.get_rrsig(&rrset);
+                rrsets.push(rrset);
+                rrsets.extend(rrsig);
+            }
+        }
+        rrsets
+    }
+
+    //largest ttl of the rrsets in the zone
+    pub fn max_ttl(&self) -> u32 {
+        self.names
+            .iter()
+            .flat_map(|name| self.get_rrsets(name.name()))
+            .map(|rrset| rrset.ttl.0)
+            .max()
+            .unwrap_or(0)
+    }
+
+    //build zone from complete content, like a zone file, which must have
+    //soa and ns at apex
+    pub fn from_rrsets(name: Name, rrsets: Vec<RRset>) -> anyhow::Result<Self> {
+        let mut zone = MemoryZone::new(name);
+        for rrset in rrsets {
+            if !rrset.name.is_subdomain(&zone.origin) {
+                bail!("{} is out of zone {}", rrset.name, zone.origin);
+            }
+            if rrset.typ == RRType::SOA {
+                if !rrset.name.eq(&zone.origin) {
+                    bail!("soa {} isn't at zone apex", rrset.name);
+                }
+                if rrset.rr_count() != 1 {
+                    bail!("zone should have exactly one soa");
+                }
+            }
+            zone.add_rrset(rrset);
+        }
+
+        if zone.get_apex_rrset(RRType::SOA).is_none() {
+            bail!("no soa at zone apex");
+        }
+        if zone.get_apex_rrset(RRType::NS).is_none() {
+            bail!("no ns at zone apex");
+        }
+        Ok(zone)
     }
 
     pub fn add_rrset(&mut self, rrset: RRset) {
+        match rrset.typ {
+            RRType::NSEC | RRType::NSEC3 => {
+                self.denial_names
+                    .insert(CanonicalName::new(rrset.name.clone()));
+            }
+            RRType::NSEC3PARAM if rrset.name.eq(&self.origin) => {
+                self.nsec3_param = rdata_wires(&rrset)
+                    .ok()
+                    .and_then(|wires| wires.first().and_then(|wire| parse_nsec3param(wire)));
+            }
+            _ => {}
+        }
         let mut tree = self.domains.write().unwrap();
         let mut result = tree.find(&rrset.name);
-        let is_delegation = !rrset.name.eq(&self.origin) && rrset.typ == r53::RRType::NS;
+        let redirects = redirects_below(&rrset, &self.origin);
+        self.names.insert(CanonicalName::new(rrset.name.clone()));
         if result.flag != FindResultFlag::ExacatMatch {
             let node = tree.insert(rrset.name.clone(), Some(vec![rrset])).0;
-            if is_delegation {
+            if redirects {
                 node.set_callback(true);
             }
         } else {
@@ -51,52 +691,195 @@ impl MemoryZone {
             };
             let old = result.node.get_value_mut().get_or_insert(vec![]);
             add_or_replace_rrset(old, rrset);
-            if is_delegation {
+            if redirects {
                 result.node.set_callback(true);
             }
         };
     }
 
-    pub fn find(&self, name: &Name, typ: RRType, find_mode: FindMode) -> FindResult {
+    pub fn get_rrset(&self, name: &Name, typ: RRType) -> Option<RRset> {
         let tree = self.domains.read().unwrap();
-        let mut node_chain = NodeChain::new(&*tree);
-        let mut result = FindResult::NXDomain;
-
-        let mut callback = match find_mode {
-            FindMode::DefaultFind => Some(|n: NodePtr<Vec<RRset>>, _, result: &mut FindResult| {
-                for rrset in n.get_value().as_ref().unwrap().iter() {
-                    if rrset.typ == RRType::NS {
-                        *result = FindResult::Delegation(rrset.clone());
-                        return true;
-                    }
-                }
-                false
-            }),
-            FindMode::GlueOkFind => None,
+        let result = tree.find(name);
+        if result.flag == FindResultFlag::ExacatMatch {
+            if let Some(rrsets) = result.get_value() {
+                return rrsets.iter().find(|rrset| rrset.typ == typ).cloned();
+            }
+        }
+        None
+    }
+
+    //all the rrsets owned by the name
+    pub fn get_rrsets(&self, name: &Name) -> Vec<RRset> {
+        let tree = self.domains.read().unwrap();
+        let result = tree.find(name);
+        if result.flag == FindResultFlag::ExacatMatch {
+            if let Some(rrsets) = result.get_value() {
+                return rrsets.clone();
+            }
+        }
+        Vec::new()
+    }
+
+    pub fn delete_rrset(&mut self, name: &Name, typ: RRType) -> anyhow::Result<()> {
+        if name.eq(&self.origin) && (typ == RRType::SOA || typ == RRType::NS) {
+            return Err(AuthError::ApexRRset.into());
+        }
+        let mut tree = self.domains.write().unwrap();
+        let mut result = tree.find(name);
+        if result.flag != FindResultFlag::ExacatMatch {
+            return Err(AuthError::UnknownRRset.into());
+        }
+        let rrsets = match result.node.get_value_mut() {
+            Some(rrsets) => rrsets,
+            None => return Err(AuthError::UnknownRRset.into()),
         };
-        let find_result = tree.find_node_ext(name, &mut node_chain, &mut callback, &mut result);
-        match result {
-            FindResult::Delegation(_) => {
-                return result;
-            }
-            _ => match find_result.flag {
-                FindResultFlag::ExacatMatch => {
-                    if let Some(rrsets) = find_result.get_value() {
-                        for rrset in rrsets {
-                            if rrset.typ == typ {
-                                return FindResult::Success(rrset.clone());
-                            }
+        let len = rrsets.len();
+        rrsets.retain(|rrset| rrset.typ != typ);
+        if rrsets.len() == len {
+            return Err(AuthError::UnknownRRset.into());
+        }
+        let is_empty = rrsets.is_empty();
+        if typ == RRType::NS || typ == RRType::DNAME {
+            let callback = rrsets
+                .iter()
+                .any(|rrset| redirects_below(rrset, &self.origin));
+            result.node.set_callback(callback);
+        }
+        //node is kept in the tree, it may still be the parent of other names
+        if is_empty {
+            *result.node.get_value_mut() = None;
+            self.names.remove(&CanonicalName::new(name.clone()));
+        }
+        match typ {
+            RRType::NSEC | RRType::NSEC3 => {
+                self.denial_names.remove(&CanonicalName::new(name.clone()));
+            }
+            RRType::NSEC3PARAM if name.eq(&self.origin) => self.nsec3_param = None,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    //remove the rdatas in rrset from the existing one, the rrset is deleted
+    //when no rdata is left
+    pub fn delete_rr(&mut self, rrset: &RRset) -> anyhow::Result<()> {
+        let mut old = self
+            .get_rrset(&rrset.name, rrset.typ)
+            .ok_or(AuthError::UnknownRRset)?;
+        if !rrset.rdatas.iter().all(|rdata| old.rdatas.contains(rdata)) {
+            return Err(AuthError::UnknownRR.into());
+        }
+        old.rdatas.retain(|rdata| !rrset.rdatas.contains(rdata));
+        if old.rdatas.is_empty() {
+            self.delete_rrset(&rrset.name, rrset.typ)
+        } else {
+            self.add_rrset(old);
+            Ok(())
+        }
+    }
+
+    //name without rrsets but with names below it is an empty non-terminal
+    fn has_descendant(&self, name: &Name) -> bool {
+        self.names
+            .range((Excluded(CanonicalName::new(name.clone())), Unbounded))
+            .next()
+            .map_or(false, |next| next.name().is_subdomain(name))
+    }
+
+    pub fn find(&self, name: &Name, typ: RRType, find_mode: FindMode) -> FindResult {
+        //glue below a delegation is never synthesized from wildcard
+        let synthesize = matches!(find_mode, FindMode::DefaultFind);
+        {
+            let tree = self.domains.read().unwrap();
+            let mut node_chain = NodeChain::new(&*tree);
+            let mut result = FindResult::NXDomain;
+
+            //delegation takes over the names below it, and so does dname
+            //except for its owner. ns at apex isn't a delegation
+            let mut callback = match find_mode {
+                FindMode::DefaultFind => {
+                    Some(|n: NodePtr<Vec<RRset>>, _, result: &mut FindResult| {
+                        let rrsets = n.get_value().as_ref().unwrap();
+                        if let Some(ns) = rrsets
+                            .iter()
+                            .find(|rrset| rrset.typ == RRType::NS && !rrset.name.eq(&self.origin))
+                        {
+                            *result = FindResult::Delegation(ns.clone());
+                            return true;
+                        }
+                        if let Some(dname) = rrsets
+                            .iter()
+                            .find(|rrset| rrset.typ == RRType::DNAME && !rrset.name.eq(name))
+                        {
+                            *result = FindResult::DName(dname.clone(), None);
+                            return true;
                         }
+                        false
+                    })
+                }
+                FindMode::GlueOkFind => None,
+            };
+            let find_result = tree.find_node_ext(name, &mut node_chain, &mut callback, &mut result);
+            match result {
+                FindResult::Delegation(_) => return result,
+                FindResult::DName(dname, _) => {
+                    let cname = dname_cname(&dname, name).ok();
+                    return FindResult::DName(dname, cname);
+                }
+                _ => {}
+            }
+            if find_result.flag == FindResultFlag::ExacatMatch {
+                if let Some(rrsets) = find_result.get_value() {
+                    if let Some(rrset) = find_rrset(rrsets, typ) {
+                        return rrset;
+                    }
+                    if !rrsets.is_empty() {
+                        return FindResult::NXRRset;
                     }
-                    return FindResult::NXRRset;
                 }
-                _ => {
-                    return FindResult::NXDomain;
+            }
+        }
+        //empty non-terminal exists, wildcard isn't expanded for it
+        if self.has_descendant(name) {
+            return FindResult::NXRRset;
+        }
+        match self.source_of_synthesis(name).filter(|_| synthesize) {
+            Some(wildcard) => match find_rrset(&self.get_rrsets(&wildcard), typ) {
+                Some(FindResult::Success(mut rrset)) => {
+                    rrset.name = name.clone();
+                    FindResult::Success(rrset)
                 }
+                Some(FindResult::CName(mut cname)) => {
+                    cname.name = name.clone();
+                    FindResult::CName(cname)
+                }
+                _ => FindResult::NXRRset,
             },
+            None => FindResult::NXDomain,
+        }
+    }
+
+    //wildcard at the closest encloser of the name which doesn't exist, the
+    //rrsets of the name are synthesized from it(rfc4592 section 3.3)
+    fn source_of_synthesis(&self, name: &Name) -> Option<Name> {
+        if !name.is_subdomain(&self.origin) || self.name_exists(name) {
+            return None;
+        }
+        let mut wildcard = vec![b"*".to_vec()];
+        wildcard.extend(self.closest_encloser(name));
+        let wildcard = labels_name(&wildcard).ok()?;
+        if self.name_exists(&wildcard) {
+            Some(wildcard)
+        } else {
+            None
         }
     }
 
+    //name with rrsets or an empty non-terminal
+    fn name_exists(&self, name: &Name) -> bool {
+        self.names.contains(&CanonicalName::new(name.clone())) || self.has_descendant(name)
+    }
+
     pub fn get_apex_rrset(&self, typ: RRType) -> Option<RRset> {
         let tree = self.domains.read().unwrap();
         let result = tree.find(&self.origin);
@@ -111,6 +894,241 @@ impl MemoryZone {
         return None;
     }
 
+    pub fn serial(&self) -> Option<u32> {
+        self.get_apex_rrset(RRType::SOA)
+            .and_then(|soa| soa_serial(&soa))
+    }
+
+    pub fn set_serial(&mut self, serial: u32) -> anyhow::Result<()> {
+        let soa = self
+            .get_apex_rrset(RRType::SOA)
+            .ok_or_else(|| anyhow::anyhow!("no soa at zone apex"))?;
+        self.add_rrset(set_soa_serial(&soa, serial)?);
+        Ok(())
+    }
+
+    //change the rrset of the name and type and move the zone to the new serial,
+    //the difference is recorded for ixfr
+    pub fn update<F>(&mut self, name: &Name, typ: RRType, serial: u32, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Self) -> anyhow::Result<()>,
+    {
+        self.update_rrsets(&[(name.clone(), typ)], serial, f)
+    }
+
+    //change several rrsets as one version of the zone, the signatures of a
+    //signed zone are maintained along with the rrsets
+    pub fn update_rrsets<F>(
+        &mut self,
+        keys: &[(Name, RRType)],
+        serial: u32,
+        f: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Self) -> anyhow::Result<()>,
+    {
+        if let Some((name, typ)) = keys
+            .iter()
+            .find(|(_, typ)| {
+                matches!(
+                    typ,
+                    RRType::RRSIG
+                        | RRType::DNSKEY
+                        | RRType::CDS
+                        | RRType::CDNSKEY
+                        | RRType::NSEC
+                        | RRType::NSEC3
+                        | RRType::NSEC3PARAM
+                )
+            })
+            .filter(|_| self.is_signed())
+        {
+            bail!("{} {} is managed by dnssec signing", name, typ);
+        }
+        self.change_rrsets(keys, serial, now(), f)
+    }
+
+    //names of a signed zone whose signatures expire within the refresh
+    //window at now
+    fn expiring_names(&self, now: u32) -> Vec<Name> {
+        if !self.is_signed() {
+            return Vec::new();
+        }
+        let deadline = now.saturating_add(SIGNATURE_REFRESH);
+        self.names
+            .iter()
+            .filter(|name| {
+                self.get_rrset(name.name(), RRType::RRSIG)
+                    .and_then(|rrsigs| rrsig_expiration(&rrsigs))
+                    .map_or(false, |expiration| expiration <= deadline)
+            })
+            .map(|name| name.name().clone())
+            .collect()
+    }
+
+    #[inline]
+    pub fn signatures_expiring(&self, now: u32) -> bool {
+        !self.expiring_names(now).is_empty()
+    }
+
+    //sign the names whose signatures are about to expire again as a new
+    //version of the zone
+    pub fn refresh_signatures(&mut self, now: u32, serial: u32) -> anyhow::Result<()> {
+        let changes = self
+            .expiring_names(now)
+            .into_iter()
+            .map(|name| (name, RRType::RRSIG))
+            .collect::<Vec<_>>();
+        self.change_rrsets(&changes, serial, now, |_| Ok(()))
+    }
+
+    fn change_rrsets<F>(
+        &mut self,
+        keys: &[(Name, RRType)],
+        serial: u32,
+        now: u32,
+        f: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Self) -> anyhow::Result<()>,
+    {
+        let from_soa = self
+            .get_apex_rrset(RRType::SOA)
+            .ok_or_else(|| anyhow::anyhow!("no soa at zone apex"))?;
+        //rrsigs and denial records changed by signing are recorded like the
+        //rrsets
+        let signed_names = self.signed_names(keys);
+        let mut olds = OldRRsets::new();
+        for (name, typ) in keys {
+            self.remember(&mut olds, name, *typ);
+        }
+        for name in signed_names.iter() {
+            self.remember(&mut olds, name.name(), RRType::RRSIG);
+        }
+        let was_signed = self.is_signed();
+        let (keys, nsec3, chain) = (self.keys.clone(), self.nsec3.clone(), self.chain.clone());
+        let result = f(self).and_then(|_| {
+            self.set_serial(serial)?;
+            if was_signed || self.is_signed() {
+                let mut names = signed_names;
+                for owner in self.update_chain(&mut olds)? {
+                    self.remember(&mut olds, &owner, RRType::RRSIG);
+                    names.insert(CanonicalName::new(owner));
+                }
+                for name in names {
+                    self.sign_name(name.name(), now)?;
+                }
+            }
+            Ok(())
+        });
+        //failed change leaves the zone as it was, the rrsets it may have
+        //touched are all remembered
+        if let Err(e) = result {
+            for (name, typ, old) in olds.into_values() {
+                match old {
+                    Some(rrset) => self.add_rrset(rrset),
+                    None if self.get_rrset(&name, typ).is_some() => {
+                        let _ = self.delete_rrset(&name, typ);
+                    }
+                    None => {}
+                }
+            }
+            self.add_rrset(from_soa);
+            self.keys = keys;
+            self.nsec3 = nsec3;
+            self.chain = chain;
+            return Err(e);
+        }
+        let to_soa = self.get_apex_rrset(RRType::SOA).unwrap();
+
+        let (mut deleted, mut added) = (Vec::new(), Vec::new());
+        //soa change is carried by the soas of the diff itself
+        for (name, typ, old) in olds
+            .into_values()
+            .filter(|(name, typ, _)| !(*typ == RRType::SOA && name.eq(&self.origin)))
+        {
+            let (d, a) = rrset_diff(old, self.get_rrset(&name, typ));
+            deleted.extend(d);
+            added.extend(a);
+        }
+        self.push_diffs(vec![ZoneDiff {
+            from_soa,
+            deleted,
+            to_soa,
+            added,
+        }]);
+        Ok(())
+    }
+
+    //keep the rrset before it's changed, the first one kept is the old one
+    fn remember(&self, olds: &mut OldRRsets, name: &Name, typ: RRType) {
+        olds.entry((CanonicalName::new(name.clone()), typ.as_u16()))
+            .or_insert_with(|| (name.clone(), typ, self.get_rrset(name, typ)));
+    }
+
+    pub fn diffs(&self) -> Vec<ZoneDiff> {
+        self.diffs.iter().cloned().collect()
+    }
+
+    //record changes made to the zone, the oldest ones are dropped when
+    //there are too many
+    pub fn push_diffs(&mut self, diffs: Vec<ZoneDiff>) {
+        self.diffs.extend(diffs);
+        while self.diffs.len() > MAX_ZONE_DIFFS {
+            self.diffs.pop_front();
+        }
+    }
+
+    //difference sequences from serial to the current version, None if the
+    //serial is too old or unknown
+    pub fn ixfr_diffs(&self, serial: u32) -> Option<Vec<RRset>> {
+        let start = self
+            .diffs
+            .iter()
+            .position(|diff| soa_serial(&diff.from_soa) == Some(serial))?;
+        let mut rrsets = Vec::new();
+        for diff in self.diffs.iter().skip(start) {
+            rrsets.push(diff.from_soa.clone());
+            rrsets.extend(diff.deleted.iter().cloned());
+            rrsets.push(diff.to_soa.clone());
+            rrsets.extend(diff.added.iter().cloned());
+        }
+        Some(rrsets)
+    }
+
+    //all the rrsets in canonical order of owner names, soa is the first one
+    pub fn rrsets(&self) -> Vec<RRset> {
+        let tree = self.domains.read().unwrap();
+        let mut all = Vec::new();
+        for name in self.names.iter() {
+            let result = tree.find(name.name());
+            if result.flag != FindResultFlag::ExacatMatch {
+                continue;
+            }
+            if let Some(rrsets) = result.get_value() {
+                let mut rrsets = rrsets.clone();
+                rrsets.sort_by_key(|rrset| {
+                    if rrset.typ == RRType::SOA {
+                        0
+                    } else {
+                        rrset.typ.as_u16() as u32 + 1
+                    }
+                });
+                all.extend(rrsets);
+            }
+        }
+        all
+    }
+
+    //dump zone content in master file format with absolute names
+    pub fn to_master_file(&self) -> String {
+        let mut content = format!("$ORIGIN {}\n", self.origin);
+        for rrset in self.rrsets() {
+            content.push_str(&rrset.to_string());
+        }
+        content
+    }
+
     pub fn get_glue_for_ns(&self, ns: &RRset) -> Option<Vec<RRset>> {
         let mut glues = Vec::with_capacity(ns.rr_count());
         for rdata in &ns.rdatas {
@@ -133,6 +1151,59 @@ impl MemoryZone {
     }
 }
 
+//rrs deleted and added when old is replaced by new, a ttl change
+//replaces all the rrs
+fn rrset_diff(old: Option<RRset>, new: Option<RRset>) -> (Vec<RRset>, Vec<RRset>) {
+    match (old, new) {
+        (Some(old), Some(new)) if old.ttl == new.ttl => {
+            let mut deleted = old.clone();
+            deleted.rdatas.retain(|rdata| !new.rdatas.contains(rdata));
+            let mut added = new.clone();
+            added.rdatas.retain(|rdata| !old.rdatas.contains(rdata));
+            (
+                Some(deleted)
+                    .filter(|rrset| !rrset.rdatas.is_empty())
+                    .into_iter()
+                    .collect(),
+                Some(added)
+                    .filter(|rrset| !rrset.rdatas.is_empty())
+                    .into_iter()
+                    .collect(),
+            )
+        }
+        (old, new) => (old.into_iter().collect(), new.into_iter().collect()),
+    }
+}
+
+//rrset of the type, or the cname standing for all the other types
+fn find_rrset(rrsets: &[RRset], typ: RRType) -> Option<FindResult> {
+    if let Some(rrset) = rrsets.iter().find(|rrset| rrset.typ == typ) {
+        return Some(FindResult::Success(rrset.clone()));
+    }
+    rrsets
+        .iter()
+        .find(|rrset| rrset.typ == RRType::CNAME)
+        .map(|cname| FindResult::CName(cname.clone()))
+}
+
+//delegation or dname, whose node makes the lookup of the names below it stop
+fn redirects_below(rrset: &RRset, origin: &Name) -> bool {
+    (rrset.typ == RRType::NS && !rrset.name.eq(origin)) || rrset.typ == RRType::DNAME
+}
+
+//cname from the name to the one with the dname owner replaced by the
+//target(rfc6672 section 2.2), which can't be longer than 255 octets
+fn dname_cname(dname: &RRset, name: &Name) -> anyhow::Result<RRset> {
+    let mut labels = wire_labels(&name.to_string());
+    labels.truncate(labels.len() - wire_labels(&dname.name.to_string()).len());
+    labels.extend(wire_labels(&rdata_target(dname)?.to_string()));
+    let target = labels_wire(&labels);
+    if target.len() > 255 {
+        bail!("{} is too long after dname substitution", name);
+    }
+    rrset_from_rdatas(name, RRType::CNAME, dname.ttl.0, &[target])
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -156,4 +1227,231 @@ mod test {
         let result = zone.find(&a_b_com, RRType::A, FindMode::DefaultFind);
         assert!(matches!(result, FindResult::Delegation(rrset) if rrset.eq(&b_com_ns)));
     }
+
+    #[test]
+    fn test_zone_find_wildcard() {
+        let mut zone = MemoryZone::new(Name::from_str("example.com").unwrap());
+        zone.add_rrset(RRset::from_str("example.com. 3600 IN SOA ns1.example.com. admin.example.com. 1 3600 900 604800 300").unwrap());
+        let wildcard = RRset::from_str("*.example.com. 300 IN TXT \"wild\"").unwrap();
+        zone.add_rrset(wildcard.clone());
+        zone.add_rrset(RRset::from_str("host.example.com. 300 IN A 1.1.1.1").unwrap());
+        zone.add_rrset(RRset::from_str("a.b.example.com. 300 IN A 2.2.2.2").unwrap());
+        zone.add_rrset(RRset::from_str("sub.example.com. 300 IN NS ns1.sub.example.com.").unwrap());
+        zone.add_rrset(RRset::from_str("ns1.sub.example.com. 300 IN A 3.3.3.3").unwrap());
+        let find = |name: &str, typ: RRType| {
+            zone.find(&Name::from_str(name).unwrap(), typ, FindMode::DefaultFind)
+        };
+
+        //owner of the synthesized rrset is the query name
+        for name in ["foo.example.com", "deep.foo.example.com", "*.example.com"] {
+            let name = Name::from_str(name).unwrap();
+            assert!(matches!(
+                zone.find(&name, RRType::TXT, FindMode::DefaultFind),
+                FindResult::Success(rrset) if rrset.name.eq(&name) && rrset.rdatas == wildcard.rdatas
+            ));
+        }
+        assert!(matches!(
+            find("foo.example.com", RRType::A),
+            FindResult::NXRRset
+        ));
+
+        //existing names and empty non-terminals aren't expanded, neither are
+        //the names below them without a wildcard of their own
+        assert!(matches!(
+            find("host.example.com", RRType::TXT),
+            FindResult::NXRRset
+        ));
+        assert!(matches!(
+            find("b.example.com", RRType::TXT),
+            FindResult::NXRRset
+        ));
+        assert!(matches!(
+            find("x.host.example.com", RRType::TXT),
+            FindResult::NXDomain
+        ));
+        assert!(matches!(
+            find("x.b.example.com", RRType::TXT),
+            FindResult::NXDomain
+        ));
+
+        //names below a delegation and glue lookups aren't synthesized
+        assert!(matches!(
+            find("www.sub.example.com", RRType::TXT),
+            FindResult::Delegation(_)
+        ));
+        let foo = Name::from_str("foo.example.com").unwrap();
+        assert!(matches!(
+            zone.find(&foo, RRType::TXT, FindMode::GlueOkFind),
+            FindResult::NXDomain
+        ));
+
+        zone.delete_rrset(&wildcard.name, RRType::TXT).unwrap();
+        assert!(matches!(
+            zone.find(&foo, RRType::TXT, FindMode::DefaultFind),
+            FindResult::NXDomain
+        ));
+    }
+
+    #[test]
+    fn test_zone_find_cname() {
+        let mut zone = MemoryZone::new(Name::from_str("example.com").unwrap());
+        zone.add_rrset(RRset::from_str("example.com. 3600 IN SOA ns1.example.com. admin.example.com. 1 3600 900 604800 300").unwrap());
+        let cname = RRset::from_str("www.example.com. 300 IN CNAME web.example.com.").unwrap();
+        zone.add_rrset(cname.clone());
+        let dname = RRset::from_str("old.example.com. 300 IN DNAME example.org.").unwrap();
+        zone.add_rrset(dname.clone());
+        let www = Name::from_str("www.example.com").unwrap();
+        let old = Name::from_str("old.example.com").unwrap();
+
+        assert!(matches!(
+            zone.find(&www, RRType::A, FindMode::DefaultFind),
+            FindResult::CName(rrset) if rrset.eq(&cname)
+        ));
+        assert!(matches!(
+            zone.find(&www, RRType::CNAME, FindMode::DefaultFind),
+            FindResult::Success(rrset) if rrset.eq(&cname)
+        ));
+
+        //dname redirects the names below its owner but not the owner
+        let host = Name::from_str("a.host.old.example.com").unwrap();
+        match zone.find(&host, RRType::A, FindMode::DefaultFind) {
+            FindResult::DName(rrset, Some(cname)) => {
+                assert!(rrset.eq(&dname));
+                assert!(cname.name.eq(&host));
+                assert_eq!(cname.typ, RRType::CNAME);
+                assert_eq!(
+                    rdata_target(&cname).unwrap(),
+                    Name::from_str("a.host.example.org").unwrap()
+                );
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(matches!(
+            zone.find(&old, RRType::A, FindMode::DefaultFind),
+            FindResult::NXRRset
+        ));
+
+        //names below the dname are found again once it's gone
+        zone.delete_rrset(&old, RRType::DNAME).unwrap();
+        assert!(matches!(
+            zone.find(&host, RRType::A, FindMode::DefaultFind),
+            FindResult::NXDomain
+        ));
+    }
+
+    #[test]
+    fn test_zone_failed_change() {
+        let mut zone = MemoryZone::new(Name::from_str("com").unwrap());
+        zone.add_rrset(RRset::from_str("com. 900 IN SOA a.gtld-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400").unwrap());
+        let a_com = RRset::from_str("a.com. 900 IN A 1.1.1.1").unwrap();
+        zone.add_rrset(a_com.clone());
+        let b_com = Name::from_str("b.com").unwrap();
+
+        //rrsets changed before the failure are put back
+        let keys = [(a_com.name.clone(), RRType::A), (b_com.clone(), RRType::A)];
+        assert!(zone
+            .update_rrsets(&keys, 2, |zone| {
+                zone.delete_rrset(&a_com.name, RRType::A)?;
+                zone.add_rrset(RRset::from_str("b.com. 900 IN A 2.2.2.2").unwrap());
+                zone.delete_rrset(&b_com, RRType::AAAA)
+            })
+            .is_err());
+        assert_eq!(zone.get_rrset(&a_com.name, RRType::A), Some(a_com));
+        assert!(zone.get_rrset(&b_com, RRType::A).is_none());
+        assert_eq!(zone.serial(), Some(1));
+        assert!(zone.diffs().is_empty());
+    }
+
+    #[test]
+    fn test_zone_delete() {
+        let mut zone = MemoryZone::new(Name::from_str("com").unwrap());
+        zone.add_rrset(RRset::from_str("com. 900 IN SOA a.gtld-servers.net. nstld.verisign-grs.com. 1653038001 1800 900 604800 86400").unwrap());
+        zone.add_rrset(RRset::from_str("com. 900 IN NS a.gtld-servers.net.").unwrap());
+        zone.add_rrset(
+            RRset::from_strs(&["a.b.com. 900 IN A 1.1.1.1", "a.b.com. 900 IN A 2.2.2.2"]).unwrap(),
+        );
+        zone.add_rrset(RRset::from_str("c.com. 900 IN NS ns1.c.com.").unwrap());
+
+        let com = Name::from_str("com").unwrap();
+        let a_b_com = Name::from_str("a.b.com").unwrap();
+        let b_com = Name::from_str("b.com").unwrap();
+        let c_com = Name::from_str("c.com").unwrap();
+        assert!(zone.delete_rrset(&com, RRType::SOA).is_err());
+        assert!(zone.delete_rrset(&a_b_com, RRType::AAAA).is_err());
+
+        zone.delete_rr(&RRset::from_str("a.b.com. 900 IN A 1.1.1.1").unwrap())
+            .unwrap();
+        assert_eq!(zone.get_rrset(&a_b_com, RRType::A).unwrap().rr_count(), 1);
+        assert!(zone
+            .delete_rr(&RRset::from_str("a.b.com. 900 IN A 1.1.1.1").unwrap())
+            .is_err());
+        assert!(matches!(
+            zone.find(&b_com, RRType::A, FindMode::DefaultFind),
+            FindResult::NXRRset
+        ));
+
+        zone.delete_rr(&RRset::from_str("a.b.com. 900 IN A 2.2.2.2").unwrap())
+            .unwrap();
+        for name in [&a_b_com, &b_com] {
+            assert!(matches!(
+                zone.find(name, RRType::A, FindMode::DefaultFind),
+                FindResult::NXDomain
+            ));
+        }
+
+        zone.delete_rrset(&c_com, RRType::NS).unwrap();
+        let www_c_com = Name::from_str("www.c.com").unwrap();
+        assert!(matches!(
+            zone.find(&www_c_com, RRType::A, FindMode::DefaultFind),
+            FindResult::NXDomain
+        ));
+        assert_eq!(zone.rrsets().len(), 2);
+    }
+
+    #[test]
+    fn test_zone_ixfr() {
+        let mut zone = MemoryZone::new(Name::from_str("com").unwrap());
+        zone.add_rrset(RRset::from_str("com. 900 IN SOA a.gtld-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400").unwrap());
+        zone.add_rrset(RRset::from_str("com. 900 IN NS a.gtld-servers.net.").unwrap());
+        let a_com = Name::from_str("a.com").unwrap();
+
+        let a = RRset::from_strs(&["a.com. 900 IN A 1.1.1.1", "a.com. 900 IN A 2.2.2.2"]).unwrap();
+        zone.update(&a_com, RRType::A, 2, |zone| {
+            zone.add_rrset(a);
+            Ok(())
+        })
+        .unwrap();
+        let rr = RRset::from_str("a.com. 900 IN A 1.1.1.1").unwrap();
+        zone.update(&a_com, RRType::A, 3, |zone| zone.delete_rr(&rr))
+            .unwrap();
+        //failed change isn't recorded
+        assert!(zone
+            .update(&a_com, RRType::A, 4, |zone| zone.delete_rr(&rr))
+            .is_err());
+        assert_eq!(zone.serial(), Some(3));
+
+        let to_strings = |rrsets: Vec<RRset>| {
+            rrsets
+                .iter()
+                .map(|rrset| rrset.to_string())
+                .collect::<Vec<String>>()
+        };
+        let soa = |serial: u32| {
+            format!("com.\t900\tIN\tSOA\ta.gtld-servers.net. nstld.verisign-grs.com. {} 1800 900 604800 86400\n", serial)
+        };
+        assert_eq!(
+            to_strings(zone.ixfr_diffs(1).unwrap()),
+            vec![
+                soa(1),
+                soa(2),
+                "a.com.\t900\tIN\tA\t1.1.1.1\na.com.\t900\tIN\tA\t2.2.2.2\n".to_string(),
+                soa(2),
+                "a.com.\t900\tIN\tA\t1.1.1.1\n".to_string(),
+                soa(3),
+            ]
+        );
+        assert_eq!(zone.ixfr_diffs(2).unwrap().len(), 3);
+        assert!(zone.ixfr_diffs(0).is_none());
+        assert!(zone.ixfr_diffs(3).is_none());
+    }
 }
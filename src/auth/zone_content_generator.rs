@@ -1,30 +1,208 @@
+use anyhow::{self, bail};
 use r53::{Name, RRset};
-use std::fmt::Display;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
 use std::str::FromStr;
 
-pub fn default_soa(name: &Name) -> RRset {
-    let soa = format!(
-        "{} 3600 IN SOA hd.fuxi. root.fuxi. 1 600 300 2419200 600",
-        name
-    );
-    RRset::from_str(soa.as_ref()).unwrap()
+use super::dnssec::Nsec3Config;
+
+//soa fields of a new zone, missing ones are taken from the template
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SoaConfig {
+    pub mname: Option<String>,
+    pub rname: Option<String>,
+    pub serial: Option<u32>,
+    pub refresh: Option<u32>,
+    pub retry: Option<u32>,
+    pub expire: Option<u32>,
+    pub minimum: Option<u32>,
+}
+
+//name server of a zone, in zone name server needs glue addresses and out of
+//zone one must not have any
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NameServer {
+    pub name: String,
+    #[serde(default)]
+    pub ips: Vec<String>,
+}
+
+//what's given when a zone is created, ips are the glue of the default
+//name server ns.<zone>
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ZoneConfig {
+    #[serde(default)]
+    pub ips: Vec<String>,
+    #[serde(default)]
+    pub ttl: Option<u32>,
+    #[serde(default)]
+    pub soa: SoaConfig,
+    #[serde(default)]
+    pub nameservers: Vec<NameServer>,
+    //address prefixes allowed to transfer the zone
+    #[serde(default)]
+    pub allow_transfer: Vec<String>,
+    //secondaries notified when the zone changes, address with optional port
+    #[serde(default)]
+    pub also_notify: Vec<String>,
+    //address prefixes allowed to send dynamic update
+    #[serde(default)]
+    pub allow_update: Vec<String>,
+    //algorithm to sign the zone with, ecdsap256sha256 or ed25519
+    #[serde(default)]
+    pub dnssec: Option<String>,
+    //nsec3 parameters of the signed zone, nsec is used without them
+    #[serde(default)]
+    pub nsec3: Option<Nsec3Config>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SoaTemplate {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl Default for SoaTemplate {
+    fn default() -> Self {
+        Self {
+            mname: "hd.fuxi.".to_string(),
+            rname: "root.fuxi.".to_string(),
+            serial: 1,
+            refresh: 600,
+            retry: 300,
+            expire: 2419200,
+            minimum: 600,
+        }
+    }
+}
+
+//server wide defaults of new zones, name servers in template are normally
+//out of zone ones shared by all the zones
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ZoneTemplate {
+    pub ttl: u32,
+    pub soa: SoaTemplate,
+    pub nameservers: Vec<NameServer>,
 }
 
-pub fn default_ns_and_glue<T: AsRef<str> + Display>(
-    name: &Name,
-    ip_addrs: &Vec<T>,
-) -> (RRset, RRset) {
-    let ns = format!("{} 3600 IN NS ns.{}", name, name);
+impl Default for ZoneTemplate {
+    fn default() -> Self {
+        Self {
+            ttl: 3600,
+            soa: SoaTemplate::default(),
+            nameservers: Vec::new(),
+        }
+    }
+}
+
+impl ZoneTemplate {
+    //template in json, fields not in the file keep the default value
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    //soa, ns and glue rrsets of a new zone
+    pub fn zone_rrsets(&self, name: &Name, config: &ZoneConfig) -> anyhow::Result<Vec<RRset>> {
+        let ttl = config.ttl.unwrap_or(self.ttl);
+        let mut rrsets = vec![self.soa(name, ttl, &config.soa)?];
+
+        let default_ns;
+        let nameservers = if !config.nameservers.is_empty() {
+            &config.nameservers
+        } else if config.ips.is_empty() && !self.nameservers.is_empty() {
+            &self.nameservers
+        } else {
+            default_ns = vec![NameServer {
+                name: format!("ns.{}", name),
+                ips: config.ips.clone(),
+            }];
+            &default_ns
+        };
 
-    let mut glue = Vec::with_capacity(ip_addrs.len());
-    for ip in ip_addrs {
-        glue.push(format!("ns.{} 3600 IN A {}", name, ip));
+        let mut ns = Vec::with_capacity(nameservers.len());
+        let mut glues = Vec::new();
+        for nameserver in nameservers {
+            let ns_name = Name::new(&nameserver.name)?;
+            if ns.iter().any(|n: &Name| n.eq(&ns_name)) {
+                bail!("duplicate name server {}", ns_name);
+            }
+            if ns_name.is_subdomain(name) {
+                if nameserver.ips.is_empty() {
+                    bail!("name server {} in zone needs glue", ns_name);
+                }
+                glues.extend(glue_rrsets(&ns_name, ttl, &nameserver.ips)?);
+            } else if !nameserver.ips.is_empty() {
+                bail!("name server {} out of zone shouldn't have glue", ns_name);
+            }
+            ns.push(ns_name);
+        }
+
+        let ns = ns
+            .iter()
+            .map(|ns_name| format!("{} {} IN NS {}", name, ttl, ns_name))
+            .collect::<Vec<String>>();
+        rrsets.push(RRset::from_strs(&ns)?);
+        rrsets.extend(glues);
+        Ok(rrsets)
     }
 
-    (
-        RRset::from_str(ns.as_ref()).unwrap(),
-        RRset::from_strs(&glue).unwrap(),
-    )
+    fn soa(&self, name: &Name, ttl: u32, config: &SoaConfig) -> anyhow::Result<RRset> {
+        let mname = Name::new(config.mname.as_ref().unwrap_or(&self.soa.mname))?;
+        let rname = Name::new(config.rname.as_ref().unwrap_or(&self.soa.rname))?;
+        let refresh = config.refresh.unwrap_or(self.soa.refresh);
+        let retry = config.retry.unwrap_or(self.soa.retry);
+        let expire = config.expire.unwrap_or(self.soa.expire);
+        if refresh == 0 || retry == 0 || expire == 0 {
+            bail!("soa refresh, retry and expire should be positive");
+        }
+        if expire < refresh.saturating_add(retry) {
+            bail!("soa expire should be larger than refresh plus retry");
+        }
+        let soa = format!(
+            "{} {} IN SOA {} {} {} {} {} {} {}",
+            name,
+            ttl,
+            mname,
+            rname,
+            config.serial.unwrap_or(self.soa.serial),
+            refresh,
+            retry,
+            expire,
+            config.minimum.unwrap_or(self.soa.minimum),
+        );
+        RRset::from_str(soa.as_ref())
+    }
+}
+
+//a and aaaa rrsets for the addresses
+fn glue_rrsets(name: &Name, ttl: u32, ips: &[String]) -> anyhow::Result<Vec<RRset>> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for ip in ips {
+        match ip.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => v4.push(format!("{} {} IN A {}", name, ttl, ip)),
+            Ok(IpAddr::V6(ip)) => v6.push(format!("{} {} IN AAAA {}", name, ttl, ip)),
+            Err(_) => bail!("invalid glue address {} of {}", ip, name),
+        }
+    }
+
+    let mut rrsets = Vec::with_capacity(2);
+    for rrs in [v4, v6] {
+        if !rrs.is_empty() {
+            rrsets.push(RRset::from_strs(&rrs)?);
+        }
+    }
+    Ok(rrsets)
 }
 
 #[cfg(test)]
@@ -32,20 +210,80 @@ mod test {
     use super::*;
     use r53::Name;
 
+    fn to_strings(rrsets: &[RRset]) -> Vec<String> {
+        rrsets.iter().map(|rrset| rrset.to_string()).collect()
+    }
+
     #[test]
-    fn test_default_soa() {
+    fn test_default_template() {
         let name = Name::new("com").unwrap();
-        let soa = default_soa(&name);
+        let config = ZoneConfig {
+            ips: vec!["1.1.1.1".to_string(), "2.2.2.2".to_string()],
+            ..Default::default()
+        };
+        let rrsets = ZoneTemplate::default().zone_rrsets(&name, &config).unwrap();
         assert_eq!(
-            soa.to_string(),
-            "com.	3600	IN	SOA	hd.fuxi. root.fuxi. 1 600 300 2419200 600\n"
+            to_strings(&rrsets),
+            vec![
+                "com.	3600	IN	SOA	hd.fuxi. root.fuxi. 1 600 300 2419200 600\n",
+                "com.	3600	IN	NS	ns.com.\n",
+                "ns.com.	3600	IN	A	1.1.1.1\nns.com.	3600	IN	A	2.2.2.2\n",
+            ]
         );
-        let (ns, glue) = default_ns_and_glue(&name, &vec!["1.1.1.1", "2.2.2.2"]);
-        assert_eq!(ns.to_string(), "com.	3600	IN	NS	ns.com.\n");
-        assert_eq!(glue.rr_count(), 2);
+
+        //in zone name server without glue
+        assert!(ZoneTemplate::default()
+            .zone_rrsets(&name, &ZoneConfig::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_zone_config() {
+        let name = Name::new("example.com").unwrap();
+        let template = ZoneTemplate {
+            nameservers: vec![NameServer {
+                name: "ns1.provider.net".to_string(),
+                ips: Vec::new(),
+            }],
+            ..Default::default()
+        };
+        let rrsets = template.zone_rrsets(&name, &ZoneConfig::default()).unwrap();
         assert_eq!(
-            glue.to_string(),
-            "ns.com.	3600	IN	A	1.1.1.1\nns.com.	3600	IN	A	2.2.2.2\n"
+            rrsets[1].to_string(),
+            "example.com.	3600	IN	NS	ns1.provider.net.\n"
         );
+
+        let config: ZoneConfig = serde_json::from_str(
+            r#"{
+                "ttl": 300,
+                "soa": {"mname": "ns1.example.com", "rname": "admin.example.com", "serial": 2022060101},
+                "nameservers": [
+                    {"name": "ns1.example.com", "ips": ["1.1.1.1", "2001:db8::1"]},
+                    {"name": "ns2.provider.net"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let rrsets = template.zone_rrsets(&name, &config).unwrap();
+        assert_eq!(
+            to_strings(&rrsets),
+            vec![
+                "example.com.	300	IN	SOA	ns1.example.com. admin.example.com. 2022060101 600 300 2419200 600\n",
+                "example.com.	300	IN	NS	ns1.example.com.\nexample.com.	300	IN	NS	ns2.provider.net.\n",
+                "ns1.example.com.	300	IN	A	1.1.1.1\n",
+                "ns1.example.com.	300	IN	AAAA	2001:db8::1\n",
+            ]
+        );
+
+        for invalid in [
+            r#"{"nameservers": [{"name": "ns.provider.net", "ips": ["1.1.1.1"]}]}"#,
+            r#"{"nameservers": [{"name": "ns.example.com", "ips": ["1.1.1"]}]}"#,
+            r#"{"nameservers": [{"name": "ns.provider.net"}, {"name": "ns.provider.net"}]}"#,
+            r#"{"soa": {"refresh": 0}}"#,
+            r#"{"soa": {"mname": "a..b"}}"#,
+        ] {
+            let config: ZoneConfig = serde_json::from_str(invalid).unwrap();
+            assert!(template.zone_rrsets(&name, &config).is_err());
+        }
     }
 }
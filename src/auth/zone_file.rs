@@ -0,0 +1,507 @@
+use anyhow::{self, bail};
+use r53::{Name, RRset};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+//rfc1035 master file parser, records are grouped into rrsets in the order
+//they first appear
+pub struct ZoneFileParser {
+    origin: Name,
+    zone: Name,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    rrsets: Vec<Vec<String>>,
+    rrset_index: HashMap<String, usize>,
+}
+
+struct Record {
+    line: usize,
+    leading_blank: bool,
+    tokens: Vec<String>,
+}
+
+impl ZoneFileParser {
+    pub fn new(origin: Name) -> Self {
+        ZoneFileParser {
+            origin: origin.clone(),
+            zone: origin,
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+            rrsets: Vec::new(),
+            rrset_index: HashMap::new(),
+        }
+    }
+
+    pub fn parse_file(mut self, path: &Path) -> anyhow::Result<Vec<RRset>> {
+        self.load_file(path, 0)?;
+        self.into_rrsets()
+    }
+
+    pub fn parse_str(mut self, content: &str) -> anyhow::Result<Vec<RRset>> {
+        self.load(content, Path::new("<string>"), 0)?;
+        self.into_rrsets()
+    }
+
+    fn into_rrsets(self) -> anyhow::Result<Vec<RRset>> {
+        let mut rrsets = Vec::with_capacity(self.rrsets.len());
+        for rrs in self.rrsets.into_iter() {
+            rrsets.push(RRset::from_strs(&rrs)?);
+        }
+        Ok(rrsets)
+    }
+
+    fn load_file(&mut self, path: &Path, depth: usize) -> anyhow::Result<()> {
+        match fs::read_to_string(path) {
+            Ok(content) => self.load(&content, path, depth),
+            Err(e) => bail!("{}: {}", path.display(), e),
+        }
+    }
+
+    fn load(&mut self, content: &str, path: &Path, depth: usize) -> anyhow::Result<()> {
+        for record in tokenize(content).map_err(|(line, e)| file_error(path, line, e))? {
+            self.handle_record(record, path, depth)?;
+        }
+        Ok(())
+    }
+
+    fn handle_record(&mut self, record: Record, path: &Path, depth: usize) -> anyhow::Result<()> {
+        let line = record.line;
+        let first = record.tokens[0].to_ascii_uppercase();
+        match first.as_ref() {
+            "$ORIGIN" if !record.leading_blank => {
+                if record.tokens.len() != 2 {
+                    return Err(file_error(path, line, "$ORIGIN needs one domain name"));
+                }
+                self.origin = self
+                    .absolute_name(&record.tokens[1])
+                    .map_err(|e| file_error(path, line, e))?;
+            }
+            "$TTL" if !record.leading_blank => {
+                if record.tokens.len() != 2 {
+                    return Err(file_error(path, line, "$TTL needs one ttl value"));
+                }
+                let ttl = parse_ttl(&record.tokens[1])
+                    .ok_or_else(|| file_error(path, line, "invalid ttl"))?;
+                self.default_ttl = Some(ttl);
+            }
+            "$INCLUDE" if !record.leading_blank => {
+                if record.tokens.len() < 2 || record.tokens.len() > 3 {
+                    return Err(file_error(path, line, "$INCLUDE needs file name"));
+                }
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(file_error(path, line, "$INCLUDE nested too deep"));
+                }
+                let mut include = PathBuf::from(&record.tokens[1]);
+                if include.is_relative() {
+                    if let Some(dir) = path.parent() {
+                        include = dir.join(include);
+                    }
+                }
+                //origin set by $INCLUDE or inside the included file doesn't
+                //leak into the including file
+                let origin = self.origin.clone();
+                if record.tokens.len() == 3 {
+                    self.origin = self
+                        .absolute_name(&record.tokens[2])
+                        .map_err(|e| file_error(path, line, e))?;
+                }
+                let result = self.load_file(&include, depth + 1);
+                self.origin = origin;
+                result?;
+            }
+            _ => {
+                if first.starts_with('$') && !record.leading_blank {
+                    return Err(file_error(path, line, "unknown directive"));
+                }
+                let rr = self
+                    .build_rr(record)
+                    .map_err(|e| file_error(path, line, e))?;
+                self.add_rr(rr);
+            }
+        }
+        Ok(())
+    }
+
+    fn build_rr(&mut self, record: Record) -> Result<String, String> {
+        let mut tokens = record.tokens.into_iter().peekable();
+        let owner = if record.leading_blank {
+            match self.last_owner {
+                Some(ref owner) => owner.clone(),
+                None => return Err("no previous owner name".to_string()),
+            }
+        } else {
+            self.absolute_name(&tokens.next().unwrap())?.to_string()
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        while let Some(token) = tokens.peek() {
+            if ttl.is_none() {
+                if let Some(v) = parse_ttl(token) {
+                    ttl = Some(v);
+                    tokens.next();
+                    continue;
+                }
+            }
+            if class.is_none() && is_class(token) {
+                class = Some(token.to_ascii_uppercase());
+                tokens.next();
+                continue;
+            }
+            break;
+        }
+        match class {
+            Some(ref class) if class != "IN" => {
+                return Err(format!("unsupported class {}", class));
+            }
+            _ => {}
+        }
+
+        let typ = match tokens.next() {
+            Some(typ) => typ.to_ascii_uppercase(),
+            None => return Err("missing record type".to_string()),
+        };
+        let mut rdata = tokens.collect::<Vec<String>>();
+        if rdata.is_empty() {
+            return Err("missing record data".to_string());
+        }
+        for i in name_fields(&typ) {
+            if let Some(field) = rdata.get_mut(*i) {
+                *field = self.absolute_name(field)?.to_string();
+            }
+        }
+
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(ttl) => ttl,
+            None => return Err("no ttl specified and no $TTL default".to_string()),
+        };
+        self.last_ttl = Some(ttl);
+        self.last_owner = Some(owner.clone());
+
+        let rr = format!("{} {} IN {} {}", owner, ttl, typ, rdata.join(" "));
+        let rrset = RRset::from_str(&rr).map_err(|e| e.to_string())?;
+        if !rrset.name.is_subdomain(&self.zone) {
+            return Err(format!("{} is out of zone {}", rrset.name, self.zone));
+        }
+        Ok(rr)
+    }
+
+    fn add_rr(&mut self, rr: String) {
+        let mut fields = rr.split_whitespace();
+        let owner = fields.next().unwrap().to_ascii_lowercase();
+        let typ = fields.nth(2).unwrap();
+        let key = format!("{} {}", owner, typ);
+        match self.rrset_index.get(&key) {
+            Some(i) => self.rrsets[*i].push(rr),
+            None => {
+                self.rrset_index.insert(key, self.rrsets.len());
+                self.rrsets.push(vec![rr]);
+            }
+        }
+    }
+
+    fn absolute_name(&self, name: &str) -> Result<Name, String> {
+        let name = if name == "@" {
+            return Ok(self.origin.clone());
+        } else if name.ends_with('.') {
+            name.to_string()
+        } else {
+            let origin = self.origin.to_string();
+            if origin == "." {
+                format!("{}.", name)
+            } else {
+                format!("{}.{}", name, origin)
+            }
+        };
+        Name::new(&name).map_err(|e| e.to_string())
+    }
+}
+
+//index of the domain name fields in rdata which could be relative
+fn name_fields(typ: &str) -> &'static [usize] {
+    match typ {
+        "NS" | "CNAME" | "DNAME" | "PTR" => &[0],
+        "MX" | "AFSDB" | "KX" => &[1],
+        "SRV" => &[3],
+        "SOA" | "RP" => &[0, 1],
+        _ => &[],
+    }
+}
+
+fn is_class(token: &str) -> bool {
+    matches!(
+        token.to_ascii_uppercase().as_ref(),
+        "IN" | "CH" | "CS" | "HS"
+    )
+}
+
+//ttl in seconds or with units like 1h30m
+pub(crate) fn parse_ttl(token: &str) -> Option<u32> {
+    if let Ok(ttl) = token.parse::<u32>() {
+        return Some(ttl);
+    }
+    if !token.chars().next()?.is_ascii_digit() {
+        return None;
+    }
+
+    let mut ttl: u32 = 0;
+    let mut value: u32 = 0;
+    let mut has_value = false;
+    for c in token.chars() {
+        if let Some(d) = c.to_digit(10) {
+            value = value.checked_mul(10)?.checked_add(d)?;
+            has_value = true;
+        } else {
+            if !has_value {
+                return None;
+            }
+            let unit = match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 604800,
+                _ => return None,
+            };
+            ttl = ttl.checked_add(value.checked_mul(unit)?)?;
+            value = 0;
+            has_value = false;
+        }
+    }
+    if has_value {
+        return None;
+    }
+    Some(ttl)
+}
+
+fn file_error<E: ToString>(path: &Path, line: usize, e: E) -> anyhow::Error {
+    anyhow::anyhow!("{}:{}: {}", path.display(), line, e.to_string())
+}
+
+//split content into records, records could span multiple lines inside
+//parentheses, comments are stripped and quoted strings kept as one token
+fn tokenize(content: &str) -> Result<Vec<Record>, (usize, String)> {
+    let mut records = Vec::new();
+    let mut current: Option<Record> = None;
+    let mut depth = 0;
+
+    for (i, line) in content.lines().enumerate() {
+        let line_no = i + 1;
+        let mut chars = line.chars().peekable();
+        if current.is_none() {
+            let leading_blank = matches!(chars.peek(), Some(' ') | Some('\t'));
+            current = Some(Record {
+                line: line_no,
+                leading_blank,
+                tokens: Vec::new(),
+            });
+        }
+        let record = current.as_mut().unwrap();
+
+        let mut token = String::new();
+        let mut in_quote = false;
+        while let Some(c) = chars.next() {
+            if in_quote {
+                token.push(c);
+                if c == '\\' {
+                    if let Some(escaped) = chars.next() {
+                        token.push(escaped);
+                    }
+                } else if c == '"' {
+                    in_quote = false;
+                }
+                continue;
+            }
+            match c {
+                '"' => {
+                    token.push(c);
+                    in_quote = true;
+                }
+                '\\' => {
+                    token.push(c);
+                    if let Some(escaped) = chars.next() {
+                        token.push(escaped);
+                    }
+                }
+                ';' => break,
+                '(' | ')' | ' ' | '\t' => {
+                    if !token.is_empty() {
+                        record.tokens.push(token.clone());
+                        token.clear();
+                    }
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        if depth == 0 {
+                            return Err((line_no, "unbalanced parenthesis".to_string()));
+                        }
+                        depth -= 1;
+                    }
+                }
+                _ => token.push(c),
+            }
+        }
+        if in_quote {
+            return Err((line_no, "unterminated quoted string".to_string()));
+        }
+        if !token.is_empty() {
+            record.tokens.push(token);
+        }
+
+        if depth == 0 {
+            let record = current.take().unwrap();
+            if !record.tokens.is_empty() {
+                records.push(record);
+            }
+        }
+    }
+
+    if let Some(record) = current {
+        return Err((record.line, "unbalanced parenthesis".to_string()));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::zone::MemoryZone;
+    use crate::test_util::temp_dir;
+    use r53::RRType;
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 admin (
+            2022060101 ; serial
+            7200       ; refresh
+            3600 1209600
+            300 )
+    IN  NS  ns1
+    IN  NS  ns2.example.net.
+ns1 600 IN A 1.1.1.1
+        IN A 1.1.1.2
+www     A   2.2.2.2 ; comment
+mail    IN 300 MX 10 mx
+txt     TXT "v=spf1 ; not a comment"
+$ORIGIN sub.example.com.
+host    A   3.3.3.3
+"#;
+
+    fn find<'a>(rrsets: &'a [RRset], name: &str, typ: RRType) -> &'a RRset {
+        let name = Name::new(name).unwrap();
+        rrsets
+            .iter()
+            .find(|rrset| rrset.name == name && rrset.typ == typ)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_zone() {
+        let origin = Name::new("example.com").unwrap();
+        let rrsets = ZoneFileParser::new(origin).parse_str(ZONE).unwrap();
+        assert_eq!(rrsets.len(), 8);
+
+        let soa = find(&rrsets, "example.com", RRType::SOA);
+        assert_eq!(
+            soa.rdatas[0].to_string(),
+            "ns1.example.com. admin.example.com. 2022060101 7200 3600 1209600 300"
+        );
+        assert_eq!(soa.ttl.0, 3600);
+        assert_eq!(find(&rrsets, "example.com", RRType::NS).rr_count(), 2);
+
+        let ns1 = find(&rrsets, "ns1.example.com", RRType::A);
+        assert_eq!(ns1.rr_count(), 2);
+        assert_eq!(ns1.ttl.0, 600);
+        assert_eq!(find(&rrsets, "www.example.com", RRType::A).ttl.0, 3600);
+        assert_eq!(
+            find(&rrsets, "mail.example.com", RRType::MX).rdatas[0].to_string(),
+            "10 mx.example.com."
+        );
+        find(&rrsets, "txt.example.com", RRType::TXT);
+        find(&rrsets, "host.sub.example.com", RRType::A);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let origin = Name::new("example.com").unwrap();
+        let err = ZoneFileParser::new(origin.clone())
+            .parse_str("www 3600 IN A 1.1.1.1\nbad 3600 IN A 1.1.1\n")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("<string>:2:"));
+
+        let err = ZoneFileParser::new(origin.clone())
+            .parse_str("www IN A 1.1.1.1\n")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("<string>:1:"));
+
+        let err = ZoneFileParser::new(origin.clone())
+            .parse_str("$TTL 300\nwww.example.net. A 1.1.1.1\n")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("<string>:2:"));
+
+        let err = ZoneFileParser::new(origin)
+            .parse_str("$TTL 300\n@ SOA ns1 admin (1 2 3\n4 5\n")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("<string>:2:"));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = temp_dir("zone-file");
+        fs::write(
+            dir.join("example.com.zone"),
+            "$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\n@ NS ns1\n$INCLUDE hosts sub\nns1 A 1.1.1.1\n",
+        )
+        .unwrap();
+        fs::write(dir.join("hosts"), "www A 2.2.2.2\nbad A 2.2.2\n").unwrap();
+
+        let origin = Name::new("example.com").unwrap();
+        let err = ZoneFileParser::new(origin.clone())
+            .parse_file(&dir.join("example.com.zone"))
+            .unwrap_err();
+        assert!(err.to_string().contains("hosts:2:"));
+
+        fs::write(dir.join("hosts"), "www A 2.2.2.2\n").unwrap();
+        let rrsets = ZoneFileParser::new(origin)
+            .parse_file(&dir.join("example.com.zone"))
+            .unwrap();
+        find(&rrsets, "www.sub.example.com", RRType::A);
+        find(&rrsets, "ns1.example.com", RRType::A);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let origin = Name::new("example.com").unwrap();
+        let rrsets = ZoneFileParser::new(origin.clone()).parse_str(ZONE).unwrap();
+        let zone = MemoryZone::from_rrsets(origin.clone(), rrsets.clone()).unwrap();
+        let content = zone.to_master_file();
+        assert!(content.starts_with("$ORIGIN example.com.\nexample.com.\t3600\tIN\tSOA\t"));
+
+        let exported = ZoneFileParser::new(origin.clone())
+            .parse_str(&content)
+            .unwrap();
+        assert_eq!(exported.len(), rrsets.len());
+        for rrset in rrsets.iter() {
+            assert_eq!(find(&exported, &rrset.name.to_string(), rrset.typ), rrset);
+        }
+        let zone = MemoryZone::from_rrsets(origin, exported).unwrap();
+        assert_eq!(zone.to_master_file(), content);
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("300"), Some(300));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W"), Some(604800));
+        assert_eq!(parse_ttl("IN"), None);
+        assert_eq!(parse_ttl("1h3"), None);
+    }
+}
@@ -2,62 +2,155 @@ mod api;
 mod auth;
 mod recursor;
 mod server;
+#[cfg(test)]
+mod test_util;
 
 use std::net::SocketAddr;
+use std::path::Path;
 use std::thread;
 
-use clap::{arg, Command};
+use anyhow::bail;
+use clap::{arg, ArgMatches, Command};
+use r53::Name;
 use tokio::runtime::{self, Runtime};
 use tokio::signal;
 
-use auth::Auth;
+use auth::{parse_remotes, Auth, RolloverPolicy, SerialPolicy, ZoneTemplate};
 use recursor::Recursor;
-use server::UdpServer;
+use server::{
+    bind_udp_sockets, Handler, QuicServer, TcpServer, TlsCertificate, TlsServer, TsigKey,
+    TsigKeyring, UdpServer, DEFAULT_UDP_PAYLOAD_LEN,
+};
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let matches = Command::new("xdns")
         .about("xdns a dns server")
         .subcommand_required(true)
         .arg_required_else_help(true)
         .allow_external_subcommands(true)
         .subcommand(
-            Command::new("auth")
-                .about("authority dns server")
-                .arg(arg!(--dns <DNS> "dns server addr"))
-                .arg(arg!(--http <HTTP> "http server addr"))
+            dns_server_args(Command::new("auth").about("authority dns server"))
+                .arg(arg!(--http <HTTP> "http server addr").validator(parse_addr))
+                .arg(arg!(--"data-dir" [DIR] "dir to persist zones changed through http api"))
+                .arg(arg!(--"zone-dir" [DIR] "dir of zone files named after the zones"))
+                .arg(
+                    arg!(--"zone-template" [FILE] "json file of soa and ns defaults for new zones"),
+                )
+                .arg(
+                    arg!(--"serial-policy" [POLICY] "how soa serial is bumped on zone change")
+                        .possible_values(["increment", "unixtime", "date"])
+                        .default_value("increment"),
+                )
+                .arg(
+                    arg!(--"zsk-lifetime" [DAYS] "days before zsk of signed zones is rolled, 0 to roll on request")
+                        .default_value("90"),
+                )
+                .arg(
+                    arg!(--"ksk-lifetime" [DAYS] "days before ksk of signed zones is rolled, 0 to roll on request")
+                        .default_value("0"),
+                )
+                .arg(
+                    arg!(--zone [ZONE] "zone file in format <zone>=<path>, could be repeated")
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    arg!(--secondary [ZONE] "secondary zone in format <zone>=<primary>[@<key>][,<primary>[@<key>]], could be repeated")
+                        .multiple_occurrences(true)
+                        .validator(parse_secondary),
+                )
+                .arg(
+                    arg!(--"tsig-key" [KEY] "tsig key in format <name>:<hmac-sha256|hmac-sha512>:<base64 secret>, could be repeated")
+                        .multiple_occurrences(true),
+                )
                 .arg_required_else_help(true),
         )
         .subcommand(
-            Command::new("recursor")
-                .about("recursive dns server")
-                .arg(arg!(--dns <DNS> "dns server addr"))
-                .arg(arg!(--http <HTTP> "http server addr"))
-                .arg(arg!(--report <REPORT> "report collect server addr"))
+            dns_server_args(Command::new("recursor").about("recursive dns server"))
+                .arg(arg!(--http <HTTP> "http server addr").validator(parse_addr))
+                .arg(arg!(--report <REPORT> "report collect server addr").validator(parse_addr))
                 .arg_required_else_help(true),
         )
         .get_matches();
 
     match matches.subcommand() {
         Some(("auth", sub_matches)) => {
-            let dns_addr = sub_matches
-                .value_of("dns")
-                .unwrap()
-                .parse::<SocketAddr>()
-                .unwrap();
+            let dns_config = DnsConfig::from_matches(sub_matches);
             let cmd_addr = sub_matches
                 .value_of("http")
                 .unwrap()
                 .parse::<SocketAddr>()
                 .unwrap();
-            start_auth(cmd_addr, dns_addr);
+            let auth = Auth::new();
+            let mut auth = match sub_matches.value_of("zone-template") {
+                Some(path) => match ZoneTemplate::load(Path::new(path)) {
+                    Ok(template) => auth.zone_template(template),
+                    Err(e) => panic!("load zone template failed: {}", e),
+                },
+                None => auth,
+            };
+            if let Some(keys) = sub_matches.values_of("tsig-key") {
+                let keys = keys
+                    .map(|key| match key.parse::<TsigKey>() {
+                        Ok(key) => key,
+                        Err(e) => panic!("invalid tsig key: {}", e),
+                    })
+                    .collect();
+                auth = auth.tsig_keys(TsigKeyring::new(keys));
+            }
+            let auth = auth.serial_policy(
+                sub_matches
+                    .value_of("serial-policy")
+                    .unwrap()
+                    .parse::<SerialPolicy>()
+                    .unwrap(),
+            );
+            let lifetime = |arg: &str| {
+                sub_matches
+                    .value_of(arg)
+                    .unwrap()
+                    .parse::<u32>()
+                    .unwrap_or_else(|_| panic!("{} should be days", arg))
+                    * 86400
+            };
+            let auth = auth.rollover_policy(RolloverPolicy {
+                zsk_lifetime: lifetime("zsk-lifetime"),
+                ksk_lifetime: lifetime("ksk-lifetime"),
+                ..Default::default()
+            });
+            if let Some(dir) = sub_matches.value_of("zone-dir") {
+                if let Err(e) = auth.load_zone_dir(Path::new(dir)) {
+                    panic!("load zone dir failed: {}", e);
+                }
+            }
+            if let Some(zones) = sub_matches.values_of("zone") {
+                for zone in zones {
+                    let (name, path) = zone.split_once('=').expect("zone should be <zone>=<path>");
+                    if let Err(e) = auth.load_zone_file(Name::new(name).unwrap(), Path::new(path)) {
+                        panic!("load zone {} failed: {}", name, e);
+                    }
+                }
+            }
+            let auth = match sub_matches.value_of("data-dir") {
+                Some(dir) => match auth.restore(Path::new(dir)) {
+                    Ok(auth) => auth,
+                    Err(e) => panic!("open data dir failed: {}", e),
+                },
+                None => auth,
+            };
+            if let Some(zones) = sub_matches.values_of("secondary") {
+                for zone in zones {
+                    let (name, primaries) = parse_secondary(zone).unwrap();
+                    if let Err(e) = auth.add_secondary_zone(name.clone(), &primaries) {
+                        panic!("add secondary zone {} failed: {}", name, e);
+                    }
+                }
+            }
+            start_auth(auth, cmd_addr, dns_config);
         }
 
         Some(("recursor", sub_matches)) => {
-            let dns_addr = sub_matches
-                .value_of("dns")
-                .unwrap()
-                .parse::<SocketAddr>()
-                .unwrap();
+            let dns_config = DnsConfig::from_matches(sub_matches);
             let cmd_addr = sub_matches
                 .value_of("http")
                 .unwrap()
@@ -68,15 +161,155 @@ fn main() {
                 .unwrap()
                 .parse::<SocketAddr>()
                 .unwrap();
-            start_recursor(cmd_addr, dns_addr, report_collect_server);
+            start_recursor(cmd_addr, dns_config, report_collect_server);
         }
 
         _ => unreachable!(),
     }
 }
 
-fn start_auth(cmd_addr: SocketAddr, dns_addr: SocketAddr) {
-    let auth = Auth::new();
+fn dns_server_args(cmd: Command) -> Command {
+    cmd.arg(
+        arg!(--dns <DNS> "dns server addr, could be repeated")
+            .multiple_occurrences(true)
+            .validator(parse_addr),
+    )
+    .arg(arg!(--"udp-workers" [N] "SO_REUSEPORT udp sockets opened per dns addr"))
+    .arg(arg!(--"max-udp-payload" [SIZE] "max edns udp payload size"))
+    .arg(
+        arg!(--dot [DOT] "dns over tls addr, could be repeated")
+            .multiple_occurrences(true)
+            .validator(parse_addr),
+    )
+    .arg(
+        arg!(--doq [DOQ] "dns over quic addr, could be repeated")
+            .multiple_occurrences(true)
+            .validator(parse_addr),
+    )
+    .arg(
+        arg!(--doh [DOH] "dns over https addr, could be repeated")
+            .multiple_occurrences(true)
+            .validator(parse_addr),
+    )
+    .arg(arg!(--"tls-cert" [PATH] "tls certificate chain in pem"))
+    .arg(arg!(--"tls-key" [PATH] "tls private key in pem"))
+}
+
+struct DnsConfig {
+    addrs: Vec<SocketAddr>,
+    udp_workers: usize,
+    max_udp_payload: u16,
+    dot_addrs: Vec<SocketAddr>,
+    doq_addrs: Vec<SocketAddr>,
+    doh_addrs: Vec<SocketAddr>,
+    tls: Option<TlsCertificate>,
+}
+
+impl DnsConfig {
+    fn from_matches(matches: &ArgMatches) -> Self {
+        let addrs = parse_addrs(matches, "dns");
+        let udp_workers = matches
+            .value_of("udp-workers")
+            .map_or(1, |n| n.parse::<usize>().unwrap());
+        let max_udp_payload = matches
+            .value_of("max-udp-payload")
+            .map_or(DEFAULT_UDP_PAYLOAD_LEN, |size| size.parse::<u16>().unwrap());
+        let dot_addrs = parse_addrs(matches, "dot");
+        let doq_addrs = parse_addrs(matches, "doq");
+        let doh_addrs = parse_addrs(matches, "doh");
+        let tls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+            (Some(cert), Some(key)) => Some(TlsCertificate::load(cert, key).unwrap()),
+            _ => None,
+        };
+        if (!dot_addrs.is_empty() || !doq_addrs.is_empty() || !doh_addrs.is_empty())
+            && tls.is_none()
+        {
+            panic!("dns over tls/quic/https requires --tls-cert and --tls-key");
+        }
+        DnsConfig {
+            addrs,
+            udp_workers,
+            max_udp_payload,
+            dot_addrs,
+            doq_addrs,
+            doh_addrs,
+            tls,
+        }
+    }
+}
+
+//malformed args are reported by clap with the usage
+fn parse_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    Ok(addr.parse::<SocketAddr>()?)
+}
+
+fn parse_secondary(arg: &str) -> anyhow::Result<(Name, Vec<String>)> {
+    let (name, primaries) = match arg.split_once('=') {
+        Some(result) => result,
+        None => bail!("secondary zone should be <zone>=<primary>[@<key>][,<primary>[@<key>]]"),
+    };
+    let primaries = primaries
+        .split(',')
+        .map(|primary| primary.to_string())
+        .collect::<Vec<_>>();
+    parse_remotes(&primaries)?;
+    Ok((Name::new(name)?, primaries))
+}
+
+fn parse_addrs(matches: &ArgMatches, name: &str) -> Vec<SocketAddr> {
+    matches.values_of(name).map_or(Vec::new(), |addrs| {
+        addrs
+            .map(|addr| addr.parse::<SocketAddr>().unwrap())
+            .collect()
+    })
+}
+
+fn spawn_dns_servers<H: Handler>(handler: H, config: &DnsConfig) {
+    for addr in config.addrs.iter() {
+        let sockets = bind_udp_sockets(*addr, config.udp_workers).expect("bind addr failed");
+        for socket in sockets {
+            let handler = handler.clone();
+            let max_udp_payload = config.max_udp_payload;
+            tokio::spawn(async move {
+                UdpServer::new(handler)
+                    .max_udp_payload(max_udp_payload)
+                    .serve(socket)
+                    .await
+            });
+        }
+
+        let handler = handler.clone();
+        let addr = *addr;
+        tokio::spawn(async move { TcpServer::new(handler).run(addr).await });
+    }
+
+    for addr in config.dot_addrs.iter() {
+        let mut server = TlsServer::new(handler.clone(), config.tls.as_ref().unwrap()).unwrap();
+        let addr = *addr;
+        tokio::spawn(async move { server.run(addr).await });
+    }
+
+    for addr in config.doq_addrs.iter() {
+        let mut server = QuicServer::new(handler.clone(), config.tls.as_ref().unwrap()).unwrap();
+        let addr = *addr;
+        tokio::spawn(async move { server.run(addr).await });
+    }
+
+    if !config.doh_addrs.is_empty() {
+        let handler = handler.clone();
+        let addrs = config.doh_addrs.clone();
+        let tls = config.tls.clone().unwrap();
+        thread::spawn(move || {
+            runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(api::start_doh(handler, addrs, tls));
+        });
+    }
+}
+
+fn start_auth(auth: Auth, cmd_addr: SocketAddr, dns_config: DnsConfig) {
     {
         let auth = auth.clone();
         thread::spawn(move || {
@@ -89,7 +322,20 @@ fn start_auth(cmd_addr: SocketAddr, dns_addr: SocketAddr) {
     }
 
     Runtime::new().unwrap().block_on(async move {
-        tokio::spawn(async move { UdpServer::new(auth).run(dns_addr).await });
+        spawn_dns_servers(auth.clone(), &dns_config);
+        {
+            let auth = auth.clone();
+            tokio::spawn(async move { auth.run_secondaries().await });
+        }
+        {
+            let auth = auth.clone();
+            tokio::spawn(async move { auth.run_notify().await });
+        }
+        {
+            let auth = auth.clone();
+            tokio::spawn(async move { auth.run_rollover().await });
+        }
+        tokio::spawn(async move { auth.run_snapshot().await });
 
         match signal::ctrl_c().await {
             Ok(()) => {
@@ -102,7 +348,7 @@ fn start_auth(cmd_addr: SocketAddr, dns_addr: SocketAddr) {
     })
 }
 
-fn start_recursor(cmd_addr: SocketAddr, dns_addr: SocketAddr, report_collect_server: SocketAddr) {
+fn start_recursor(cmd_addr: SocketAddr, dns_config: DnsConfig, report_collect_server: SocketAddr) {
     let recursor = Recursor::new();
     {
         let recursor = recursor.clone();
@@ -116,10 +362,7 @@ fn start_recursor(cmd_addr: SocketAddr, dns_addr: SocketAddr, report_collect_ser
     }
 
     Runtime::new().unwrap().block_on(async move {
-        {
-            let recursor = recursor.clone();
-            tokio::spawn(async move { UdpServer::new(recursor).run(dns_addr).await });
-        }
+        spawn_dns_servers(recursor.clone(), &dns_config);
 
         tokio::spawn(async move {
             recursor
@@ -5,14 +5,21 @@ use tokio::{net::UdpSocket, time::timeout};
 
 use r53::{MessageRender, Request, Response};
 
+use crate::server::{build_edns, DEFAULT_UDP_PAYLOAD_LEN};
+
 const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(3);
 const QUERY_BUFFER_LEN: usize = 512;
-const RESPONSE_BUFFER_LEN: usize = 1232;
+const RESPONSE_BUFFER_LEN: usize = DEFAULT_UDP_PAYLOAD_LEN as usize;
 
 pub async fn roundtrip(req: &Request, target: SocketAddr) -> anyhow::Result<Response> {
     let mut req_buf = [0; QUERY_BUFFER_LEN];
     let mut resp_buf = [0; RESPONSE_BUFFER_LEN];
 
+    //upstream sees our own payload size, not the one from the client
+    let mut req = req.clone();
+    let dnssec_aware = req.edns.as_ref().map_or(false, |edns| edns.dnssec_aware);
+    req.edns = Some(build_edns(DEFAULT_UDP_PAYLOAD_LEN, dnssec_aware));
+
     let mut render = MessageRender::new(&mut req_buf);
     let len = req.to_wire(&mut render)?;
     let socket = UdpSocket::bind(&("0.0.0.0:0".parse::<SocketAddr>().unwrap())).await?;
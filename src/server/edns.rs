@@ -0,0 +1,108 @@
+use r53::{Edns, Request, Response, ResponseBuilder};
+
+pub const EDNS_VERSION: u8 = 0;
+pub const DEFAULT_UDP_PAYLOAD_LEN: u16 = 1232;
+pub const MIN_UDP_PAYLOAD_LEN: u16 = 512;
+pub const MAX_UDP_PAYLOAD_LEN: u16 = 4096;
+//BADVERS(16) doesn't fit in the header rcode, the upper 8 bits go to OPT
+const BADVERS_EXTENDED_RCODE: u8 = 1;
+
+pub fn build_edns(udp_size: u16, dnssec_aware: bool) -> Edns {
+    Edns {
+        versoin: EDNS_VERSION,
+        extened_rcode: 0,
+        udp_size,
+        dnssec_aware,
+        options: None,
+    }
+}
+
+//payload size the client can receive over udp, capped by what the server allows,
+//512 is always allowed even if the server limit is set lower
+pub fn max_udp_payload_len(req: &Request, server_udp_size: u16) -> usize {
+    let server_udp_size = server_udp_size.max(MIN_UDP_PAYLOAD_LEN);
+    match req.edns {
+        Some(ref edns) => edns.udp_size.clamp(MIN_UDP_PAYLOAD_LEN, server_udp_size) as usize,
+        None => MIN_UDP_PAYLOAD_LEN as usize,
+    }
+}
+
+//rfc6891: a responder that doesn't implement the requested version returns BADVERS
+pub fn bad_version_response(req: &Request, server_udp_size: u16) -> Option<Response> {
+    match req.edns {
+        Some(ref edns) if edns.versoin > EDNS_VERSION => {
+            let mut resp = Response::with_question(req.question.name.clone(), req.question.typ);
+            let mut builder = ResponseBuilder::new(&mut resp);
+            builder.id(req.header.id).make_response().done();
+            let mut edns = build_edns(server_udp_size, false);
+            edns.extened_rcode = BADVERS_EXTENDED_RCODE;
+            resp.edns = Some(edns);
+            Some(resp)
+        }
+        _ => None,
+    }
+}
+
+//only echo OPT to clients which sent one, whatever the handler put there
+//(e.g. an upstream answer) is replaced with the server's own view
+pub fn set_response_edns(req_edns: Option<&Edns>, resp: &mut Response, server_udp_size: u16) {
+    resp.edns = req_edns.map(|edns| build_edns(server_udp_size, edns.dnssec_aware));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{Name, RRType, Rcode};
+
+    fn request_with_edns(edns: Option<Edns>) -> Request {
+        let mut req = Request::new(Name::new("example.com").unwrap(), RRType::A);
+        req.edns = edns;
+        req
+    }
+
+    #[test]
+    fn test_payload_negotiation() {
+        let req = request_with_edns(None);
+        assert_eq!(max_udp_payload_len(&req, DEFAULT_UDP_PAYLOAD_LEN), 512);
+
+        let req = request_with_edns(Some(build_edns(4096, false)));
+        assert_eq!(max_udp_payload_len(&req, DEFAULT_UDP_PAYLOAD_LEN), 1232);
+        assert_eq!(max_udp_payload_len(&req, MAX_UDP_PAYLOAD_LEN), 4096);
+
+        let req = request_with_edns(Some(build_edns(100, false)));
+        assert_eq!(max_udp_payload_len(&req, DEFAULT_UDP_PAYLOAD_LEN), 512);
+
+        //server limit below 512 doesn't shrink the payload
+        let req = request_with_edns(Some(build_edns(4096, false)));
+        assert_eq!(max_udp_payload_len(&req, 256), 512);
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let req = request_with_edns(Some(build_edns(4096, false)));
+        assert!(bad_version_response(&req, DEFAULT_UDP_PAYLOAD_LEN).is_none());
+
+        let mut edns = build_edns(4096, false);
+        edns.versoin = 1;
+        let req = request_with_edns(Some(edns));
+        let resp = bad_version_response(&req, DEFAULT_UDP_PAYLOAD_LEN).unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NoError);
+        let edns = resp.edns.unwrap();
+        assert_eq!(edns.extened_rcode, BADVERS_EXTENDED_RCODE);
+        assert_eq!(edns.versoin, EDNS_VERSION);
+    }
+
+    #[test]
+    fn test_echo_opt() {
+        let mut resp = Response::with_question(Name::new("example.com").unwrap(), RRType::A);
+        resp.edns = Some(build_edns(512, false));
+        set_response_edns(None, &mut resp, DEFAULT_UDP_PAYLOAD_LEN);
+        assert!(resp.edns.is_none());
+
+        let edns = build_edns(4096, true);
+        set_response_edns(Some(&edns), &mut resp, DEFAULT_UDP_PAYLOAD_LEN);
+        let echo = resp.edns.unwrap();
+        assert_eq!(echo.udp_size, DEFAULT_UDP_PAYLOAD_LEN);
+        assert!(echo.dnssec_aware);
+    }
+}
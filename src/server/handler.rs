@@ -1,9 +1,101 @@
 use anyhow;
 use async_trait::async_trait;
+use std::net::IpAddr;
 
-use r53::{Request, Response};
+use r53::{
+    header_flag::HeaderFlag, Opcode, RRType, RRset, Rcode, Request, Response, ResponseBuilder,
+};
+
+use super::edns::{bad_version_response, set_response_edns};
+use super::transfer::rcode_response;
+use super::tsig::TsigKeyring;
+use super::update::Update;
+
+//peer of a request, key is the name of the tsig key the request is
+//verified with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub ip: IpAddr,
+    pub key: Option<String>,
+}
 
 #[async_trait]
 pub trait Handler: Send + Clone + 'static {
     async fn resolve(&mut self, req: Request) -> anyhow::Result<Response>;
+
+    //keys to verify signed requests, a request signed with any other key is
+    //answered with BADKEY
+    fn keyring(&self) -> Option<&TsigKeyring> {
+        None
+    }
+
+    //records of the zone transfer in the order they are sent, the error
+    //rcode is returned to the client when the transfer isn't served. serial
+    //is the version the client has in ixfr query
+    async fn transfer(
+        &mut self,
+        _req: &Request,
+        _client: &Client,
+        _serial: Option<u32>,
+    ) -> Result<Vec<RRset>, Rcode> {
+        Err(Rcode::NotImp)
+    }
+
+    //rfc1996 notify of the zone at question name, the rcode is returned to
+    //the client
+    async fn notify(&mut self, _req: &Request, _client: &Client) -> Rcode {
+        Rcode::NotImp
+    }
+
+    //rfc2136 update, the rcode is returned to the client
+    async fn update(&mut self, _update: Update, _client: &Client) -> Rcode {
+        Rcode::NotImp
+    }
+}
+
+//common request processing shared by all the transports, edns is negotiated
+//here so handlers only deal with the query itself
+pub(crate) async fn handle_request<H: Handler>(
+    handler: &mut H,
+    req: Request,
+    server_udp_size: u16,
+) -> anyhow::Result<Response> {
+    if let Some(resp) = bad_version_response(&req, server_udp_size) {
+        return Ok(resp);
+    }
+    //zone transfer is only served over stream transports, notify and update
+    //only over udp and tcp where the client address is known
+    if req.question.typ == RRType::AXFR || req.header.opcode != Opcode::Query {
+        return Ok(rcode_response(&req, Rcode::NotImp));
+    }
+
+    let req_edns = req.edns.clone();
+    let mut resp = if req.question.typ == RRType::IXFR {
+        //ixfr over udp is answered with the current soa, which tells the
+        //client to retry over tcp(rfc1995 section 2)
+        let mut soa_req = req;
+        soa_req.question.typ = RRType::SOA;
+        let mut resp = handler.resolve(soa_req).await?;
+        resp.question.typ = RRType::IXFR;
+        resp
+    } else {
+        handler.resolve(req).await?
+    };
+    set_response_edns(req_edns.as_ref(), &mut resp, server_udp_size);
+    Ok(resp)
+}
+
+pub(crate) async fn handle_notify<H: Handler>(
+    handler: &mut H,
+    req: Request,
+    client: &Client,
+) -> Response {
+    let rcode = handler.notify(&req, client).await;
+    let mut resp = rcode_response(&req, rcode);
+    let mut builder = ResponseBuilder::new(&mut resp);
+    builder
+        .opcode(Opcode::Notify)
+        .set_flag(HeaderFlag::AuthAnswer)
+        .done();
+    resp
 }
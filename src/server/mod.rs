@@ -1,5 +1,25 @@
+mod edns;
 mod handler;
+mod quic_server;
+mod socket;
+mod tcp_server;
+mod tls;
+mod tls_server;
+mod transfer;
+mod tsig;
 mod udp_server;
+mod update;
 
-pub use handler::Handler;
+pub use edns::{build_edns, DEFAULT_UDP_PAYLOAD_LEN};
+pub use handler::{Client, Handler};
+pub use quic_server::QuicServer;
+pub use socket::bind_udp_sockets;
+pub use tcp_server::TcpServer;
+pub(crate) use tcp_server::{handle_message, read_message, render_message, write_message};
+pub use tls::TlsCertificate;
+pub use tls_server::TlsServer;
+pub(crate) use tsig::{key_name, TsigSession};
+pub use tsig::{TsigKey, TsigKeyring};
 pub use udp_server::UdpServer;
+pub(crate) use update::read_labels;
+pub use update::{Update, UpdateClass, UpdateRR};
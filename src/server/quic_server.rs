@@ -0,0 +1,165 @@
+use super::handler::Handler;
+use super::tcp_server::{handle_message, read_message, write_message};
+use super::tls::TlsCertificate;
+use quinn::{Connecting, Endpoint, IdleTimeout, ServerConfig, TransportConfig, VarInt};
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+const DOQ_ALPN: &[u8] = b"doq";
+const MAX_MESSAGE_LEN: usize = 65535;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+const DEFAULT_MAX_CONNECTIONS: u32 = 1024;
+const DEFAULT_MAX_INFLIGHT: usize = 1024;
+
+//dns over quic(rfc9250), every bidirectional stream carries one query and
+//its response, both with the same 2 bytes length prefix as tcp
+pub struct QuicServer<H: Handler> {
+    handler: H,
+    server_config: ServerConfig,
+    max_inflight: usize,
+}
+
+impl<H: Handler> QuicServer<H> {
+    pub fn new(handler: H, tls: &TlsCertificate) -> anyhow::Result<Self> {
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(IdleTimeout::try_from(DEFAULT_IDLE_TIMEOUT)?))
+            .max_concurrent_bidi_streams(VarInt::from_u32(MAX_CONCURRENT_STREAMS))
+            .max_concurrent_uni_streams(VarInt::from_u32(0));
+        let mut server_config = ServerConfig::with_crypto(tls.server_config(DOQ_ALPN)?);
+        server_config
+            .transport_config(Arc::new(transport))
+            .concurrent_connections(DEFAULT_MAX_CONNECTIONS);
+        Ok(QuicServer {
+            handler,
+            server_config,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+        })
+    }
+
+    //connections over the cap are refused by the endpoint
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.server_config
+            .concurrent_connections(u32::try_from(max_connections).unwrap_or(u32::MAX));
+        self
+    }
+
+    pub fn max_inflight(mut self, max_inflight: usize) -> Self {
+        self.max_inflight = max_inflight;
+        self
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<Endpoint> {
+        Endpoint::server(self.server_config.clone(), addr)
+    }
+
+    pub async fn run(&mut self, addr: SocketAddr) {
+        let endpoint = self.bind(addr).expect("bind addr failed");
+        self.serve(endpoint).await;
+    }
+
+    pub async fn serve(&mut self, endpoint: Endpoint) {
+        let inflight = Arc::new(Semaphore::new(self.max_inflight));
+        while let Some(connecting) = endpoint.accept().await {
+            let handler = self.handler.clone();
+            let inflight = inflight.clone();
+            tokio::spawn(async move {
+                serve_connection(connecting, handler, inflight).await;
+            });
+        }
+    }
+}
+
+async fn serve_connection<H: Handler>(
+    connecting: Connecting,
+    handler: H,
+    inflight: Arc<Semaphore>,
+) {
+    let connection = match connecting.await {
+        Ok(connection) => connection,
+        Err(_) => return,
+    };
+
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        //queries of all the connections share the limit, once it's reached
+        //new streams wait and the stream limit of each connection holds its
+        //client back
+        let permit = match inflight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        let mut handler = handler.clone();
+        tokio::spawn(async move {
+            let mut req_buf = vec![0; MAX_MESSAGE_LEN];
+            let len = match timeout(
+                DEFAULT_STREAM_TIMEOUT,
+                read_message(&mut recv, &mut req_buf),
+            )
+            .await
+            {
+                Ok(Ok(len)) => len,
+                _ => return,
+            };
+            if let Ok((_, message)) = handle_message(&mut handler, &req_buf[..len]).await {
+                if write_message(&mut send, &message).await.is_ok() {
+                    let _ = send.finish().await;
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{build_auth, self_signed_certificate};
+    use r53::{MessageRender, Name, RRType, Rcode, Request, Response, SectionType};
+
+    #[tokio::test]
+    async fn test_doq_query() {
+        let auth = build_auth();
+        let (tls, client_config) = self_signed_certificate(DOQ_ALPN);
+        let mut server = QuicServer::new(auth, &tls)
+            .unwrap()
+            .max_connections(1)
+            .max_inflight(1);
+        let endpoint = server.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(endpoint).await });
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(client_config));
+        let connection = client.connect(addr, "localhost").unwrap().await.unwrap();
+
+        //two queries on two streams of the same connection
+        for name in ["www.example.com", "ns.example.com"] {
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            let req = Request::new(Name::new(name).unwrap(), RRType::A);
+            let mut query = vec![0; 512];
+            let len = {
+                let mut render = MessageRender::new(&mut query[..]);
+                req.to_wire(&mut render).unwrap()
+            };
+            write_message(&mut send, &query[..len]).await.unwrap();
+            send.finish().await.unwrap();
+
+            let mut buf = vec![0; MAX_MESSAGE_LEN];
+            let len = read_message(&mut recv, &mut buf).await.unwrap();
+            let resp = Response::from_wire(&buf[..len]).unwrap();
+            assert_eq!(resp.header.id, 0);
+            assert_eq!(resp.header.rcode, Rcode::NoError);
+            assert_eq!(resp.section(SectionType::Answer).unwrap().len(), 1);
+        }
+
+        //second connection is over the cap
+        assert!(client.connect(addr, "localhost").unwrap().await.is_err());
+    }
+}
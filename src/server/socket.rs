@@ -0,0 +1,68 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+//open count udp sockets on the same addr, with more than one socket, they are
+//bound with SO_REUSEPORT so the kernel spreads incoming queries among them
+pub fn bind_udp_sockets(addr: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    let reuse_port = count > 1;
+    let first = bind_udp_socket(addr, reuse_port)?;
+    //with port 0, the rest sockets have to join the port picked by the first one
+    let addr = first.local_addr()?;
+    let mut sockets = vec![first];
+    for _ in 1..count {
+        sockets.push(bind_udp_socket(addr, reuse_port)?);
+    }
+    Ok(sockets)
+}
+
+fn bind_udp_socket(addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT isn't supported",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_reuse_port_sockets() {
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let sockets = bind_udp_sockets(addr, 4).unwrap();
+        assert_eq!(sockets.len(), 4);
+        let port = sockets[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        for socket in sockets.iter() {
+            assert_eq!(socket.local_addr().unwrap().port(), port);
+        }
+
+        //without reuse port, the addr can't be shared
+        let addr = sockets[0].local_addr().unwrap();
+        drop(sockets);
+        let socket = bind_udp_sockets(addr, 1).unwrap();
+        assert!(bind_udp_sockets(addr, 1).is_err());
+        drop(socket);
+    }
+}
@@ -0,0 +1,462 @@
+use super::edns::DEFAULT_UDP_PAYLOAD_LEN;
+use super::handler::{handle_notify, handle_request, Client, Handler};
+use super::transfer::{ixfr_serial, rcode_response, transfer_messages};
+use super::tsig::{strip_tsig, RequestTsig};
+use super::update::{handle_update, parse_request};
+use r53::{MessageRender, Opcode, RRType, Rcode, Request, Response, ResponseBuilder};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::timeout;
+
+const MAX_MESSAGE_LEN: usize = 65535;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const MAX_PIPELINED_QUERIES: usize = 32;
+//zone transfers aren't bound by it, they take as long as the zone needs
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+//messages of a transfer packed ahead of the writer
+const MAX_QUEUED_MESSAGES: usize = 4;
+
+pub struct TcpServer<H: Handler> {
+    handler: H,
+    idle_timeout: Duration,
+    max_connections: usize,
+}
+
+impl<H: Handler> TcpServer<H> {
+    pub fn new(handler: H) -> Self {
+        TcpServer {
+            handler,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub async fn run(&mut self, addr: SocketAddr) {
+        let listener = TcpListener::bind(&addr).await.expect("bind addr failed");
+        self.serve(listener).await;
+    }
+
+    pub async fn serve(&mut self, listener: TcpListener) {
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok((stream, addr)) => (stream, addr.ip()),
+                Err(_) => continue,
+            };
+            //over the cap, close the connection right away so the client
+            //can fall back to another server
+            let permit = match connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => continue,
+            };
+            let handler = self.handler.clone();
+            let idle_timeout = self.idle_timeout;
+            tokio::spawn(async move {
+                serve_connection(stream, client, handler, idle_timeout).await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn serve_connection<H: Handler>(
+    stream: TcpStream,
+    client: IpAddr,
+    handler: H,
+    idle_timeout: Duration,
+) {
+    let _ = stream.set_nodelay(true);
+    serve_stream(stream, client, handler, idle_timeout).await;
+}
+
+//serve rfc7766 length prefixed messages over any byte stream, queries on the
+//same connection are resolved concurrently and answered as soon as they are ready
+pub(crate) async fn serve_stream<S, H>(
+    stream: S,
+    client: IpAddr,
+    handler: H,
+    idle_timeout: Duration,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    H: Handler,
+{
+    let (mut reader, mut writer) = io::split(stream);
+    //every answer has its own queue of messages, a zone transfer is written
+    //while it's packed and the other answers wait until it's done, so they
+    //aren't interleaved
+    let (sender, mut receiver) = mpsc::channel::<AnswerQueue>(MAX_PIPELINED_QUERIES);
+    let writer_task = tokio::spawn(async move {
+        'writer: while let Some(mut messages) = receiver.recv().await {
+            while let Some(message) = messages.recv().await {
+                if write_message(&mut writer, &message).await.is_err() {
+                    break 'writer;
+                }
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let inflight = Arc::new(Semaphore::new(MAX_PIPELINED_QUERIES));
+    let mut req_buf = vec![0; MAX_MESSAGE_LEN];
+    loop {
+        let len = match timeout(idle_timeout, read_message(&mut reader, &mut req_buf)).await {
+            Ok(Ok(len)) => len,
+            _ => break,
+        };
+        //tsig is verified here, the handler only sees the key name
+        let (wire, record) = match strip_tsig(&req_buf[..len]) {
+            Ok(result) => result,
+            Err(_) => break,
+        };
+        let request = match parse_request(&wire) {
+            Ok(request) => request,
+            Err(_) => break,
+        };
+        let mut tsig = RequestTsig::new(handler.keyring(), &wire, record);
+        let serial = if request.question.typ == RRType::IXFR {
+            ixfr_serial(&wire)
+        } else {
+            None
+        };
+        let wire = if request.header.opcode == Opcode::Update {
+            wire.into_owned()
+        } else {
+            Vec::new()
+        };
+        let permit = match inflight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        let mut handler = handler.clone();
+        let sender = sender.clone();
+        let client = Client {
+            ip: client,
+            key: tsig.key_name(),
+        };
+        tokio::spawn(async move {
+            let max_len = MAX_MESSAGE_LEN - tsig.space();
+            let messages: Box<dyn Iterator<Item = Vec<u8>> + Send> = if tsig.is_failed() {
+                Box::new(
+                    render_within(&rcode_response(&request, Rcode::NotAuth), max_len).into_iter(),
+                )
+            } else if request.header.opcode == Opcode::Query
+                && (request.question.typ == RRType::AXFR || request.question.typ == RRType::IXFR)
+            {
+                Box::new(transfer_messages(&mut handler, request, &client, serial, max_len).await)
+            } else {
+                //failed or timed out request is answered with SERVFAIL, so the
+                //client doesn't wait for its own retry timer
+                let servfail = rcode_response(&request, Rcode::ServFail);
+                let response = if request.header.opcode == Opcode::Notify {
+                    timeout(
+                        REQUEST_TIMEOUT,
+                        handle_notify(&mut handler, request, &client),
+                    )
+                    .await
+                    .map(Ok)
+                } else if request.header.opcode == Opcode::Update {
+                    timeout(
+                        REQUEST_TIMEOUT,
+                        handle_update(&mut handler, request, &wire, &client),
+                    )
+                    .await
+                    .map(Ok)
+                } else {
+                    timeout(
+                        REQUEST_TIMEOUT,
+                        handle_request(&mut handler, request, DEFAULT_UDP_PAYLOAD_LEN),
+                    )
+                    .await
+                };
+                let response = match response {
+                    Ok(Ok(response)) => response,
+                    _ => servfail,
+                };
+                Box::new(render_within(&response, max_len).into_iter())
+            };
+            let mut answer = Answer::new(sender);
+            //every message of a transfer is signed
+            for mut message in messages {
+                tsig.sign(&mut message);
+                if answer.send(message).await.is_err() {
+                    break;
+                }
+            }
+            drop(permit);
+        });
+    }
+
+    //writer exits once every pending query has sent its answer
+    drop(sender);
+    let _ = writer_task.await;
+}
+
+type AnswerQueue = mpsc::Receiver<Vec<u8>>;
+
+//messages of one answer, its queue is handed to the writer along with the
+//first message, so answers are written in the order they are ready
+struct Answer {
+    sender: mpsc::Sender<AnswerQueue>,
+    messages: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl Answer {
+    fn new(sender: mpsc::Sender<AnswerQueue>) -> Self {
+        Answer {
+            sender,
+            messages: None,
+        }
+    }
+
+    async fn send(&mut self, message: Vec<u8>) -> anyhow::Result<()> {
+        let messages = match self.messages {
+            Some(ref messages) => messages,
+            None => {
+                let (messages, queue) = mpsc::channel(MAX_QUEUED_MESSAGES);
+                self.sender
+                    .send(queue)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("connection closed"))?;
+                self.messages.insert(messages)
+            }
+        };
+        messages
+            .send(message)
+            .await
+            .map_err(|_| anyhow::anyhow!("connection closed"))
+    }
+}
+
+pub(crate) fn render_message(response: &Response) -> Option<Vec<u8>> {
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let len = {
+        let mut render = MessageRender::new(&mut buf[..]);
+        response.to_wire(&mut render).ok()?
+    };
+    buf.truncate(len);
+    Some(buf)
+}
+
+//single query over doh or doq, verified and answered with tsig the same way
+//as udp and tcp. a failed query is answered with SERVFAIL, only malformed
+//message is an error. the response is returned along with its wire
+pub(crate) async fn handle_message<H: Handler>(
+    handler: &mut H,
+    message: &[u8],
+) -> anyhow::Result<(Response, Vec<u8>)> {
+    let (wire, record) = strip_tsig(message)?;
+    let request = Request::from_wire(&wire)?;
+    let mut tsig = RequestTsig::new(handler.keyring(), &wire, record);
+    let response = if tsig.is_failed() {
+        rcode_response(&request, Rcode::NotAuth)
+    } else {
+        let fallback = rcode_response(&request, Rcode::ServFail);
+        handle_request(handler, request, DEFAULT_UDP_PAYLOAD_LEN)
+            .await
+            .unwrap_or(fallback)
+    };
+    let mut message = render_within(&response, MAX_MESSAGE_LEN - tsig.space())
+        .ok_or_else(|| anyhow::anyhow!("render response failed"))?;
+    tsig.sign(&mut message);
+    Ok((response, message))
+}
+
+//response which doesn't fit in max_len is answered with SERVFAIL, so the
+//client isn't left waiting for a message never sent
+fn render_within(response: &Response, max_len: usize) -> Option<Vec<u8>> {
+    render_message(response)
+        .filter(|message| message.len() <= max_len)
+        .or_else(|| render_message(&servfail_response(response)))
+}
+
+fn servfail_response(response: &Response) -> Response {
+    let mut servfail =
+        Response::with_question(response.question.name.clone(), response.question.typ);
+    servfail.header.opcode = response.header.opcode;
+    let mut builder = ResponseBuilder::new(&mut servfail);
+    builder
+        .id(response.header.id)
+        .make_response()
+        .rcode(Rcode::ServFail)
+        .done();
+    servfail
+}
+
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let len = reader.read_u16().await? as usize;
+    if len == 0 || len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid message length",
+        ));
+    }
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(len)
+}
+
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(message.len() + 2);
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::test_util::build_auth;
+    use r53::{Name, RRType, RRset, Rcode, SectionType};
+    use std::str::FromStr;
+
+    fn query_wire(id: u16, name: &str, typ: RRType) -> Vec<u8> {
+        let mut req = Request::new(Name::new(name).unwrap(), typ);
+        req.header.id = id;
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+        let len = {
+            let mut render = MessageRender::new(&mut buf[..]);
+            req.to_wire(&mut render).unwrap()
+        };
+        buf.truncate(len);
+        buf
+    }
+
+    async fn start_server(idle_timeout: Duration) -> SocketAddr {
+        start_auth_server(build_auth(), idle_timeout).await
+    }
+
+    async fn start_auth_server(auth: Auth, idle_timeout: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            TcpServer::new(auth)
+                .idle_timeout(idle_timeout)
+                .serve(listener)
+                .await
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_queries() {
+        let addr = start_server(DEFAULT_IDLE_TIMEOUT).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_message(&mut stream, &query_wire(1, "www.example.com", RRType::A))
+            .await
+            .unwrap();
+        write_message(&mut stream, &query_wire(2, "ns.example.com", RRType::A))
+            .await
+            .unwrap();
+
+        let mut ids = Vec::new();
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+        for _ in 0..2 {
+            let len = read_message(&mut stream, &mut buf).await.unwrap();
+            let resp = Response::from_wire(&buf[..len]).unwrap();
+            assert_eq!(resp.header.rcode, Rcode::NoError);
+            assert_eq!(resp.section(SectionType::Answer).unwrap().len(), 1);
+            ids.push(resp.header.id);
+        }
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_oversize_response() {
+        let mut resp = Response::with_question(Name::new("www.example.com").unwrap(), RRType::A);
+        let mut builder = ResponseBuilder::new(&mut resp);
+        builder
+            .id(1)
+            .make_response()
+            .add_rrset(
+                SectionType::Answer,
+                RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+            )
+            .done();
+        let len = render_message(&resp).unwrap().len();
+
+        let message = render_within(&resp, len).unwrap();
+        assert_eq!(message.len(), len);
+        let message = render_within(&resp, len - 1).unwrap();
+        assert!(message.len() < len);
+        let resp = Response::from_wire(&message).unwrap();
+        assert_eq!(resp.header.id, 1);
+        assert_eq!(resp.header.rcode, Rcode::ServFail);
+        assert_eq!(resp.header.an_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_axfr() {
+        let auth = build_auth();
+        let zone = Name::new("example.com").unwrap();
+        for i in 0..1000 {
+            let txt = format!("a{}.example.com. 3600 IN TXT \"{}\"", i, "a".repeat(250));
+            auth.add_rrset(&zone, RRset::from_str(&txt).unwrap())
+                .unwrap();
+        }
+        let addr = start_auth_server(auth.clone(), DEFAULT_IDLE_TIMEOUT).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+
+        //no client is allowed by default
+        write_message(&mut stream, &query_wire(1, "example.com", RRType::AXFR))
+            .await
+            .unwrap();
+        let len = read_message(&mut stream, &mut buf).await.unwrap();
+        let resp = Response::from_wire(&buf[..len]).unwrap();
+        assert_eq!(resp.header.rcode, Rcode::Refused);
+
+        auth.set_allow_transfer(&zone, &["127.0.0.0/8"]).unwrap();
+        write_message(&mut stream, &query_wire(2, "example.com", RRType::AXFR))
+            .await
+            .unwrap();
+        let mut rrsets = Vec::new();
+        let mut messages = 0;
+        while rrsets.len() < 2 || rrsets.last().map(|rrset: &RRset| rrset.typ) != Some(RRType::SOA)
+        {
+            let len = read_message(&mut stream, &mut buf).await.unwrap();
+            let resp = Response::from_wire(&buf[..len]).unwrap();
+            assert_eq!(resp.header.id, 2);
+            assert_eq!(resp.header.rcode, Rcode::NoError);
+            rrsets.extend(resp.section(SectionType::Answer).unwrap().iter().cloned());
+            messages += 1;
+        }
+        assert!(messages > 1);
+        assert_eq!(rrsets[0].typ, RRType::SOA);
+        assert_eq!(rrsets.len(), auth.get_rrsets(&zone).unwrap().len() + 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_closed() {
+        let addr = start_server(Duration::from_millis(100)).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let len = timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, 0);
+    }
+}
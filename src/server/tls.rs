@@ -0,0 +1,57 @@
+use anyhow::{self, bail};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+//certificate and key shared by all the encrypted transports
+#[derive(Clone)]
+pub struct TlsCertificate {
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl TlsCertificate {
+    pub fn load<P: AsRef<Path>>(cert_path: P, key_path: P) -> anyhow::Result<Self> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?;
+        if certs.is_empty() {
+            bail!("no certificate found");
+        }
+
+        let mut key = None;
+        for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))? {
+            match item {
+                rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::RSAKey(der)
+                | rustls_pemfile::Item::ECKey(der) => {
+                    key = Some(der);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        match key {
+            Some(key) => Ok(TlsCertificate {
+                certs: certs.into_iter().map(Certificate).collect(),
+                key: PrivateKey(key),
+            }),
+            None => bail!("no private key found"),
+        }
+    }
+
+    pub fn server_config(&self, alpn: &[u8]) -> anyhow::Result<Arc<ServerConfig>> {
+        let mut config = self.http_server_config()?;
+        config.alpn_protocols = vec![alpn.to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    //http server negotiates h2 and http/1.1 by itself
+    pub fn http_server_config(&self) -> anyhow::Result<ServerConfig> {
+        Ok(ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(self.certs.clone(), self.key.clone())?)
+    }
+}
@@ -0,0 +1,120 @@
+use super::handler::Handler;
+use super::tcp_server::serve_stream;
+use super::tls::TlsCertificate;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+const DOT_ALPN: &[u8] = b"dot";
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+//dns over tls(rfc7858), same framing as tcp inside the tls session
+pub struct TlsServer<H: Handler> {
+    handler: H,
+    acceptor: TlsAcceptor,
+    idle_timeout: Duration,
+    max_connections: usize,
+}
+
+impl<H: Handler> TlsServer<H> {
+    pub fn new(handler: H, tls: &TlsCertificate) -> anyhow::Result<Self> {
+        Ok(TlsServer {
+            handler,
+            acceptor: TlsAcceptor::from(tls.server_config(DOT_ALPN)?),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        })
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub async fn run(&mut self, addr: SocketAddr) {
+        let listener = TcpListener::bind(&addr).await.expect("bind addr failed");
+        self.serve(listener).await;
+    }
+
+    pub async fn serve(&mut self, listener: TcpListener) {
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok((stream, addr)) => (stream, addr.ip()),
+                Err(_) => continue,
+            };
+            let permit = match connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => continue,
+            };
+            let acceptor = self.acceptor.clone();
+            let handler = self.handler.clone();
+            let idle_timeout = self.idle_timeout;
+            tokio::spawn(async move {
+                let _ = stream.set_nodelay(true);
+                if let Ok(Ok(stream)) =
+                    timeout(DEFAULT_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                {
+                    serve_stream(stream, client, handler, idle_timeout).await;
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tcp_server::{read_message, write_message};
+    use crate::test_util::{build_auth, self_signed_certificate};
+    use r53::{MessageRender, Name, RRType, Rcode, Request, Response, SectionType};
+    use rustls::ServerName;
+    use std::convert::TryFrom;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn test_dot_query() {
+        let auth = build_auth();
+        let (tls, client_config) = self_signed_certificate(DOT_ALPN);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = TlsServer::new(auth, &tls).unwrap();
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(client_config)
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let mut req = Request::new(Name::new("www.example.com").unwrap(), RRType::A);
+        req.header.id = 2000;
+        let mut query = vec![0; 512];
+        let len = {
+            let mut render = MessageRender::new(&mut query[..]);
+            req.to_wire(&mut render).unwrap()
+        };
+        write_message(&mut stream, &query[..len]).await.unwrap();
+
+        let mut buf = vec![0; 65535];
+        let len = read_message(&mut stream, &mut buf).await.unwrap();
+        let resp = Response::from_wire(&buf[..len]).unwrap();
+        assert_eq!(resp.header.id, 2000);
+        assert_eq!(resp.header.rcode, Rcode::NoError);
+        let answers = resp.section(SectionType::Answer).unwrap();
+        assert_eq!(answers[0].rdatas[0].to_string(), "2.2.2.2");
+    }
+}
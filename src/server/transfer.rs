@@ -0,0 +1,276 @@
+use anyhow::{self, bail};
+use r53::{RRset, Rcode, Request, Response, ResponseBuilder, SectionType};
+use std::collections::VecDeque;
+
+use super::handler::{Client, Handler};
+use super::tcp_server::render_message;
+
+//rrsets tried in one message, halved until the message fits in 64k
+const RRSETS_PER_MESSAGE: usize = 128;
+
+pub(crate) fn rcode_response(req: &Request, rcode: Rcode) -> Response {
+    let mut resp = Response::with_question(req.question.name.clone(), req.question.typ);
+    let mut builder = ResponseBuilder::new(&mut resp);
+    builder
+        .id(req.header.id)
+        .opcode(req.header.opcode)
+        .make_response()
+        .rcode(rcode)
+        .done();
+    resp
+}
+
+//rfc5936 zone transfer, the records are streamed in as many messages as
+//needed, a refused transfer gets a single message with the error rcode.
+//max_len leaves room for the tsig of each message
+pub(crate) async fn transfer_messages<H: Handler>(
+    handler: &mut H,
+    req: Request,
+    client: &Client,
+    serial: Option<u32>,
+    max_len: usize,
+) -> TransferMessages {
+    let (rrsets, rcode) = match handler.transfer(&req, client, serial).await {
+        Ok(rrsets) => (rrsets, None),
+        Err(rcode) => (Vec::new(), Some(rcode)),
+    };
+    TransferMessages {
+        req,
+        rrsets: rrsets.into(),
+        max_len,
+        rcode,
+    }
+}
+
+//messages of a transfer are packed one at a time while they are sent, so
+//only the records are kept in memory. a transfer failed in the middle ends
+//with a SERVFAIL message
+pub(crate) struct TransferMessages {
+    req: Request,
+    rrsets: VecDeque<RRset>,
+    max_len: usize,
+    rcode: Option<Rcode>,
+}
+
+impl Iterator for TransferMessages {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if let Some(rcode) = self.rcode.take() {
+            self.rrsets.clear();
+            return render_message(&rcode_response(&self.req, rcode));
+        }
+        if self.rrsets.is_empty() {
+            return None;
+        }
+        match self.pack() {
+            Ok(message) => Some(message),
+            Err(_) => {
+                self.rcode = Some(Rcode::ServFail);
+                self.next()
+            }
+        }
+    }
+}
+
+impl TransferMessages {
+    //as many rrsets as fit in one message, the rdatas of an rrset too large
+    //for a message are split across consecutive ones
+    fn pack(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut count = RRSETS_PER_MESSAGE.min(self.rrsets.len());
+        loop {
+            let rrsets = self.rrsets.iter().take(count).cloned();
+            if let Some(message) = render_answer(&self.req, rrsets, self.max_len) {
+                self.rrsets.drain(..count);
+                return Ok(message);
+            }
+            if count == 1 {
+                break;
+            }
+            count /= 2;
+        }
+
+        let rrset = &mut self.rrsets[0];
+        let mut count = rrset.rdatas.len() / 2;
+        while count > 0 {
+            let part = RRset {
+                name: rrset.name.clone(),
+                typ: rrset.typ,
+                class: rrset.class,
+                ttl: rrset.ttl,
+                rdatas: rrset.rdatas[..count].to_vec(),
+            };
+            if let Some(message) = render_answer(&self.req, Some(part).into_iter(), self.max_len) {
+                rrset.rdatas.drain(..count);
+                return Ok(message);
+            }
+            count /= 2;
+        }
+        bail!("rr of {} {} is too large", rrset.name, rrset.typ)
+    }
+}
+
+fn render_answer<I: Iterator<Item = RRset>>(
+    req: &Request,
+    rrsets: I,
+    max_len: usize,
+) -> Option<Vec<u8>> {
+    let mut resp = rcode_response(req, Rcode::NoError);
+    let mut builder = ResponseBuilder::new(&mut resp);
+    for rrset in rrsets {
+        builder.add_rrset(SectionType::Answer, rrset);
+    }
+    builder.done();
+    render_message(&resp).filter(|message| message.len() <= max_len)
+}
+
+//serial of the soa in authority section of ixfr query, which isn't kept
+//in the parsed request
+pub(crate) fn ixfr_serial(wire: &[u8]) -> Option<u32> {
+    let ns_count = u16::from_be_bytes([*wire.get(8)?, *wire.get(9)?]);
+    if ns_count == 0 {
+        return None;
+    }
+    //question, then owner, type, class, ttl and rdlength of the soa
+    let pos = skip_name(wire, 12)? + 4;
+    let pos = skip_name(wire, pos)?;
+    if wire.get(pos..pos + 2)? != [0, 6] {
+        return None;
+    }
+    let pos = skip_name(wire, pos + 10)?;
+    let pos = skip_name(wire, pos)?;
+    let serial = wire.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([
+        serial[0], serial[1], serial[2], serial[3],
+    ]))
+}
+
+fn skip_name(wire: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *wire.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            wire.get(pos + 1)?;
+            return Some(pos + 2);
+        }
+        pos += len + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{MessageRender, Name, RRType};
+    use std::str::FromStr;
+
+    fn pack_messages(req: &Request, rrsets: Vec<RRset>, max_len: usize) -> Vec<Vec<u8>> {
+        TransferMessages {
+            req: req.clone(),
+            rrsets: rrsets.into(),
+            max_len,
+            rcode: None,
+        }
+        .collect()
+    }
+
+    #[test]
+    fn test_ixfr_serial() {
+        let req = Request::new(Name::new("example.com").unwrap(), RRType::IXFR);
+        let mut buf = vec![0; 512];
+        let len = {
+            let mut render = MessageRender::new(&mut buf[..]);
+            req.to_wire(&mut render).unwrap()
+        };
+        let mut wire = buf[..len].to_vec();
+        assert_eq!(ixfr_serial(&wire), None);
+
+        //soa with the owner compressed to the question name, root mname
+        //and rname followed by the serial and timers
+        wire[9] = 1;
+        wire.extend_from_slice(&[0xc0, 12, 0, 6, 0, 1, 0, 0, 0, 0, 0, 22, 0, 0]);
+        wire.extend_from_slice(&2022060101u32.to_be_bytes());
+        wire.extend_from_slice(&[0; 16]);
+        assert_eq!(ixfr_serial(&wire), Some(2022060101));
+        assert_eq!(ixfr_serial(&wire[..wire.len() - 18]), None);
+    }
+
+    #[test]
+    fn test_pack_messages() {
+        let req = Request::new(Name::new("example.com").unwrap(), RRType::AXFR);
+        let txt = "a".repeat(250);
+        let rrsets = (0..1000)
+            .map(|i| {
+                RRset::from_str(&format!("a{}.example.com. 3600 IN TXT \"{}\"", i, txt)).unwrap()
+            })
+            .collect::<Vec<RRset>>();
+        let messages = pack_messages(&req, rrsets.clone(), 65535);
+        assert!(messages.len() > 3);
+
+        let mut names = Vec::new();
+        for message in messages {
+            assert!(message.len() <= 65535);
+            let resp = Response::from_wire(&message).unwrap();
+            assert_eq!(resp.header.rcode, Rcode::NoError);
+            for rrset in resp.section(SectionType::Answer).unwrap() {
+                names.push(rrset.name.clone());
+            }
+        }
+        assert_eq!(
+            names,
+            rrsets
+                .iter()
+                .map(|rrset| rrset.name.clone())
+                .collect::<Vec<Name>>()
+        );
+    }
+
+    #[test]
+    fn test_split_large_rrset() {
+        let req = Request::new(Name::new("example.com").unwrap(), RRType::AXFR);
+        let txts = (0..1000)
+            .map(|i| format!("big.example.com. 3600 IN TXT \"{:0>250}\"", i))
+            .collect::<Vec<String>>();
+        let rrsets = vec![
+            RRset::from_str("a.example.com. 3600 IN A 1.1.1.1").unwrap(),
+            RRset::from_strs(&txts).unwrap(),
+            RRset::from_str("b.example.com. 3600 IN A 2.2.2.2").unwrap(),
+        ];
+        let messages = pack_messages(&req, rrsets.clone(), 65535);
+        assert!(messages.len() > 3);
+
+        let mut rdatas = Vec::new();
+        for message in messages {
+            assert!(message.len() <= 65535);
+            let resp = Response::from_wire(&message).unwrap();
+            assert_eq!(resp.header.rcode, Rcode::NoError);
+            for rrset in resp.section(SectionType::Answer).unwrap() {
+                if rrset.typ == RRType::TXT {
+                    rdatas.extend(rrset.rdatas.iter().cloned());
+                }
+            }
+        }
+        assert_eq!(rdatas, rrsets[1].rdatas);
+    }
+
+    #[test]
+    fn test_rr_too_large() {
+        let req = Request::new(Name::new("example.com").unwrap(), RRType::AXFR);
+        let rrsets = vec![
+            RRset::from_str("a.example.com. 3600 IN A 1.1.1.1").unwrap(),
+            RRset::from_str(&format!(
+                "b.example.com. 3600 IN TXT \"{}\"",
+                "b".repeat(200)
+            ))
+            .unwrap(),
+        ];
+        //the first message is sent, then the transfer fails
+        let messages = pack_messages(&req, rrsets, 150);
+        assert_eq!(messages.len(), 2);
+        let resp = Response::from_wire(&messages[0]).unwrap();
+        assert_eq!(resp.header.rcode, Rcode::NoError);
+        let resp = Response::from_wire(&messages[1]).unwrap();
+        assert_eq!(resp.header.rcode, Rcode::ServFail);
+    }
+}
@@ -0,0 +1,807 @@
+use anyhow::{self, bail};
+use ring::hmac;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::update::{read_labels, read_name, read_u16};
+
+const TSIG_TYPE: u16 = 250;
+const CLASS_ANY: u16 = 255;
+//rfc8945 section 10, the recommended fudge is 300 seconds
+const FUDGE: u16 = 300;
+//rfc8945 section 5.3.1, at most 99 messages of a response may be unsigned
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+//tsig error carried in the tsig of a NOTAUTH response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigError {
+    BadSig,
+    BadKey,
+    BadTime,
+}
+
+impl TsigError {
+    fn code(self) -> u16 {
+        match self {
+            TsigError::BadSig => 16,
+            TsigError::BadKey => 17,
+            TsigError::BadTime => 18,
+        }
+    }
+
+    fn from_code(code: u16) -> Option<Self> {
+        match code {
+            16 => Some(TsigError::BadSig),
+            17 => Some(TsigError::BadKey),
+            18 => Some(TsigError::BadTime),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TsigError::BadSig => write!(f, "BADSIG"),
+            TsigError::BadKey => write!(f, "BADKEY"),
+            TsigError::BadTime => write!(f, "BADTIME"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    //algorithm name in tsig rdata
+    fn name(self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256.",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512.",
+        }
+    }
+
+    fn hmac(self) -> hmac::Algorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+
+    fn mac_len(self) -> usize {
+        match self {
+            TsigAlgorithm::HmacSha256 => 32,
+            TsigAlgorithm::HmacSha512 => 64,
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(TsigAlgorithm::HmacSha256),
+            "hmac-sha512" => Ok(TsigAlgorithm::HmacSha512),
+            _ => bail!("unsupported tsig algorithm {}", s),
+        }
+    }
+}
+
+//secret shared by both ends of the exchange, the name is kept in lower
+//case with the trailing dot like the one in tsig
+#[derive(Debug, Clone)]
+pub struct TsigKey {
+    name: String,
+    algorithm: TsigAlgorithm,
+    key: hmac::Key,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: TsigAlgorithm, secret: &[u8]) -> anyhow::Result<Self> {
+        if secret.is_empty() {
+            bail!("tsig key {} has empty secret", name);
+        }
+        Ok(TsigKey {
+            name: key_name(name)?,
+            algorithm,
+            key: hmac::Key::new(algorithm.hmac(), secret),
+        })
+    }
+}
+
+//key in format <name>:<algorithm>:<base64 secret>
+impl FromStr for TsigKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.splitn(3, ':').collect::<Vec<&str>>();
+        if fields.len() != 3 {
+            bail!("tsig key should be <name>:<algorithm>:<secret>");
+        }
+        let secret = match base64::decode(fields[2]) {
+            Ok(secret) => secret,
+            Err(_) => bail!("tsig key {} secret isn't base64", fields[0]),
+        };
+        TsigKey::new(fields[0], fields[1].parse()?, &secret)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TsigKeyring {
+    keys: HashMap<String, TsigKey>,
+}
+
+impl TsigKeyring {
+    pub fn new(keys: Vec<TsigKey>) -> Self {
+        TsigKeyring {
+            keys: keys
+                .into_iter()
+                .map(|key| (key.name.clone(), key))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys.get(&key_name(name).ok()?)
+    }
+}
+
+//lower case name with the trailing dot
+pub(crate) fn key_name(name: &str) -> anyhow::Result<String> {
+    let mut name = name.to_ascii_lowercase();
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+    if name == "." || name.split('.').rev().skip(1).any(|label| label.is_empty()) {
+        bail!("invalid key name {}", name);
+    }
+    let wire = name_wire(&name);
+    if wire.len() > 255 || name.split('.').any(|label| label.len() > 63) {
+        bail!("key name {} is too long", name);
+    }
+    Ok(name)
+}
+
+//uncompressed wire format of a name, \DDD escapes from read_name are
+//turned back into the raw bytes
+fn name_wire(name: &str) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(name.len() + 1);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let mut raw = Vec::with_capacity(label.len());
+        let bytes = label.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = bytes
+                .get(i + 1..i + 4)
+                .and_then(|digits| std::str::from_utf8(digits).ok()?.parse::<u8>().ok());
+            match escaped {
+                Some(c) if bytes[i] == b'\\' => {
+                    raw.push(c);
+                    i += 4;
+                }
+                _ => {
+                    raw.push(bytes[i].to_ascii_lowercase());
+                    i += 1;
+                }
+            }
+        }
+        wire.push(raw.len() as u8);
+        wire.extend_from_slice(&raw);
+    }
+    wire.push(0);
+    wire
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TsigRecord {
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl TsigRecord {
+    //tsig variables of rfc8945 section 4.3.3, the messages after the first
+    //one of a response only cover the timers
+    fn variables(&self, timers_only: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if !timers_only {
+            buf.extend_from_slice(&name_wire(&self.key_name));
+            buf.extend_from_slice(&CLASS_ANY.to_be_bytes());
+            buf.extend_from_slice(&0u32.to_be_bytes());
+            buf.extend_from_slice(&name_wire(&self.algorithm));
+        }
+        buf.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        buf.extend_from_slice(&self.fudge.to_be_bytes());
+        if !timers_only {
+            buf.extend_from_slice(&self.error.to_be_bytes());
+            buf.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&self.other);
+        }
+        buf
+    }
+
+    fn to_wire(&self) -> Vec<u8> {
+        let mut rdata = name_wire(&self.algorithm);
+        rdata.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&self.fudge.to_be_bytes());
+        rdata.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&self.mac);
+        rdata.extend_from_slice(&self.original_id.to_be_bytes());
+        rdata.extend_from_slice(&self.error.to_be_bytes());
+        rdata.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&self.other);
+
+        let mut wire = name_wire(&self.key_name);
+        wire.extend_from_slice(&TSIG_TYPE.to_be_bytes());
+        wire.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        wire.extend_from_slice(&0u32.to_be_bytes());
+        wire.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&rdata);
+        wire
+    }
+
+    fn append_to(&self, wire: &mut Vec<u8>) {
+        let ar_count = u16::from_be_bytes([wire[10], wire[11]]) + 1;
+        wire[10..12].copy_from_slice(&ar_count.to_be_bytes());
+        wire.extend_from_slice(&self.to_wire());
+    }
+}
+
+//tsig has to be the last record of the message, it's removed so the rest
+//is parsed as usual. message without tsig is returned as it is
+pub(crate) fn strip_tsig(wire: &[u8]) -> anyhow::Result<(Cow<'_, [u8]>, Option<TsigRecord>)> {
+    let count = |i: usize| read_u16(wire, 4 + i * 2);
+    let mut pos = 12;
+    for _ in 0..count(0)? {
+        pos = read_labels(wire, pos)?.1 + 4;
+    }
+    let ar_count = count(3)?;
+    let rr_count = count(1)? as usize + count(2)? as usize + ar_count as usize;
+    for i in 0..rr_count {
+        let start = pos;
+        let (_, next) = read_labels(wire, pos)?;
+        let typ = read_u16(wire, next)?;
+        pos = next + 10 + read_u16(wire, next + 8)? as usize;
+        if pos > wire.len() {
+            bail!("message is truncated");
+        }
+        if typ == TSIG_TYPE {
+            if i + 1 != rr_count || ar_count == 0 {
+                bail!("tsig isn't the last record");
+            }
+            let record = parse_tsig(&wire[..pos], start)?;
+            let mut stripped = wire[..start].to_vec();
+            stripped[10..12].copy_from_slice(&(ar_count - 1).to_be_bytes());
+            return Ok((Cow::Owned(stripped), Some(record)));
+        }
+    }
+    Ok((Cow::Borrowed(wire), None))
+}
+
+fn parse_tsig(wire: &[u8], pos: usize) -> anyhow::Result<TsigRecord> {
+    let field = |pos: usize, len: usize| match wire.get(pos..pos + len) {
+        Some(field) => Ok(field),
+        None => Err(anyhow::anyhow!("tsig is truncated")),
+    };
+    let (key_name, pos) = read_name(wire, pos)?;
+    if read_u16(wire, pos + 2)? != CLASS_ANY {
+        bail!("tsig class isn't ANY");
+    }
+    let (algorithm, pos) = read_name(wire, pos + 10)?;
+    let mut time_signed = [0; 8];
+    time_signed[2..].copy_from_slice(field(pos, 6)?);
+    let fudge = read_u16(wire, pos + 6)?;
+    let mac_len = read_u16(wire, pos + 8)? as usize;
+    let mac = field(pos + 10, mac_len)?.to_vec();
+    let pos = pos + 10 + mac_len;
+    let other_len = read_u16(wire, pos + 4)? as usize;
+    let other = field(pos + 6, other_len)?.to_vec();
+    if pos + 6 + other_len != wire.len() {
+        bail!("invalid tsig rdata length");
+    }
+    Ok(TsigRecord {
+        key_name: key_name.to_ascii_lowercase(),
+        algorithm: algorithm.to_ascii_lowercase(),
+        time_signed: u64::from_be_bytes(time_signed),
+        fudge,
+        mac,
+        original_id: read_u16(wire, pos)?,
+        error: read_u16(wire, pos + 2)?,
+        other,
+    })
+}
+
+//data the mac is computed over, the message without tsig carries the
+//original id, unsigned messages of a response are covered by the next mac
+fn signed_data(
+    prior_mac: Option<&[u8]>,
+    unsigned: &[u8],
+    wire: &[u8],
+    record: &TsigRecord,
+    timers_only: bool,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(wire.len() + unsigned.len() + 128);
+    if let Some(mac) = prior_mac {
+        data.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(mac);
+    }
+    data.extend_from_slice(unsigned);
+    data.extend_from_slice(&record.original_id.to_be_bytes());
+    data.extend_from_slice(&wire[2..]);
+    data.extend_from_slice(&record.variables(timers_only));
+    data
+}
+
+//signs and verifies the messages of one exchange, the mac of every message
+//covers the previous one so messages of a response can't be dropped or
+//reordered
+#[derive(Debug, Clone)]
+pub struct TsigSession {
+    key: TsigKey,
+    prior_mac: Option<Vec<u8>>,
+    //response messages signed or verified so far
+    responses: usize,
+    //unsigned response messages after the last signed one
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+}
+
+impl TsigSession {
+    pub fn new(key: TsigKey) -> Self {
+        TsigSession {
+            key,
+            prior_mac: None,
+            responses: 0,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+        }
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key.name
+    }
+
+    //length of the tsig appended to a message
+    pub fn space(&self) -> usize {
+        name_wire(&self.key.name).len()
+            + 10
+            + name_wire(self.key.algorithm.name()).len()
+            + 16
+            + self.key.algorithm.mac_len()
+    }
+
+    //sign the request, or the next message of the response on server
+    pub fn sign(&mut self, wire: &mut Vec<u8>) {
+        self.sign_with(wire, now(), 0, Vec::new());
+    }
+
+    fn sign_with(&mut self, wire: &mut Vec<u8>, time_signed: u64, error: u16, other: Vec<u8>) {
+        let mut record = TsigRecord {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([wire[0], wire[1]]),
+            error,
+            other,
+        };
+        let data = signed_data(
+            self.prior_mac.as_deref(),
+            &[],
+            wire,
+            &record,
+            self.responses > 0,
+        );
+        record.mac = hmac::sign(&self.key.key, &data).as_ref().to_vec();
+        if self.prior_mac.is_some() {
+            self.responses += 1;
+        }
+        self.prior_mac = Some(record.mac.clone());
+        record.append_to(wire);
+    }
+
+    //verify the next message of the response on client, the message is
+    //returned without tsig
+    pub fn verify<'a>(&mut self, wire: &'a [u8]) -> anyhow::Result<Cow<'a, [u8]>> {
+        self.verify_at(wire, now())
+    }
+
+    fn verify_at<'a>(&mut self, wire: &'a [u8], now: u64) -> anyhow::Result<Cow<'a, [u8]>> {
+        let (message, record) = strip_tsig(wire)?;
+        let record = match record {
+            Some(record) => record,
+            None if self.responses > 0 && self.unsigned_count < MAX_UNSIGNED_MESSAGES => {
+                self.unsigned.extend_from_slice(&message);
+                self.unsigned_count += 1;
+                return Ok(message);
+            }
+            None => bail!("response isn't signed"),
+        };
+        if let Some(error) = TsigError::from_code(record.error) {
+            bail!("tsig error {}", error);
+        }
+        if record.key_name != self.key.name || record.algorithm != self.key.algorithm.name() {
+            bail!("response is signed with key {}", record.key_name);
+        }
+        let data = signed_data(
+            self.prior_mac.as_deref(),
+            &self.unsigned,
+            &message,
+            &record,
+            self.responses > 0,
+        );
+        if hmac::verify(&self.key.key, &data, &record.mac).is_err() {
+            bail!("tsig error {}", TsigError::BadSig);
+        }
+        if now.abs_diff(record.time_signed) > record.fudge as u64 {
+            bail!("tsig error {}", TsigError::BadTime);
+        }
+        self.prior_mac = Some(record.mac);
+        self.responses += 1;
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+        Ok(message)
+    }
+
+    //the last message of a response has to be signed
+    pub fn finish(&self) -> anyhow::Result<()> {
+        if self.unsigned_count > 0 {
+            bail!("last message of response isn't signed");
+        }
+        Ok(())
+    }
+}
+
+//request whose tsig can't be verified, which is answered with NOTAUTH and
+//the error in tsig. only the BADTIME answer is signed
+#[derive(Debug)]
+pub(crate) struct TsigFailure {
+    record: TsigRecord,
+    session: Option<TsigSession>,
+}
+
+impl TsigFailure {
+    #[cfg(test)]
+    fn error(&self) -> TsigError {
+        TsigError::from_code(self.record.error).unwrap()
+    }
+
+    fn space(&self) -> usize {
+        self.record.to_wire().len()
+            + self
+                .session
+                .as_ref()
+                .map_or(0, |session| session.key.algorithm.mac_len())
+    }
+
+    fn sign(&mut self, wire: &mut Vec<u8>) {
+        let mut record = self.record.clone();
+        record.original_id = u16::from_be_bytes([wire[0], wire[1]]);
+        match self.session.as_mut() {
+            Some(session) => {
+                session.sign_with(wire, record.time_signed, record.error, record.other)
+            }
+            None => record.append_to(wire),
+        }
+    }
+}
+
+//tsig of a request on server, the answer is signed the same way
+#[derive(Debug)]
+pub(crate) enum RequestTsig {
+    Unsigned,
+    Verified(TsigSession),
+    //answered with NOTAUTH without going to the handler
+    Failed(TsigFailure),
+}
+
+impl RequestTsig {
+    pub fn new(keyring: Option<&TsigKeyring>, wire: &[u8], record: Option<TsigRecord>) -> Self {
+        match record {
+            None => RequestTsig::Unsigned,
+            Some(record) => match verify_request(keyring, wire, record) {
+                Ok(session) => RequestTsig::Verified(session),
+                Err(failure) => RequestTsig::Failed(failure),
+            },
+        }
+    }
+
+    pub fn key_name(&self) -> Option<String> {
+        match self {
+            RequestTsig::Verified(session) => Some(session.key_name().to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, RequestTsig::Failed(_))
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        matches!(self, RequestTsig::Unsigned)
+    }
+
+    //room left in the answer for the tsig
+    pub fn space(&self) -> usize {
+        match self {
+            RequestTsig::Unsigned => 0,
+            RequestTsig::Verified(session) => session.space(),
+            RequestTsig::Failed(failure) => failure.space(),
+        }
+    }
+
+    pub fn sign(&mut self, wire: &mut Vec<u8>) {
+        match self {
+            RequestTsig::Unsigned => {}
+            RequestTsig::Verified(session) => session.sign(wire),
+            RequestTsig::Failed(failure) => failure.sign(wire),
+        }
+    }
+}
+
+//check the tsig of a request, the session signs the response
+fn verify_request(
+    keyring: Option<&TsigKeyring>,
+    wire: &[u8],
+    record: TsigRecord,
+) -> Result<TsigSession, TsigFailure> {
+    verify_request_at(keyring, wire, record, now())
+}
+
+fn verify_request_at(
+    keyring: Option<&TsigKeyring>,
+    wire: &[u8],
+    record: TsigRecord,
+    now: u64,
+) -> Result<TsigSession, TsigFailure> {
+    let unsigned_failure = |error: TsigError| {
+        let mut record = record.clone();
+        record.mac.clear();
+        record.error = error.code();
+        record.other.clear();
+        TsigFailure {
+            record,
+            session: None,
+        }
+    };
+    let key = match keyring.and_then(|keyring| keyring.get(&record.key_name)) {
+        Some(key) if key.algorithm.name() == record.algorithm => key.clone(),
+        _ => return Err(unsigned_failure(TsigError::BadKey)),
+    };
+    //truncated mac isn't accepted
+    if record.mac.len() != key.algorithm.mac_len() {
+        return Err(unsigned_failure(TsigError::BadSig));
+    }
+    let data = signed_data(None, &[], wire, &record, false);
+    if hmac::verify(&key.key, &data, &record.mac).is_err() {
+        return Err(unsigned_failure(TsigError::BadSig));
+    }
+    let mut session = TsigSession::new(key);
+    session.prior_mac = Some(record.mac.clone());
+    //time signed of BADTIME answer is the one of request, the server time
+    //is in other data
+    if now.abs_diff(record.time_signed) > record.fudge as u64 {
+        let mut record = record;
+        record.error = TsigError::BadTime.code();
+        record.other = now.to_be_bytes()[2..].to_vec();
+        return Err(TsigFailure {
+            record,
+            session: Some(session),
+        });
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //header with the given counts followed by question example.com. A
+    fn message(id: u16, response: bool) -> Vec<u8> {
+        let flag = if response { 0x84 } else { 0 };
+        let mut wire = vec![0, 0, flag, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        wire[..2].copy_from_slice(&id.to_be_bytes());
+        wire.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        wire
+    }
+
+    fn keyring() -> TsigKeyring {
+        TsigKeyring::new(vec![
+            "xfr-key:hmac-sha256:c2VjcmV0LWtleS1vZi14ZnI="
+                .parse()
+                .unwrap(),
+            "Update.Key:hmac-sha512:dXBkYXRlLXNlY3JldA=="
+                .parse()
+                .unwrap(),
+        ])
+    }
+
+    fn verify_signed(
+        keyring: &TsigKeyring,
+        wire: &[u8],
+        now: u64,
+    ) -> Result<TsigSession, TsigFailure> {
+        let (message, record) = strip_tsig(wire).unwrap();
+        verify_request_at(Some(keyring), &message, record.unwrap(), now)
+    }
+
+    #[test]
+    fn test_tsig_key() {
+        let keyring = keyring();
+        assert_eq!(keyring.get("XFR-KEY").unwrap().name, "xfr-key.");
+        assert_eq!(
+            keyring.get("update.key.").unwrap().algorithm,
+            TsigAlgorithm::HmacSha512
+        );
+        assert!(keyring.get("other-key").is_none());
+        for invalid in [
+            "xfr-key:hmac-md5:c2VjcmV0",
+            "xfr-key:hmac-sha256:!!",
+            "xfr-key:hmac-sha256:",
+            "xfr..key:hmac-sha256:c2VjcmV0",
+            "xfr-key",
+        ] {
+            assert!(invalid.parse::<TsigKey>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_sign_request() {
+        let keyring = keyring();
+        let now = 1_650_000_000;
+        for name in ["xfr-key", "update.key"] {
+            let mut client = TsigSession::new(keyring.get(name).unwrap().clone());
+            let mut wire = message(1, false);
+            client.sign_with(&mut wire, now, 0, Vec::new());
+            assert_eq!(wire.len(), message(1, false).len() + client.space());
+            let (message, record) = strip_tsig(&wire).unwrap();
+            assert_eq!(message.as_ref(), &self::message(1, false)[..]);
+            assert_eq!(record.as_ref().unwrap().key_name, format!("{}.", name));
+
+            let mut server = verify_signed(&keyring, &wire, now + 10).unwrap();
+            assert_eq!(server.key_name(), format!("{}.", name));
+            let mut resp = self::message(1, true);
+            server.sign(&mut resp);
+            assert!(client.verify(&resp).is_ok());
+        }
+
+        let mut client = TsigSession::new(keyring.get("xfr-key").unwrap().clone());
+        let mut wire = message(1, false);
+        client.sign_with(&mut wire, now, 0, Vec::new());
+        let mut tampered = wire.clone();
+        tampered[2] = 1;
+        let failure = verify_signed(&keyring, &tampered, now).unwrap_err();
+        assert_eq!(failure.error(), TsigError::BadSig);
+
+        let unknown = TsigKeyring::new(vec!["xfr-key:hmac-sha512:c2VjcmV0LWtleS1vZi14ZnI="
+            .parse()
+            .unwrap()]);
+        let mut failure = verify_signed(&unknown, &wire, now).unwrap_err();
+        assert_eq!(failure.error(), TsigError::BadKey);
+        let mut resp = message(1, true);
+        failure.sign(&mut resp);
+        let (_, record) = strip_tsig(&resp).unwrap();
+        assert!(record.unwrap().mac.is_empty());
+        assert_eq!(
+            client.clone().verify(&resp).unwrap_err().to_string(),
+            "tsig error BADKEY"
+        );
+
+        //BADTIME answer is signed with the server time in other data
+        let mut failure = verify_signed(&keyring, &wire, now + 301).unwrap_err();
+        assert_eq!(failure.error(), TsigError::BadTime);
+        let mut resp = message(1, true);
+        failure.sign(&mut resp);
+        let (_, record) = strip_tsig(&resp).unwrap();
+        let record = record.unwrap();
+        assert_eq!(record.time_signed, now);
+        assert_eq!(record.other, (now + 301).to_be_bytes()[2..].to_vec());
+        assert_eq!(
+            client.verify(&resp).unwrap_err().to_string(),
+            "tsig error BADTIME"
+        );
+    }
+
+    #[test]
+    fn test_multi_message_response() {
+        let keyring = keyring();
+        let key = keyring.get("xfr-key").unwrap().clone();
+        let now = 1_650_000_000;
+        let request = |client: &mut TsigSession| {
+            let mut wire = message(7, false);
+            client.sign_with(&mut wire, now, 0, Vec::new());
+            verify_signed(&keyring, &wire, now).unwrap()
+        };
+
+        let mut client = TsigSession::new(key.clone());
+        let mut server = request(&mut client);
+        let messages = (0..4)
+            .map(|_| {
+                let mut wire = message(7, true);
+                server.sign_with(&mut wire, now, 0, Vec::new());
+                wire
+            })
+            .collect::<Vec<Vec<u8>>>();
+        for wire in messages.iter() {
+            client.verify_at(wire, now).unwrap();
+        }
+        assert!(client.finish().is_ok());
+
+        //unsigned messages are covered by the next signed one
+        let mut client = TsigSession::new(key.clone());
+        let mut server = request(&mut client);
+        let mut first = message(7, true);
+        server.sign_with(&mut first, now, 0, Vec::new());
+        let unsigned = message(7, true);
+        let mut last = message(7, true);
+        let mut record = TsigRecord {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm.name().to_string(),
+            time_signed: now,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: 7,
+            error: 0,
+            other: Vec::new(),
+        };
+        let data = signed_data(server.prior_mac.as_deref(), &unsigned, &last, &record, true);
+        record.mac = hmac::sign(&key.key, &data).as_ref().to_vec();
+        record.append_to(&mut last);
+
+        client.verify_at(&first, now).unwrap();
+        client.verify_at(&unsigned, now).unwrap();
+        assert!(client.finish().is_err());
+        client.verify_at(&last, now).unwrap();
+        assert!(client.finish().is_ok());
+
+        //dropped message breaks the chain
+        let mut client = TsigSession::new(key);
+        let mut server = request(&mut client);
+        let messages = (0..3)
+            .map(|_| {
+                let mut wire = message(7, true);
+                server.sign_with(&mut wire, now, 0, Vec::new());
+                wire
+            })
+            .collect::<Vec<Vec<u8>>>();
+        client.verify_at(&messages[0], now).unwrap();
+        assert!(client.verify_at(&messages[2], now).is_err());
+    }
+
+    #[test]
+    fn test_strip_tsig() {
+        let keyring = keyring();
+        let wire = message(1, false);
+        let (message, record) = strip_tsig(&wire).unwrap();
+        assert!(matches!(message, Cow::Borrowed(_)) && record.is_none());
+
+        //tsig followed by another record
+        let mut client = TsigSession::new(keyring.get("xfr-key").unwrap().clone());
+        let mut signed = wire.clone();
+        client.sign(&mut signed);
+        let mut invalid = signed.clone();
+        invalid[11] = 2;
+        invalid.extend_from_slice(&[0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(strip_tsig(&invalid).is_err());
+        assert!(strip_tsig(&signed[..signed.len() - 1]).is_err());
+    }
+}
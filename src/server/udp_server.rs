@@ -1,35 +1,479 @@
-use super::handler::Handler;
-use r53::{MessageRender, Request, Response};
+use super::edns::{
+    max_udp_payload_len, DEFAULT_UDP_PAYLOAD_LEN, MAX_UDP_PAYLOAD_LEN, MIN_UDP_PAYLOAD_LEN,
+};
+use super::handler::{handle_notify, handle_request, Client, Handler};
+use super::transfer::rcode_response;
+use super::tsig::{strip_tsig, RequestTsig};
+use super::update::{handle_update, parse_request};
+use r53::{header_flag::HeaderFlag, MessageRender, Opcode, Rcode, Response, ResponseBuilder};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
 
-const QUERY_BUFFER_LEN: usize = 512;
-const RESPONSE_BUFFER_LEN: usize = 1232;
+const QUERY_BUFFER_LEN: usize = MAX_UDP_PAYLOAD_LEN as usize;
+const RESPONSE_BUFFER_LEN: usize = MAX_UDP_PAYLOAD_LEN as usize;
+const DEFAULT_MAX_INFLIGHT: usize = 1024;
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct UdpServer<H: Handler> {
     handler: H,
+    max_udp_payload: u16,
+    max_inflight: usize,
+    request_timeout: Duration,
 }
 
 impl<H: Handler> UdpServer<H> {
     pub fn new(handler: H) -> Self {
-        UdpServer { handler }
+        UdpServer {
+            handler,
+            max_udp_payload: DEFAULT_UDP_PAYLOAD_LEN,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    pub fn max_udp_payload(mut self, max_udp_payload: u16) -> Self {
+        self.max_udp_payload = max_udp_payload.clamp(MIN_UDP_PAYLOAD_LEN, MAX_UDP_PAYLOAD_LEN);
+        self
+    }
+
+    pub fn max_inflight(mut self, max_inflight: usize) -> Self {
+        self.max_inflight = max_inflight;
+        self
+    }
+
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
     }
 
     pub async fn run(&mut self, addr: SocketAddr) {
         let socket = UdpSocket::bind(&addr).await.expect("bind addr failed");
+        self.serve(socket).await;
+    }
+
+    pub async fn serve(&mut self, socket: UdpSocket) {
+        let socket = Arc::new(socket);
+        let inflight = Arc::new(Semaphore::new(self.max_inflight));
         let mut req_buf = [0; QUERY_BUFFER_LEN];
-        let mut resp_buf = [0; RESPONSE_BUFFER_LEN];
         loop {
-            if let Ok((len, peer)) = socket.recv_from(&mut req_buf).await {
-                if let Ok(request) = Request::from_wire(&req_buf[..len]) {
-                    if let Ok(response) = self.handler.resolve(request).await {
-                        let mut render = MessageRender::new(&mut resp_buf);
-                        if let Ok(len) = response.to_wire(&mut render) {
-                            socket.send_to(&resp_buf[..len], peer).await.unwrap();
-                        }
+            let (len, peer) = match socket.recv_from(&mut req_buf).await {
+                Ok(result) => result,
+                Err(_) => continue,
+            };
+            let (wire, record) = match strip_tsig(&req_buf[..len]) {
+                Ok(result) => result,
+                Err(_) => continue,
+            };
+            let request = match parse_request(&wire) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            //stop reading new queries once too many are in flight, the
+            //socket buffer absorbs bursts and drops the rest
+            let permit = match inflight.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => continue,
+            };
+            let mut tsig = RequestTsig::new(self.handler.keyring(), &wire, record);
+            //update sections are parsed from the wire
+            let wire = if request.header.opcode == Opcode::Update {
+                wire.into_owned()
+            } else {
+                Vec::new()
+            };
+
+            let mut handler = self.handler.clone();
+            let socket = socket.clone();
+            let max_udp_payload = self.max_udp_payload;
+            let request_timeout = self.request_timeout;
+            let client = Client {
+                ip: peer.ip(),
+                key: tsig.key_name(),
+            };
+            tokio::spawn(async move {
+                let max_len =
+                    max_udp_payload_len(&request, max_udp_payload).saturating_sub(tsig.space());
+                //failed or timed out request is answered with SERVFAIL, so the
+                //client doesn't wait for its own retry timer
+                let servfail = rcode_response(&request, Rcode::ServFail);
+                let response = if tsig.is_failed() {
+                    Ok(Ok(rcode_response(&request, Rcode::NotAuth)))
+                } else if request.header.opcode == Opcode::Notify {
+                    timeout(
+                        request_timeout,
+                        handle_notify(&mut handler, request, &client),
+                    )
+                    .await
+                    .map(Ok)
+                } else if request.header.opcode == Opcode::Update {
+                    timeout(
+                        request_timeout,
+                        handle_update(&mut handler, request, &wire, &client),
+                    )
+                    .await
+                    .map(Ok)
+                } else {
+                    timeout(
+                        request_timeout,
+                        handle_request(&mut handler, request, max_udp_payload),
+                    )
+                    .await
+                };
+                let response = match response {
+                    Ok(Ok(response)) => response,
+                    _ => servfail,
+                };
+                let mut resp_buf = [0; RESPONSE_BUFFER_LEN];
+                if let Some(len) = render_response(&response, &mut resp_buf[..max_len]) {
+                    if tsig.is_unsigned() {
+                        let _ = socket.send_to(&resp_buf[..len], peer).await;
+                    } else {
+                        let mut message = resp_buf[..len].to_vec();
+                        tsig.sign(&mut message);
+                        let _ = socket.send_to(&message, peer).await;
                     }
                 }
+                drop(permit);
+            });
+        }
+    }
+}
+
+//render the response into buf, if it doesn't fit, fall back to header and
+//question only with TC set, so the client will retry over tcp
+fn render_response(response: &Response, buf: &mut [u8]) -> Option<usize> {
+    {
+        let mut render = MessageRender::new(buf);
+        if let Ok(len) = response.to_wire(&mut render) {
+            return Some(len);
+        }
+    }
+
+    let truncated = truncated_response(response);
+    let mut render = MessageRender::new(buf);
+    truncated.to_wire(&mut render).ok()
+}
+
+fn truncated_response(response: &Response) -> Response {
+    let mut truncated =
+        Response::with_question(response.question.name.clone(), response.question.typ);
+    truncated.header.flag = response.header.flag;
+    truncated.header.opcode = response.header.opcode;
+    truncated.edns = response.edns.clone();
+    let mut builder = ResponseBuilder::new(&mut truncated);
+    builder
+        .id(response.header.id)
+        .make_response()
+        .rcode(response.header.rcode)
+        .set_flag(HeaderFlag::Truncation)
+        .done();
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::server::edns::build_edns;
+    use crate::test_util;
+    use async_trait::async_trait;
+    use r53::{header_flag, Name, RRType, RRset, Rcode, Request, SectionType};
+    use std::str::FromStr;
+    use std::time::Instant;
+
+    fn build_auth() -> Auth {
+        let auth = test_util::build_auth();
+        let zone = Name::new("example.com").unwrap();
+        let txts = (0..12)
+            .map(|i| format!("big.example.com. 3600 IN TXT \"{:0>60}\"", i))
+            .collect::<Vec<String>>();
+        auth.add_rrset(&zone, RRset::from_strs(&txts).unwrap())
+            .unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("small.example.com. 3600 IN TXT \"hello\"").unwrap(),
+        )
+        .unwrap();
+        auth
+    }
+
+    #[tokio::test]
+    async fn test_truncate_large_response() {
+        let mut auth = build_auth();
+        let mut buf = [0; RESPONSE_BUFFER_LEN];
+
+        let req = Request::new(Name::new("big.example.com").unwrap(), RRType::TXT);
+        let max_len = max_udp_payload_len(&req, DEFAULT_UDP_PAYLOAD_LEN);
+        assert_eq!(max_len, 512);
+        let response = auth.resolve(req).await.unwrap();
+        let len = render_response(&response, &mut buf[..max_len]).unwrap();
+        assert!(len <= max_len);
+        let truncated = Response::from_wire(&buf[..len]).unwrap();
+        assert!(header_flag::is_flag_set(
+            truncated.header.flag,
+            HeaderFlag::Truncation
+        ));
+        assert_eq!(truncated.header.rcode, Rcode::NoError);
+        assert_eq!(truncated.header.an_count, 0);
+        assert_eq!(truncated.question.name, response.question.name);
+        assert_eq!(truncated.question.typ, RRType::TXT);
+
+        let req = Request::new(Name::new("small.example.com").unwrap(), RRType::TXT);
+        let response = auth.resolve(req).await.unwrap();
+        let len = render_response(&response, &mut buf[..max_len]).unwrap();
+        let resp = Response::from_wire(&buf[..len]).unwrap();
+        assert!(!header_flag::is_flag_set(
+            resp.header.flag,
+            HeaderFlag::Truncation
+        ));
+        assert_eq!(resp.section(SectionType::Answer).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_max_udp_payload_floor() {
+        let server = UdpServer::new(build_auth()).max_udp_payload(256);
+        assert_eq!(server.max_udp_payload, MIN_UDP_PAYLOAD_LEN);
+        let server = UdpServer::new(build_auth()).max_udp_payload(u16::MAX);
+        assert_eq!(server.max_udp_payload, MAX_UDP_PAYLOAD_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_udp_server_truncate() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move { UdpServer::new(build_auth()).serve(socket).await });
+
+        let mut req = Request::new(Name::new("big.example.com").unwrap(), RRType::TXT);
+        req.header.id = 1000;
+        let mut req_buf = [0; QUERY_BUFFER_LEN];
+        let len = {
+            let mut render = MessageRender::new(&mut req_buf);
+            req.to_wire(&mut render).unwrap()
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&req_buf[..len], addr).await.unwrap();
+        let mut resp_buf = [0; RESPONSE_BUFFER_LEN];
+        let len = client.recv(&mut resp_buf).await.unwrap();
+        assert!(len <= 512);
+        let resp = Response::from_wire(&resp_buf[..len]).unwrap();
+        assert_eq!(resp.header.id, 1000);
+        assert!(header_flag::is_flag_set(
+            resp.header.flag,
+            HeaderFlag::Truncation
+        ));
+        assert!(resp.edns.is_none());
+
+        //with a large enough advertised payload size the whole rrset fits
+        req.edns = Some(build_edns(4096, false));
+        let len = {
+            let mut render = MessageRender::new(&mut req_buf);
+            req.to_wire(&mut render).unwrap()
+        };
+        client.send_to(&req_buf[..len], addr).await.unwrap();
+        let len = client.recv(&mut resp_buf).await.unwrap();
+        assert!(len > 512 && len <= DEFAULT_UDP_PAYLOAD_LEN as usize);
+        let resp = Response::from_wire(&resp_buf[..len]).unwrap();
+        assert!(!header_flag::is_flag_set(
+            resp.header.flag,
+            HeaderFlag::Truncation
+        ));
+        assert_eq!(resp.header.an_count, 12);
+        assert_eq!(resp.edns.unwrap().udp_size, DEFAULT_UDP_PAYLOAD_LEN);
+    }
+
+    #[derive(Clone)]
+    struct SlowHandler {
+        slow_name: Name,
+    }
+
+    #[async_trait]
+    impl Handler for SlowHandler {
+        async fn resolve(&mut self, req: Request) -> anyhow::Result<Response> {
+            if req.question.name == self.slow_name {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+            let mut resp = Response::with_question(req.question.name.clone(), req.question.typ);
+            let mut builder = ResponseBuilder::new(&mut resp);
+            builder.id(req.header.id).make_response().done();
+            Ok(resp)
+        }
+    }
+
+    //rr of an update message, names are uncompressed
+    fn update_rr(name: &str, typ: RRType, class: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut wire = Vec::new();
+        for label in name.split('.') {
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label.as_bytes());
+        }
+        wire.push(0);
+        wire.extend_from_slice(&typ.as_u16().to_be_bytes());
+        wire.extend_from_slice(&class.to_be_bytes());
+        wire.extend_from_slice(&ttl.to_be_bytes());
+        wire.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        wire.extend_from_slice(rdata);
+        wire
+    }
+
+    fn update_wire(id: u16, prerequisites: &[Vec<u8>], updates: &[Vec<u8>]) -> Vec<u8> {
+        let mut wire = id.to_be_bytes().to_vec();
+        wire.extend_from_slice(&[0x28, 0, 0, 1]);
+        wire.extend_from_slice(&(prerequisites.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&(updates.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&[0, 0]);
+        wire.extend_from_slice(&update_rr("example.com", RRType::SOA, 1, 0, &[])[..17]);
+        for rr in prerequisites.iter().chain(updates) {
+            wire.extend_from_slice(rr);
+        }
+        wire
+    }
+
+    #[tokio::test]
+    async fn test_udp_update() {
+        const IN: u16 = 1;
+        const NONE: u16 = 254;
+        const ANY: u16 = 255;
+        let auth = build_auth();
+        let zone = Name::new("example.com").unwrap();
+        auth.set_allow_update(&zone, &["127.0.0.0/8"]).unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_strs(&[
+                "www.example.com. 3600 IN A 2.2.2.2",
+                "www.example.com. 3600 IN A 3.3.3.3",
+            ])
+            .unwrap(),
+        )
+        .unwrap();
+        auth.add_rrset(
+            &zone,
+            RRset::from_str("ftp.example.com. 3600 IN A 4.4.4.4").unwrap(),
+        )
+        .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        {
+            let auth = auth.clone();
+            tokio::spawn(async move { UdpServer::new(auth).serve(socket).await });
+        }
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let send = |message: Vec<u8>| {
+            let client = &client;
+            async move {
+                client.send_to(&message, addr).await.unwrap();
+                let mut buf = [0; RESPONSE_BUFFER_LEN];
+                let len = client.recv(&mut buf).await.unwrap();
+                Response::from_wire(&buf[..len]).unwrap()
             }
+        };
+
+        //www is in use and nx isn't, then delete ftp A, every rrset of
+        //small, the rr 2.2.2.2 of www, and add new
+        let prerequisites = [
+            update_rr("www.example.com", RRType::ANY, ANY, 0, &[]),
+            update_rr("nx.example.com", RRType::ANY, NONE, 0, &[]),
+        ];
+        let updates = [
+            update_rr("ftp.example.com", RRType::A, ANY, 0, &[]),
+            update_rr("small.example.com", RRType::ANY, ANY, 0, &[]),
+            update_rr("www.example.com", RRType::A, NONE, 0, &[2, 2, 2, 2]),
+            update_rr("new.example.com", RRType::A, IN, 3600, &[5, 5, 5, 5]),
+        ];
+        let resp = send(update_wire(1, &prerequisites, &updates)).await;
+        assert_eq!(resp.header.id, 1);
+        assert_eq!(resp.header.opcode, Opcode::Update);
+        assert_eq!(resp.header.rcode, Rcode::NoError);
+        let name = |name: &str| Name::new(name).unwrap();
+        assert!(auth
+            .get_rrset(&zone, &name("ftp.example.com"), RRType::A)
+            .is_err());
+        assert!(auth
+            .get_rrset(&zone, &name("small.example.com"), RRType::TXT)
+            .is_err());
+        let www = auth
+            .get_rrset(&zone, &name("www.example.com"), RRType::A)
+            .unwrap();
+        assert_eq!(www.rdatas.len(), 1);
+        assert_eq!(www.rdatas[0].to_string(), "3.3.3.3");
+        assert!(auth
+            .get_rrset(&zone, &name("new.example.com"), RRType::A)
+            .is_ok());
+
+        //ftp A should exist but is gone, nothing is changed
+        let prerequisites = [update_rr("ftp.example.com", RRType::A, ANY, 0, &[])];
+        let updates = [update_rr("www.example.com", RRType::A, ANY, 0, &[])];
+        let resp = send(update_wire(2, &prerequisites, &updates)).await;
+        assert_eq!(resp.header.id, 2);
+        assert_eq!(resp.header.rcode, Rcode::NXRRset);
+        assert!(auth
+            .get_rrset(&zone, &name("www.example.com"), RRType::A)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_not_block_others() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handler = SlowHandler {
+            slow_name: Name::new("slow.example.com").unwrap(),
+        };
+        tokio::spawn(async move {
+            UdpServer::new(handler)
+                .request_timeout(Duration::from_secs(1))
+                .serve(socket)
+                .await
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let build_query = |id: u16, name: &str| {
+            let mut req = Request::new(Name::new(name).unwrap(), RRType::A);
+            req.header.id = id;
+            let mut req_buf = [0; QUERY_BUFFER_LEN];
+            let len = {
+                let mut render = MessageRender::new(&mut req_buf);
+                req.to_wire(&mut render).unwrap()
+            };
+            req_buf[..len].to_vec()
+        };
+
+        let start = Instant::now();
+        client
+            .send_to(&build_query(0, "slow.example.com"), addr)
+            .await
+            .unwrap();
+        let fast_query_count = 100;
+        for id in 1..=fast_query_count {
+            client
+                .send_to(&build_query(id, "fast.example.com"), addr)
+                .await
+                .unwrap();
         }
+
+        let mut resp_buf = [0; RESPONSE_BUFFER_LEN];
+        for _ in 0..fast_query_count {
+            let len = timeout(Duration::from_millis(500), client.recv(&mut resp_buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let resp = Response::from_wire(&resp_buf[..len]).unwrap();
+            assert_ne!(resp.header.id, 0);
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        //the slow query exceeds the request deadline and is answered with
+        //SERVFAIL before the handler is done
+        let len = timeout(Duration::from_millis(1500), client.recv(&mut resp_buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let resp = Response::from_wire(&resp_buf[..len]).unwrap();
+        assert_eq!(resp.header.id, 0);
+        assert_eq!(resp.header.rcode, Rcode::ServFail);
+        assert!(start.elapsed() < Duration::from_secs(2));
     }
 }
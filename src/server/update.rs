@@ -0,0 +1,282 @@
+use super::handler::{Client, Handler};
+use super::transfer::rcode_response;
+use anyhow::{self, bail};
+use r53::{Name, Opcode, RData, RRType, Rcode, Request, Response, ResponseBuilder, SectionType};
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u8 = 5;
+
+//rfc2136 update, the sections are parsed from wire since rrs of class ANY
+//and NONE may have empty rdata
+#[derive(Debug, Clone)]
+pub struct Update {
+    pub zone: Name,
+    pub prerequisites: Vec<UpdateRR>,
+    pub updates: Vec<UpdateRR>,
+}
+
+//class of prerequisite and update rrs, Zone is IN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateClass {
+    Zone,
+    None,
+    Any,
+}
+
+//rdata is None when rdlength is 0
+#[derive(Debug, Clone)]
+pub struct UpdateRR {
+    pub name: Name,
+    pub typ: RRType,
+    pub class: UpdateClass,
+    pub ttl: u32,
+    pub rdata: Option<RData>,
+}
+
+pub(crate) async fn handle_update<H: Handler>(
+    handler: &mut H,
+    req: Request,
+    wire: &[u8],
+    client: &Client,
+) -> Response {
+    let rcode = match parse_update(wire) {
+        Ok(update) => handler.update(update, client).await,
+        Err(_) => Rcode::FormErr,
+    };
+    let mut resp = rcode_response(&req, rcode);
+    ResponseBuilder::new(&mut resp)
+        .opcode(Opcode::Update)
+        .done();
+    resp
+}
+
+//request of a message received by the servers. rrs of class ANY and NONE
+//in update aren't left to r53, only the header and the zone of an update
+//are taken into the request, the rest is parsed by parse_update
+pub(crate) fn parse_request(wire: &[u8]) -> anyhow::Result<Request> {
+    match wire.get(2) {
+        Some(flag) if (flag >> 3) & 0x0f == OPCODE_UPDATE => {
+            let (zone, _) = read_name(wire, 12)?;
+            let mut request = Request::new(Name::new(&zone)?, RRType::SOA);
+            request.header.id = read_u16(wire, 0)?;
+            request.header.opcode = Opcode::Update;
+            Ok(request)
+        }
+        _ => Ok(Request::from_wire(wire)?),
+    }
+}
+
+pub(crate) fn parse_update(wire: &[u8]) -> anyhow::Result<Update> {
+    let count = |i: usize| read_u16(wire, 4 + i * 2);
+    if count(0)? != 1 {
+        bail!("zone section should have one rr");
+    }
+    let (zone, pos) = read_name(wire, 12)?;
+    if read_u16(wire, pos)? != RRType::SOA.as_u16() || read_u16(wire, pos + 2)? != CLASS_IN {
+        bail!("zone section should be soa in class IN");
+    }
+    let mut pos = pos + 4;
+    let mut sections = Vec::with_capacity(2);
+    for i in 1..3 {
+        let mut rrs = Vec::new();
+        for _ in 0..count(i)? {
+            let (rr, next) = parse_rr(wire, pos)?;
+            rrs.push(rr);
+            pos = next;
+        }
+        sections.push(rrs);
+    }
+    let updates = sections.pop().unwrap();
+    let prerequisites = sections.pop().unwrap();
+    Ok(Update {
+        zone: Name::new(&zone)?,
+        prerequisites,
+        updates,
+    })
+}
+
+fn parse_rr(wire: &[u8], pos: usize) -> anyhow::Result<(UpdateRR, usize)> {
+    let (name, pos) = read_name(wire, pos)?;
+    let typ = RRType::new(read_u16(wire, pos)?);
+    let class = match read_u16(wire, pos + 2)? {
+        CLASS_IN => UpdateClass::Zone,
+        CLASS_NONE => UpdateClass::None,
+        CLASS_ANY => UpdateClass::Any,
+        class => bail!("unknown class {}", class),
+    };
+    let ttl = match wire.get(pos + 4..pos + 8) {
+        Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        None => bail!("message is truncated"),
+    };
+    let len = read_u16(wire, pos + 8)? as usize;
+    let pos = pos + 10;
+    if wire.len() < pos + len {
+        bail!("rdata is truncated");
+    }
+    let rdata = if len == 0 {
+        None
+    } else {
+        Some(parse_rdata(wire, typ, pos, len)?)
+    };
+    Ok((
+        UpdateRR {
+            name: Name::new(&name)?,
+            typ,
+            class,
+            ttl,
+            rdata,
+        },
+        pos + len,
+    ))
+}
+
+//names in rdata of the well known types may be compressed against the
+//whole message, the rdata is decompressed and parsed in a message of its own
+fn parse_rdata(wire: &[u8], typ: RRType, pos: usize, len: usize) -> anyhow::Result<RData> {
+    let end = pos + len;
+    let mut rdata = Vec::with_capacity(len);
+    let copy_name = |pos: usize, rdata: &mut Vec<u8>| -> anyhow::Result<usize> {
+        let (labels, next) = read_labels(wire, pos)?;
+        for label in labels {
+            rdata.push(label.len() as u8);
+            rdata.extend_from_slice(label);
+        }
+        rdata.push(0);
+        Ok(next)
+    };
+    match typ {
+        RRType::NS | RRType::CNAME | RRType::PTR => {
+            copy_name(pos, &mut rdata)?;
+        }
+        RRType::MX => {
+            rdata.extend_from_slice(wire.get(pos..pos + 2).unwrap_or_default());
+            copy_name(pos + 2, &mut rdata)?;
+        }
+        RRType::SOA => {
+            let next = copy_name(pos, &mut rdata)?;
+            let next = copy_name(next, &mut rdata)?;
+            rdata.extend_from_slice(&wire[next.min(end)..end]);
+        }
+        _ => rdata.extend_from_slice(&wire[pos..end]),
+    }
+
+    //response with root question and one answer owned by root
+    let mut message = vec![0, 0, 0x80, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 6, 0, 1, 0];
+    message.extend_from_slice(&typ.as_u16().to_be_bytes());
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&rdata);
+    let resp = Response::from_wire(&message)?;
+    match resp
+        .section(SectionType::Answer)
+        .and_then(|answer| answer.first())
+        .and_then(|rrset| rrset.rdatas.first())
+    {
+        Some(rdata) => Ok(rdata.clone()),
+        None => bail!("invalid rdata of {}", typ),
+    }
+}
+
+pub(super) fn read_u16(wire: &[u8], pos: usize) -> anyhow::Result<u16> {
+    match wire.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => bail!("message is truncated"),
+    }
+}
+
+//labels of the name at pos following compression pointers, and the
+//position right after the name
+pub(crate) fn read_labels(wire: &[u8], mut pos: usize) -> anyhow::Result<(Vec<&[u8]>, usize)> {
+    let mut labels = Vec::new();
+    let mut next = None;
+    let mut jumps = 0;
+    loop {
+        let len = match wire.get(pos) {
+            Some(len) => *len as usize,
+            None => bail!("name is truncated"),
+        };
+        if len == 0 {
+            return Ok((labels, next.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let offset = read_u16(wire, pos)? as usize & 0x3fff;
+            if next.is_none() {
+                next = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 64 {
+                bail!("compression loop");
+            }
+            pos = offset;
+            continue;
+        }
+        match wire.get(pos + 1..pos + 1 + len) {
+            Some(label) => labels.push(label),
+            None => bail!("name is truncated"),
+        }
+        pos += len + 1;
+    }
+}
+
+pub(super) fn read_name(wire: &[u8], pos: usize) -> anyhow::Result<(String, usize)> {
+    let (labels, next) = read_labels(wire, pos)?;
+    if labels.is_empty() {
+        return Ok((".".to_string(), next));
+    }
+    let mut name = String::new();
+    for label in labels {
+        for &c in label {
+            if c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'*' {
+                name.push(c as char);
+            } else {
+                name.push_str(&format!("\\{:03}", c));
+            }
+        }
+        name.push('.');
+    }
+    Ok((name, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_update() {
+        //example.com update, prerequisite www.example.com ANY ANY, updates
+        //delete ftp.example.com A and add www.example.com CNAME with the
+        //target compressed to the zone name
+        let mut wire = vec![0, 1, 0x28, 0, 0, 1, 0, 1, 0, 2, 0, 0];
+        wire.extend_from_slice(b"\x07example\x03com\x00\x00\x06\x00\x01");
+        wire.extend_from_slice(b"\x03www\xc0\x0c\x00\xff\x00\xff\x00\x00\x00\x00\x00\x00");
+        wire.extend_from_slice(b"\x03ftp\xc0\x0c\x00\x01\x00\xff\x00\x00\x00\x00\x00\x00");
+        wire.extend_from_slice(b"\x03www\xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x0c");
+
+        let update = parse_update(&wire).unwrap();
+        assert_eq!(update.zone, Name::new("example.com").unwrap());
+        assert_eq!(update.prerequisites.len(), 1);
+        let prerequisite = &update.prerequisites[0];
+        assert_eq!(prerequisite.name, Name::new("www.example.com").unwrap());
+        assert_eq!(
+            (prerequisite.typ, prerequisite.class),
+            (RRType::ANY, UpdateClass::Any)
+        );
+        assert!(prerequisite.rdata.is_none());
+
+        assert_eq!(update.updates.len(), 2);
+        assert_eq!(update.updates[0].typ, RRType::A);
+        assert!(update.updates[0].rdata.is_none());
+        let add = &update.updates[1];
+        assert_eq!((add.class, add.ttl), (UpdateClass::Zone, 3600));
+        assert_eq!(
+            add.rdata.as_ref().unwrap().to_string(),
+            "example.com.".to_string()
+        );
+
+        assert!(parse_update(&wire[..wire.len() - 1]).is_err());
+        wire[5] = 2;
+        assert!(parse_update(&wire).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+//fixtures shared by the unit tests
+use crate::auth::{Auth, ZoneConfig};
+use crate::server::TlsCertificate;
+use r53::{Name, RRset};
+use rustls::{Certificate, ClientConfig, RootCertStore};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+//example.com served by ns.example.com at 1.1.1.1, with www.example.com at 2.2.2.2
+pub fn build_auth() -> Auth {
+    let auth = Auth::new();
+    let zone = Name::new("example.com").unwrap();
+    let config = ZoneConfig {
+        ips: vec!["1.1.1.1".to_string()],
+        ..Default::default()
+    };
+    auth.add_zone(zone.clone(), &config).unwrap();
+    auth.add_rrset(
+        &zone,
+        RRset::from_str("www.example.com. 3600 IN A 2.2.2.2").unwrap(),
+    )
+    .unwrap();
+    auth
+}
+
+//new empty dir under the system temp dir, unique per process and call
+pub fn temp_dir(prefix: &str) -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir =
+        std::env::temp_dir().join(format!("xdns-{}-{}-{}", prefix, std::process::id(), nanos));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+//self signed certificate for localhost, written to a temporary dir, returns
+//the loaded certificate and a client config which trusts it
+pub fn self_signed_certificate(alpn: &[u8]) -> (TlsCertificate, Arc<ClientConfig>) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = temp_dir("tls");
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    let tls = TlsCertificate::load(&cert_path, &key_path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_config.alpn_protocols = vec![alpn.to_vec()];
+    (tls, Arc::new(client_config))
+}